@@ -0,0 +1,85 @@
+// Throughput benchmark for the price-level order book.
+// Run with `cargo bench --bench order_book`.
+#[path = "../src/models/mod.rs"]
+#[allow(dead_code)]
+mod models;
+#[path = "../src/eip712.rs"]
+#[allow(dead_code, unused_imports)]
+mod eip712;
+
+use bigdecimal::BigDecimal;
+use ethereum_types::H160;
+use models::order::{L2OrderBook, OrderEntry, OrderSide};
+use std::time::Instant;
+
+const RESTING_ORDERS: u64 = 200_000;
+const PRICE_LEVELS: u64 = 1_000;
+
+fn entry(id: u64, price: u64) -> OrderEntry {
+    OrderEntry {
+        market_id: 1,
+        order_id: id as i64,
+        amount: BigDecimal::from(10),
+        price: BigDecimal::from(price),
+        trader_address: H160::from_low_u64_be(id % 64),
+        eip712_hash: format!("{:#066x}", id),
+        expires_at: None,
+        display_amount: None,
+        hidden_amount: BigDecimal::from(0),
+    }
+}
+
+fn report(label: &str, ops: u64, started: Instant) {
+    let elapsed = started.elapsed();
+    println!(
+        "{:<32} {:>8} ops in {:>10.2?} ({:>12.0} ops/s)",
+        label,
+        ops,
+        elapsed,
+        ops as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let mut book = L2OrderBook::new();
+
+    // Rest bids below 10_000 and asks above it so the book never crosses
+    let started = Instant::now();
+    for id in 0..RESTING_ORDERS {
+        let level = id % PRICE_LEVELS;
+        if id % 2 == 0 {
+            book.insert(&OrderSide::Bid, entry(id, 10_000 - 1 - level));
+        } else {
+            book.insert(&OrderSide::Ask, entry(id, 10_000 + 1 + level));
+        }
+    }
+    report("insert", RESTING_ORDERS, started);
+    println!("resting orders: {}", book.len());
+
+    // Cancel every fourth order through the hash index
+    let started = Instant::now();
+    let mut cancelled = 0;
+    for id in (0..RESTING_ORDERS).step_by(4) {
+        if book.remove(&format!("{:#066x}", id)).is_some() {
+            cancelled += 1;
+        }
+    }
+    report("cancel by hash", cancelled, started);
+
+    // Consume the best ask repeatedly, the way match_order walks the book
+    let started = Instant::now();
+    let mut matched = 0;
+    while matched < RESTING_ORDERS / 4 {
+        let best = match book.front(&OrderSide::Ask) {
+            Some(best) => best,
+            None => break,
+        };
+        book.set_amount(&best.eip712_hash, BigDecimal::from(0));
+        matched += 1;
+    }
+    report("match against best ask", matched, started);
+
+    let started = Instant::now();
+    let depth = book.depth(&OrderSide::Bid);
+    report("aggregate bid depth", depth.len() as u64, started);
+}
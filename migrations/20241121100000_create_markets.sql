@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS markets (
+    id SERIAL PRIMARY KEY,
+    symbol TEXT NOT NULL UNIQUE,
+    base_asset TEXT NOT NULL,
+    quote_asset TEXT NOT NULL
+);
+
+INSERT INTO markets (id, symbol, base_asset, quote_asset) VALUES
+    (1, 'DDX-USD', 'DDX', 'USD'),
+    (2, 'ETH-USD', 'ETH', 'USD')
+ON CONFLICT DO NOTHING;
+SELECT setval(pg_get_serial_sequence('markets', 'id'), (SELECT MAX(id) FROM markets));
+
+-- One order book row per market; the existing single book belongs to DDX-USD
+ALTER TABLE l2_order_book ADD COLUMN IF NOT EXISTS market_id INTEGER REFERENCES markets(id);
+UPDATE l2_order_book SET market_id = 1 WHERE id = 1;
+CREATE UNIQUE INDEX IF NOT EXISTS l2_order_book_market_id ON l2_order_book(market_id);
+SELECT setval(pg_get_serial_sequence('l2_order_book', 'id'), GREATEST((SELECT MAX(id) FROM l2_order_book), 1));
+
+ALTER TABLE fills ADD COLUMN IF NOT EXISTS market_id INTEGER NOT NULL DEFAULT 1 REFERENCES markets(id);
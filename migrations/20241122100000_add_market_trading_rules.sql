@@ -0,0 +1,6 @@
+ALTER TABLE markets
+    ADD COLUMN IF NOT EXISTS price_tick NUMERIC NOT NULL DEFAULT 0.01,
+    ADD COLUMN IF NOT EXISTS amount_step NUMERIC NOT NULL DEFAULT 0.001,
+    ADD COLUMN IF NOT EXISTS min_amount NUMERIC NOT NULL DEFAULT 0.001,
+    ADD COLUMN IF NOT EXISTS max_amount NUMERIC NOT NULL DEFAULT 1000000,
+    ADD COLUMN IF NOT EXISTS min_notional NUMERIC NOT NULL DEFAULT 1;
@@ -0,0 +1,3 @@
+ALTER TABLE markets
+    ADD COLUMN IF NOT EXISTS matching_algorithm TEXT NOT NULL DEFAULT 'Fifo',
+    ADD COLUMN IF NOT EXISTS fifo_slice NUMERIC NOT NULL DEFAULT 0;
@@ -0,0 +1,15 @@
+ALTER TABLE markets
+    ADD COLUMN IF NOT EXISTS price_band NUMERIC NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS breaker_threshold NUMERIC NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS breaker_window_secs BIGINT NOT NULL DEFAULT 60,
+    ADD COLUMN IF NOT EXISTS breaker_action TEXT NOT NULL DEFAULT 'Halt';
+
+-- Trading phase changes: halts, resumes and auctions
+CREATE TABLE IF NOT EXISTS market_events (
+    id SERIAL PRIMARY KEY,
+    market_id INTEGER NOT NULL REFERENCES markets(id),
+    phase TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS market_events_market_id ON market_events(market_id, id);
@@ -0,0 +1,14 @@
+-- Every accepted order, with its engine-assigned id. A trader can use each nonce only once.
+CREATE TABLE IF NOT EXISTS orders (
+    order_id BIGSERIAL PRIMARY KEY,
+    market_id INTEGER NOT NULL REFERENCES markets(id),
+    trader_address TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    eip712_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (trader_address, nonce)
+);
+
+ALTER TABLE fills
+    ADD COLUMN IF NOT EXISTS maker_order_id BIGINT,
+    ADD COLUMN IF NOT EXISTS taker_order_id BIGINT;
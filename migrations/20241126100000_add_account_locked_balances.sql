@@ -0,0 +1,4 @@
+-- ddx_balance and usd_balance are now what is available; these hold what open orders reserve
+ALTER TABLE accounts
+    ADD COLUMN IF NOT EXISTS ddx_locked NUMERIC NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS usd_locked NUMERIC NOT NULL DEFAULT 0;
@@ -0,0 +1,35 @@
+-- Fills get an id so ledger entries can point at them
+ALTER TABLE fills ADD COLUMN IF NOT EXISTS id BIGSERIAL PRIMARY KEY;
+
+-- Append-only double-entry ledger: the entries of a transaction sum to zero per asset
+CREATE TABLE IF NOT EXISTS ledger_entries (
+    id BIGSERIAL PRIMARY KEY,
+    transaction_id UUID NOT NULL,
+    account TEXT NOT NULL,
+    asset TEXT NOT NULL,
+    bucket TEXT NOT NULL,
+    amount NUMERIC NOT NULL,
+    cause TEXT NOT NULL,
+    cause_id TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS ledger_entries_account ON ledger_entries(LOWER(account), id);
+CREATE INDEX IF NOT EXISTS ledger_entries_transaction ON ledger_entries(transaction_id);
+
+-- Opening balances, so every account's balances can be derived from the ledger
+WITH opening AS (
+    SELECT gen_random_uuid() AS transaction_id, trader_address, ddx_balance, usd_balance, ddx_locked, usd_locked
+    FROM accounts
+)
+INSERT INTO ledger_entries (transaction_id, account, asset, bucket, amount, cause, cause_id)
+SELECT transaction_id, trader_address, 'DDX', 'available', ddx_balance, 'OpeningBalance', trader_address FROM opening WHERE ddx_balance <> 0
+UNION ALL
+SELECT transaction_id, trader_address, 'DDX', 'locked', ddx_locked, 'OpeningBalance', trader_address FROM opening WHERE ddx_locked <> 0
+UNION ALL
+SELECT transaction_id, trader_address, 'USD', 'available', usd_balance, 'OpeningBalance', trader_address FROM opening WHERE usd_balance <> 0
+UNION ALL
+SELECT transaction_id, trader_address, 'USD', 'locked', usd_locked, 'OpeningBalance', trader_address FROM opening WHERE usd_locked <> 0
+UNION ALL
+SELECT transaction_id, 'adjustments', 'DDX', 'available', -(ddx_balance + ddx_locked), 'OpeningBalance', trader_address FROM opening WHERE ddx_balance + ddx_locked <> 0
+UNION ALL
+SELECT transaction_id, 'adjustments', 'USD', 'available', -(usd_balance + usd_locked), 'OpeningBalance', trader_address FROM opening WHERE usd_balance + usd_locked <> 0;
@@ -0,0 +1,14 @@
+-- Deposits and withdrawals. Their ledger entries carry the transfer id as cause_id.
+CREATE TABLE IF NOT EXISTS transfers (
+    id BIGSERIAL PRIMARY KEY,
+    trader_address TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    asset TEXT NOT NULL,
+    amount NUMERIC NOT NULL CHECK (amount > 0),
+    status TEXT NOT NULL,
+    reason TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS transfers_trader ON transfers(LOWER(trader_address), id);
+CREATE INDEX IF NOT EXISTS transfers_pending ON transfers(status) WHERE status = 'Pending';
@@ -0,0 +1,35 @@
+-- Registry of the assets accounts can hold and markets can trade
+CREATE TABLE IF NOT EXISTS assets (
+    symbol TEXT PRIMARY KEY,
+    decimals INTEGER NOT NULL DEFAULT 18,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+INSERT INTO assets (symbol) VALUES ('DDX'), ('USD') ON CONFLICT DO NOTHING;
+INSERT INTO assets (symbol)
+SELECT base_asset FROM markets UNION SELECT quote_asset FROM markets
+ON CONFLICT DO NOTHING;
+
+-- One row per account and asset held, replacing the per-asset columns on accounts
+CREATE TABLE IF NOT EXISTS balances (
+    account_id UUID NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+    asset TEXT NOT NULL REFERENCES assets(symbol),
+    available NUMERIC NOT NULL DEFAULT 0,
+    locked NUMERIC NOT NULL DEFAULT 0,
+    PRIMARY KEY (account_id, asset)
+);
+
+INSERT INTO balances (account_id, asset, available, locked)
+SELECT id, 'DDX', ddx_balance, ddx_locked FROM accounts
+UNION ALL
+SELECT id, 'USD', usd_balance, usd_locked FROM accounts
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE accounts
+    DROP COLUMN IF EXISTS ddx_balance,
+    DROP COLUMN IF EXISTS usd_balance,
+    DROP COLUMN IF EXISTS ddx_locked,
+    DROP COLUMN IF EXISTS usd_locked;
+
+ALTER TABLE markets
+    ADD CONSTRAINT markets_base_asset_fkey FOREIGN KEY (base_asset) REFERENCES assets(symbol),
+    ADD CONSTRAINT markets_quote_asset_fkey FOREIGN KEY (quote_asset) REFERENCES assets(symbol);
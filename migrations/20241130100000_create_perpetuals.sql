@@ -0,0 +1,16 @@
+-- Perpetual futures: markets where fills move positions instead of the base asset, margined in
+-- the quote asset.
+ALTER TABLE markets
+    ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'Spot',
+    ADD COLUMN IF NOT EXISTS initial_margin NUMERIC NOT NULL DEFAULT 0;
+
+-- One row per trader and perpetual market. size is signed: positive long, negative short.
+CREATE TABLE IF NOT EXISTS positions (
+    trader_address TEXT NOT NULL,
+    market_id INTEGER NOT NULL REFERENCES markets(id),
+    size NUMERIC NOT NULL DEFAULT 0,
+    entry_price NUMERIC NOT NULL DEFAULT 0,
+    realized_pnl NUMERIC NOT NULL DEFAULT 0,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (trader_address, market_id)
+);
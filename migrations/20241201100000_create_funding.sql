@@ -0,0 +1,16 @@
+-- Funding for perpetual markets: how often longs and shorts settle the premium of the mark price
+-- over the index, and how large one payment may get.
+ALTER TABLE markets
+    ADD COLUMN IF NOT EXISTS funding_interval_secs BIGINT NOT NULL DEFAULT 3600,
+    ADD COLUMN IF NOT EXISTS max_funding_rate NUMERIC NOT NULL DEFAULT 0.0075;
+
+-- One row per funding payment. Its ledger entries carry the row id as cause_id.
+CREATE TABLE IF NOT EXISTS funding_rates (
+    id BIGSERIAL PRIMARY KEY,
+    market_id INTEGER NOT NULL REFERENCES markets(id),
+    index_price NUMERIC NOT NULL,
+    mark_price NUMERIC NOT NULL,
+    rate NUMERIC NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS funding_rates_market_id ON funding_rates(market_id, id);
@@ -0,0 +1,27 @@
+-- Liquidation: positions below maintenance margin are taken over by the insurance fund, which
+-- closes them in the book.
+ALTER TABLE markets
+    ADD COLUMN IF NOT EXISTS maintenance_margin NUMERIC NOT NULL DEFAULT 0;
+UPDATE markets SET maintenance_margin = initial_margin / 2 WHERE kind = 'Perpetual' AND maintenance_margin = 0;
+
+-- The insurance fund is a system account: it absorbs liquidation shortfalls, collects
+-- liquidation fees and places the orders that close liquidated positions
+INSERT INTO accounts (trader_address)
+SELECT '0x0000000000000000000000000000000000000001'
+WHERE NOT EXISTS (
+    SELECT 1 FROM accounts WHERE LOWER(trader_address) = '0x0000000000000000000000000000000000000001'
+);
+
+-- Audit trail of every liquidation step. Ledger entries of a step carry the event id as cause_id.
+CREATE TABLE IF NOT EXISTS liquidation_events (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    trader_address TEXT NOT NULL,
+    market_id INTEGER REFERENCES markets(id),
+    size NUMERIC,
+    price NUMERIC,
+    amount NUMERIC,
+    detail TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS liquidation_events_trader ON liquidation_events(LOWER(trader_address), id);
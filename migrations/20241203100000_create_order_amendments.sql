@@ -0,0 +1,9 @@
+-- Nonces of signed amend requests. Each nonce can amend an order only once, so an earlier
+-- amend cannot be replayed.
+CREATE TABLE IF NOT EXISTS order_amendments (
+    id BIGSERIAL PRIMARY KEY,
+    eip712_hash TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (eip712_hash, nonce)
+);
@@ -0,0 +1,4 @@
+-- Nonces of signed withdrawal requests. A trader can use each nonce only once, so a withdrawal
+-- request cannot be replayed.
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS nonce TEXT;
+CREATE UNIQUE INDEX IF NOT EXISTS transfers_nonce ON transfers(LOWER(trader_address), nonce) WHERE nonce IS NOT NULL;
@@ -1,5 +1,5 @@
 // db/pool.rs
-use sqlx::{PgPool, postgres::PgConnectOptions};
+use sqlx::PgPool;
 use std::env;
 
 pub async fn create_pool() -> Result<PgPool, sqlx::Error> {
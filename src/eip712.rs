@@ -1,29 +1,406 @@
-use ethers::types::{Address, H256};
-use serde::Serialize;
+use bigdecimal::BigDecimal;
+use bigdecimal::num_bigint::ToBigInt;
+use ethereum_types::{Address, H256, U256};
+use sha3::{Digest, Keccak256};
+use std::str::FromStr;
 
-/// EIP712 Domain Separator.
-#[derive(Debug, Clone, Serialize)]
-pub struct EIP712Domain {
-    pub name: String,
-    pub version: String,
+/// EIP-712 type of the signing domain.
+pub const DOMAIN_TYPE: &str = "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+
+/// EIP-712 type of an order. Wallets sign this struct with `eth_signTypedData_v4`. Every field
+/// that changes how the order executes is part of it, so a relayer cannot alter any of them.
+pub const ORDER_TYPE: &str = "Order(address trader,uint256 marketId,uint8 side,uint8 orderType,uint8 timeInForce,uint256 amount,uint256 price,uint256 triggerPrice,uint256 worstPrice,uint256 maxSlippage,uint256 displayAmount,uint8 postOnly,uint8 selfTradePrevention,uint256 expiry,uint256 nonce)";
+
+/// EIP-712 type of a cancel request for a resting or stop order. It is only accepted until
+/// `expiry` (Unix seconds); order hashes are never reused, so it needs no nonce.
+pub const CANCEL_TYPE: &str = "Cancel(bytes32 orderHash,uint256 expiry)";
+
+/// EIP-712 type of an amend request. A zero price or amount leaves that field unchanged. Each
+/// `nonce` can amend an order once, and only until `expiry` (Unix seconds), so an earlier amend
+/// cannot be replayed to restore the order's old price and size.
+pub const AMEND_TYPE: &str = "Amend(bytes32 orderHash,uint256 price,uint256 amount,uint256 nonce,uint256 expiry)";
+
+/// EIP-712 type of a withdrawal request. Each `nonce` can be used by the trader once, and only
+/// until `expiry` (Unix seconds).
+pub const WITHDRAWAL_TYPE: &str = "Withdrawal(address trader,string asset,uint256 amount,uint256 nonce,uint256 expiry)";
+
+/// Decimal places of the fixed-point `uint256` amounts and prices in a signed order.
+pub const DECIMALS: u64 = 18;
+
+pub fn keccak256(data: &[u8]) -> H256 {
+    H256::from_slice(&Keccak256::digest(data))
+}
+
+pub fn type_hash(encoded_type: &str) -> H256 {
+    keccak256(encoded_type.as_bytes())
 }
 
-/// Define the Order schema for EIP712 hashing.
-#[derive(Debug, Clone, Serialize)]
+fn encode_uint(value: U256) -> [u8; 32] {
+    value.to_big_endian()
+}
+
+fn encode_address(address: &Address) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(address.as_bytes());
+    word
+}
+
+// Dynamic values (strings, bytes) are encoded as the hash of their contents
+fn encode_string(value: &str) -> [u8; 32] {
+    keccak256(value.as_bytes()).0
+}
+
+/// Converts a decimal amount to the 18-decimal fixed-point integer used in signed orders.
+/// Values that have no such representation (negative, more than 18 decimals, or too large for
+/// a `uint256`) are an error, since hashing a rounded value would not match what was signed.
+pub fn to_fixed_point(value: &BigDecimal) -> Result<U256, String> {
+    if *value < BigDecimal::from(0) {
+        return Err(format!("{} is negative", value));
+    }
+    let scaled = value.clone() * BigDecimal::from(10u64.pow(DECIMALS as u32));
+    let integer = scaled.with_scale(0);
+    if integer != scaled {
+        return Err(format!("{} has more than {} decimals", value, DECIMALS));
+    }
+    let (_, bytes) = integer.to_bigint().unwrap_or_default().to_bytes_be();
+    if bytes.len() > 32 {
+        return Err(format!("{} is too large", value));
+    }
+    Ok(U256::from_big_endian(&bytes))
+}
+
+/// `hashStruct(EIP712Domain)`.
+pub fn domain_separator(name: &str, version: &str, chain_id: u64, verifying_contract: &Address) -> H256 {
+    let encoded = [
+        type_hash(DOMAIN_TYPE).0,
+        encode_string(name),
+        encode_string(version),
+        encode_uint(U256::from(chain_id)),
+        encode_address(verifying_contract),
+    ]
+    .concat();
+    keccak256(&encoded)
+}
+
+/// The digest a wallet signs: `keccak256("\x19\x01" ‖ domainSeparator ‖ hashStruct(message))`.
+///
+/// Reference vector, identical to what ethers `TypedData::encode_eip712` and MetaMask
+/// `eth_signTypedData_v4` produce for the same typed data:
+/// - domain: name "DDX take-home", version "0.1.0", chainId 1,
+///   verifyingContract 0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC
+/// - order: trader 0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826, marketId 1, side 1 (Ask),
+///   orderType 0, timeInForce 0, amount 1.5 (1500000000000000000), price 2000.25
+///   (2000250000000000000000), triggerPrice 0, worstPrice 0, maxSlippage 2^256 - 1,
+///   displayAmount 0, postOnly 0, selfTradePrevention 0, expiry 0, nonce 7
+/// - digest: 0x303e6aa8b8005b18c915a61a6342868a610cf42d2dcc0a1cd9ff25b70e8bcce5
+pub fn typed_data_hash(domain_separator: &H256, struct_hash: &H256) -> H256 {
+    let encoded = [&[0x19u8, 0x01][..], domain_separator.as_bytes(), struct_hash.as_bytes()].concat();
+    keccak256(&encoded)
+}
+
+/// An order as it is signed, field for field with `ORDER_TYPE`.
+#[derive(Debug, Clone)]
 pub struct EIP712Order {
-    pub amount: u128,
-    pub nonce: u128,
-    pub price: u128,
-    pub side: u8,
-    pub trader_address: Address,
+    pub trader: Address,
+    pub market_id: u64,
+    pub side: u8,          // 0 = Bid, 1 = Ask
+    pub order_type: u8,    // 0 = Limit, 1 = Market, 2 = StopMarket, 3 = StopLimit
+    pub time_in_force: u8, // 0 = GTC, 1 = IOC, 2 = FOK, 3 = GTD
+    pub amount: U256,
+    pub price: U256,          // 0 for market orders
+    pub trigger_price: U256,  // 0 unless a stop order
+    pub worst_price: U256,    // 0 unless the market order has a worst price
+    pub max_slippage: U256,   // Fraction, U256::MAX when the order has no slippage bound
+    pub display_amount: U256, // 0 unless an iceberg order
+    pub post_only: u8,        // 0 = not post-only, 1 = Reject, 2 = Reprice
+    pub self_trade_prevention: u8, // 0 = account setting, 1 = CancelNewest, 2 = CancelOldest, 3 = CancelBoth, 4 = DecrementAndCancel
+    pub expiry: U256,         // Unix seconds, 0 unless GTD
+    pub nonce: U256,
 }
 
 impl EIP712Order {
-    /// Calculates an EIP712 hash for this order.
+    /// `hashStruct(Order)`.
     pub fn hash(&self) -> H256 {
-        // Placeholder for actual EIP712 hashing logic
-        // Using ethers-rs for simplified EIP712 hashing integration
-        // Implement hashing based on EIP-712 specs here
-        todo!()
+        let encoded = [
+            type_hash(ORDER_TYPE).0,
+            encode_address(&self.trader),
+            encode_uint(U256::from(self.market_id)),
+            encode_uint(U256::from(self.side)),
+            encode_uint(U256::from(self.order_type)),
+            encode_uint(U256::from(self.time_in_force)),
+            encode_uint(self.amount),
+            encode_uint(self.price),
+            encode_uint(self.trigger_price),
+            encode_uint(self.worst_price),
+            encode_uint(self.max_slippage),
+            encode_uint(self.display_amount),
+            encode_uint(U256::from(self.post_only)),
+            encode_uint(U256::from(self.self_trade_prevention)),
+            encode_uint(self.expiry),
+            encode_uint(self.nonce),
+        ]
+        .concat();
+        keccak256(&encoded)
+    }
+}
+
+/// `hashStruct(Cancel)`.
+pub fn cancel_hash(order_hash: &H256, expiry: U256) -> H256 {
+    let encoded = [type_hash(CANCEL_TYPE).0, order_hash.0, encode_uint(expiry)].concat();
+    keccak256(&encoded)
+}
+
+/// `hashStruct(Amend)`.
+pub fn amend_hash(order_hash: &H256, price: U256, amount: U256, nonce: U256, expiry: U256) -> H256 {
+    let encoded = [
+        type_hash(AMEND_TYPE).0,
+        order_hash.0,
+        encode_uint(price),
+        encode_uint(amount),
+        encode_uint(nonce),
+        encode_uint(expiry),
+    ]
+    .concat();
+    keccak256(&encoded)
+}
+
+/// `hashStruct(Withdrawal)`.
+pub fn withdrawal_hash(trader: &Address, asset: &str, amount: U256, nonce: U256, expiry: U256) -> H256 {
+    let encoded = [
+        type_hash(WITHDRAWAL_TYPE).0,
+        encode_address(trader),
+        encode_string(asset),
+        encode_uint(amount),
+        encode_uint(nonce),
+        encode_uint(expiry),
+    ]
+    .concat();
+    keccak256(&encoded)
+}
+
+/// Recovers the address that signed `digest`. `signature` is the 65-byte `r ‖ s ‖ v` hex string
+/// returned by `eth_signTypedData_v4`.
+pub fn recover_signer(digest: &H256, signature: &str) -> Result<Address, String> {
+    let signature = ethers::types::Signature::from_str(signature).map_err(|e| format!("Invalid signature: {}", e))?;
+    let signer = signature
+        .recover(ethers::types::H256::from_slice(digest.as_bytes()))
+        .map_err(|e| format!("Invalid signature: {}", e))?;
+    Ok(Address::from_slice(signer.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+    use ethers::types::transaction::eip712::{Eip712, TypedData};
+
+    // The reference vector in the `typed_data_hash` docs
+    fn reference_domain() -> H256 {
+        let verifying_contract = Address::from_str("0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC").unwrap();
+        domain_separator("DDX take-home", "0.1.0", 1, &verifying_contract)
+    }
+
+    fn reference_order() -> EIP712Order {
+        EIP712Order {
+            trader: Address::from_str("0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826").unwrap(),
+            market_id: 1,
+            side: 1,
+            order_type: 0,
+            time_in_force: 0,
+            amount: to_fixed_point(&BigDecimal::from_str("1.5").unwrap()).unwrap(),
+            price: to_fixed_point(&BigDecimal::from_str("2000.25").unwrap()).unwrap(),
+            trigger_price: U256::zero(),
+            worst_price: U256::zero(),
+            max_slippage: U256::MAX,
+            display_amount: U256::zero(),
+            post_only: 0,
+            self_trade_prevention: 0,
+            expiry: U256::zero(),
+            nonce: U256::from(7),
+        }
+    }
+
+    fn h256(hex: &str) -> H256 {
+        H256::from_str(hex).unwrap()
+    }
+
+    #[test]
+    fn order_type_hash_matches_vector() {
+        assert_eq!(type_hash(ORDER_TYPE), h256("0x0d8e2106a51e25d74109cdd9dc0d3ba3d86c6b8fec80df73c8d6818afbea2a3e"));
+    }
+
+    #[test]
+    fn domain_separator_matches_vector() {
+        assert_eq!(reference_domain(), h256("0x62cb0327436d9c4a5a390dad65f8c5ab0568f71ae3c71562d227a9f93442d855"));
+    }
+
+    #[test]
+    fn typed_data_hash_matches_vector() {
+        let order = reference_order();
+        assert_eq!(order.hash(), h256("0x03cc074453f3bba0850bfe17c9ce356063291a895cadb9a15b4cd851b584b04b"));
+        assert_eq!(
+            typed_data_hash(&reference_domain(), &order.hash()),
+            h256("0x303e6aa8b8005b18c915a61a6342868a610cf42d2dcc0a1cd9ff25b70e8bcce5")
+        );
+    }
+
+    // Same typed data as a wallet would receive it, hashed by ethers
+    #[test]
+    fn typed_data_hash_matches_ethers() {
+        let typed_data: TypedData = serde_json::from_value(serde_json::json!({
+            "types": {
+                "EIP712Domain": [
+                    {"name": "name", "type": "string"},
+                    {"name": "version", "type": "string"},
+                    {"name": "chainId", "type": "uint256"},
+                    {"name": "verifyingContract", "type": "address"}
+                ],
+                "Order": [
+                    {"name": "trader", "type": "address"},
+                    {"name": "marketId", "type": "uint256"},
+                    {"name": "side", "type": "uint8"},
+                    {"name": "orderType", "type": "uint8"},
+                    {"name": "timeInForce", "type": "uint8"},
+                    {"name": "amount", "type": "uint256"},
+                    {"name": "price", "type": "uint256"},
+                    {"name": "triggerPrice", "type": "uint256"},
+                    {"name": "worstPrice", "type": "uint256"},
+                    {"name": "maxSlippage", "type": "uint256"},
+                    {"name": "displayAmount", "type": "uint256"},
+                    {"name": "postOnly", "type": "uint8"},
+                    {"name": "selfTradePrevention", "type": "uint8"},
+                    {"name": "expiry", "type": "uint256"},
+                    {"name": "nonce", "type": "uint256"}
+                ]
+            },
+            "primaryType": "Order",
+            "domain": {
+                "name": "DDX take-home",
+                "version": "0.1.0",
+                "chainId": 1,
+                "verifyingContract": "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"
+            },
+            "message": {
+                "trader": "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826",
+                "marketId": 1,
+                "side": 1,
+                "orderType": 0,
+                "timeInForce": 0,
+                "amount": "1500000000000000000",
+                "price": "2000250000000000000000",
+                "triggerPrice": 0,
+                "worstPrice": 0,
+                "maxSlippage": "115792089237316195423570985008687907853269984665640564039457584007913129639935",
+                "displayAmount": 0,
+                "postOnly": 0,
+                "selfTradePrevention": 0,
+                "expiry": 0,
+                "nonce": 7
+            }
+        }))
+        .unwrap();
+        let digest = typed_data_hash(&reference_domain(), &reference_order().hash());
+        assert_eq!(digest.0, typed_data.encode_eip712().unwrap());
+    }
+
+    #[test]
+    fn to_fixed_point_rejects_values_it_cannot_represent() {
+        let fixed = |value: &str| to_fixed_point(&BigDecimal::from_str(value).unwrap());
+        assert_eq!(fixed("0.000000000000000001"), Ok(U256::one()));
+        assert_eq!(fixed("2000.250000000000000000000"), Ok(U256::from(2000250u64) * U256::exp10(15)));
+        assert!(fixed("-1").is_err());
+        assert!(fixed("0.0000000000000000015").is_err());
+        assert!(fixed("1e60").is_err());
+    }
+
+    #[test]
+    fn withdrawal_hash_matches_ethers() {
+        let typed_data: TypedData = serde_json::from_value(serde_json::json!({
+            "types": {
+                "EIP712Domain": [
+                    {"name": "name", "type": "string"},
+                    {"name": "version", "type": "string"},
+                    {"name": "chainId", "type": "uint256"},
+                    {"name": "verifyingContract", "type": "address"}
+                ],
+                "Withdrawal": [
+                    {"name": "trader", "type": "address"},
+                    {"name": "asset", "type": "string"},
+                    {"name": "amount", "type": "uint256"},
+                    {"name": "nonce", "type": "uint256"},
+                    {"name": "expiry", "type": "uint256"}
+                ]
+            },
+            "primaryType": "Withdrawal",
+            "domain": {
+                "name": "DDX take-home",
+                "version": "0.1.0",
+                "chainId": 1,
+                "verifyingContract": "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"
+            },
+            "message": {
+                "trader": "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826",
+                "asset": "USD",
+                "amount": "250000000000000000000",
+                "nonce": 3,
+                "expiry": 1735689600
+            }
+        }))
+        .unwrap();
+        let trader = Address::from_str("0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826").unwrap();
+        let amount = to_fixed_point(&BigDecimal::from(250)).unwrap();
+        let hash = withdrawal_hash(&trader, "USD", amount, U256::from(3), U256::from(1735689600u64));
+        assert_eq!(typed_data_hash(&reference_domain(), &hash).0, typed_data.encode_eip712().unwrap());
+    }
+
+    #[test]
+    fn execution_fields_change_the_hash() {
+        let order = reference_order();
+        let mut with_slippage = order.clone();
+        with_slippage.max_slippage = U256::zero();
+        let mut post_only = order.clone();
+        post_only.post_only = 1;
+        let mut stp = order.clone();
+        stp.self_trade_prevention = 2;
+        for altered in [with_slippage, post_only, stp] {
+            assert_ne!(altered.hash(), order.hash());
+        }
+    }
+
+    // Hardhat's first development account
+    fn wallet() -> LocalWallet {
+        LocalWallet::from_str("ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80").unwrap()
+    }
+
+    #[test]
+    fn recover_signer_returns_the_signer() {
+        let wallet = wallet();
+        let digest = typed_data_hash(&reference_domain(), &reference_order().hash());
+        let signature = wallet.sign_hash(ethers::types::H256::from(digest.0)).unwrap();
+        let signer = recover_signer(&digest, &signature.to_string()).unwrap();
+        assert_eq!(signer, Address::from_slice(wallet.address().as_bytes()));
+        assert_eq!(signer, Address::from_str("0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266").unwrap());
+    }
+
+    #[test]
+    fn recover_signer_rejects_tampering() {
+        let wallet = wallet();
+        let trader = Address::from_slice(wallet.address().as_bytes());
+        let digest = typed_data_hash(&reference_domain(), &reference_order().hash());
+        let signature = wallet.sign_hash(ethers::types::H256::from(digest.0)).unwrap();
+
+        // A flipped bit in the signature recovers someone else, or nothing at all
+        let mut bytes = signature.to_vec();
+        bytes[10] ^= 0x01;
+        let tampered: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_ne!(recover_signer(&digest, &tampered).ok(), Some(trader));
+
+        // The same signature over a changed order recovers someone else
+        let mut order = reference_order();
+        order.max_slippage = U256::zero();
+        let other_digest = typed_data_hash(&reference_domain(), &order.hash());
+        assert_ne!(recover_signer(&other_digest, &signature.to_string()).ok(), Some(trader));
+
+        assert!(recover_signer(&digest, "0x1234").is_err());
     }
 }
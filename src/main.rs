@@ -4,17 +4,46 @@ use crate::routes::account_routes::create_account;
 use crate::routes::account_routes::get_account;
 use crate::routes::account_routes::delete_account;
 use crate::routes::account_routes::update_account;
+use crate::routes::account_routes::get_account_ledger;
 use crate::routes::order_routes::create_order;
 use crate::routes::order_routes::initialize_app_state; // Import the AppState initialization function
 use crate::routes::order_routes::get_order_by_hash_route;
 use crate::routes::order_routes::delete_order_entry_by_hash_route;
 use crate::routes::order_routes::get_order_book;
-use sqlx::PgPool;
+use crate::routes::order_routes::run_expiry_sweeper;
+use crate::routes::order_routes::get_stop_order_route;
+use crate::routes::order_routes::delete_stop_order_route;
+use crate::routes::order_routes::amend_order_route;
+use crate::routes::market_routes::list_markets;
+use crate::routes::market_routes::create_market;
+use crate::routes::market_routes::get_auction;
+use crate::routes::market_routes::start_auction;
+use crate::routes::market_routes::uncross_auction_route;
+use crate::routes::market_routes::halt_market;
+use crate::routes::market_routes::resume_market;
+use crate::routes::market_routes::get_market_events;
+use crate::routes::asset_routes::list_assets;
+use crate::routes::asset_routes::create_asset;
+use crate::routes::transfer_routes::deposit;
+use crate::routes::transfer_routes::request_withdrawal;
+use crate::routes::transfer_routes::get_transfers;
+use crate::routes::transfer_routes::complete_withdrawal;
+use crate::routes::transfer_routes::reject_withdrawal;
+use crate::routes::position_routes::get_positions;
+use crate::routes::funding_routes::get_funding;
+use crate::routes::funding_routes::run_funding_engine;
+use crate::routes::liquidation_routes::get_liquidations;
+use crate::routes::liquidation_routes::get_insurance_fund;
+use crate::routes::liquidation_routes::run_liquidation_engine;
+use crate::oracle::{FileOracle, PriceOracle, StaticOracle};
+use std::sync::Arc;
 use dotenv::dotenv;
 mod routes;
 mod services;
 mod db;
 mod models;
+mod eip712;
+mod oracle;
 
 
 
@@ -26,7 +55,21 @@ async fn main() -> std::io::Result<()> {
 
     let db_pool = db::pool::create_pool().await.unwrap();
 
-    let app_state = web::Data::new(initialize_app_state(db_pool));
+    let app_state = web::Data::new(initialize_app_state(db_pool).await);
+
+    // Expire GTD orders in the background
+    actix_web::rt::spawn(run_expiry_sweeper(app_state.clone()));
+
+    // Index prices for perpetual markets come from the JSON file at ORACLE_FILE; without one
+    // they have no index price, so no mark price and no funding
+    let oracle: Arc<dyn PriceOracle> = match std::env::var("ORACLE_FILE") {
+        Ok(path) => Arc::new(FileOracle { path }),
+        Err(_) => Arc::new(StaticOracle::default()),
+    };
+    actix_web::rt::spawn(run_funding_engine(app_state.clone(), oracle));
+
+    // Liquidate accounts below maintenance margin at the mark prices the funding engine sets
+    actix_web::rt::spawn(run_liquidation_engine(app_state.clone()));
 
     HttpServer::new(move || {
         App::new()
@@ -36,11 +79,34 @@ async fn main() -> std::io::Result<()> {
             .route("/accounts", web::post().to(create_account))
             .route("/accounts/{trader_address}", web::get().to(get_account))
             .route("/accounts/{trader_address}", web::delete().to(delete_account)) 
-            .route("/orders", web::post().to(create_order)) // Route for adding an order
+            .route("/accounts/{trader_address}/ledger", web::get().to(get_account_ledger)) // Balance history and ledger-derived balances
             .route("/update_account", web::put().to(update_account))
-            .route("/orders/{hash}", web::get().to(get_order_by_hash_route)) // Route for getting an order by its hash
-            .route("/orders/{hash}", web::delete().to(delete_order_entry_by_hash_route)) // Route for getting an order by its hash
-            .route("/book", web::get().to(get_order_book)) // Add the new route
+            .route("/accounts/{trader_address}/deposits", web::post().to(deposit))
+            .route("/accounts/{trader_address}/withdrawals", web::post().to(request_withdrawal))
+            .route("/accounts/{trader_address}/transfers", web::get().to(get_transfers)) // Deposit and withdrawal history
+            .route("/accounts/{trader_address}/positions", web::get().to(get_positions)) // Perpetual positions and PnL
+            .route("/withdrawals/{id}/complete", web::post().to(complete_withdrawal))
+            .route("/withdrawals/{id}/reject", web::post().to(reject_withdrawal))
+            .route("/liquidations", web::get().to(get_liquidations)) // Liquidation audit trail, ?trader= for one trader
+            .route("/insurance_fund", web::get().to(get_insurance_fund))
+            .route("/assets", web::get().to(list_assets))
+            .route("/assets", web::post().to(create_asset)) // Register an asset before listing markets in it
+            .route("/markets", web::get().to(list_markets))
+            .route("/markets", web::post().to(create_market))
+            .route("/markets/{symbol}/auction", web::get().to(get_auction)) // Phase and indicative uncross price
+            .route("/markets/{symbol}/auction", web::post().to(start_auction))
+            .route("/markets/{symbol}/auction/uncross", web::post().to(uncross_auction_route))
+            .route("/markets/{symbol}/halt", web::post().to(halt_market))
+            .route("/markets/{symbol}/resume", web::post().to(resume_market))
+            .route("/markets/{symbol}/events", web::get().to(get_market_events)) // Halt and resume history
+            .route("/markets/{symbol}/funding", web::get().to(get_funding)) // Index, mark price and funding rate history
+            .route("/markets/{symbol}/orders", web::post().to(create_order)) // Route for adding an order
+            .route("/markets/{symbol}/orders/stops/{hash}", web::get().to(get_stop_order_route)) // Pending stop orders
+            .route("/markets/{symbol}/orders/stops/{hash}", web::delete().to(delete_stop_order_route))
+            .route("/markets/{symbol}/orders/{hash}", web::get().to(get_order_by_hash_route)) // Route for getting an order by its hash
+            .route("/markets/{symbol}/orders/{hash}", web::delete().to(delete_order_entry_by_hash_route)) // Route for getting an order by its hash
+            .route("/markets/{symbol}/orders/{hash}", web::patch().to(amend_order_route)) // Cancel-replace a resting order
+            .route("/markets/{symbol}/book", web::get().to(get_order_book)) // Add the new route
 
     })
     .bind("127.0.0.1:4321")?
@@ -1,9 +1,91 @@
 use serde::{Deserialize, Serialize};
 use bigdecimal::BigDecimal;
+use std::collections::BTreeMap;
 use crate::models::types::Address;
-#[derive(Serialize, Deserialize, Debug)]
+use crate::models::order::SelfTradePrevention;
+
+/// What a trader holds of one asset.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Balance {
+    pub available: BigDecimal, // Free to trade or withdraw
+    #[serde(default)]
+    pub locked: BigDecimal,    // Reserved by open orders and pending withdrawals
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Account {
     pub trader_address: Address,  // Ethereum address (20 bytes)
-    pub ddx_balance: BigDecimal,
-    pub usd_balance: BigDecimal,
+    #[serde(default)]
+    pub balances: BTreeMap<String, Balance>, // Keyed by asset symbol; assets not listed are zero
+    #[serde(default)]
+    pub stp_mode: Option<SelfTradePrevention>, // Default self-trade prevention for this trader's orders
+}
+
+impl Account {
+    /// An account holding nothing.
+    pub fn empty(trader_address: Address) -> Self {
+        Account { trader_address, balances: BTreeMap::new(), stp_mode: None }
+    }
+
+    /// Available balance of one asset.
+    pub fn available(&self, asset: &str) -> BigDecimal {
+        self.balances.get(asset).map(|balance| balance.available.clone()).unwrap_or_default()
+    }
+
+    /// Amount of one asset reserved by open orders and pending withdrawals.
+    pub fn locked(&self, asset: &str) -> BigDecimal {
+        self.balances.get(asset).map(|balance| balance.locked.clone()).unwrap_or_default()
+    }
+
+    pub fn balance_mut(&mut self, asset: &str) -> &mut Balance {
+        self.balances.entry(asset.to_string()).or_default()
+    }
+
+    /// Adds `amount` of `asset` to the available balance. The amount cannot be negative; profit
+    /// and loss go through `adjust`.
+    pub fn credit(&mut self, asset: &str, amount: &BigDecimal) -> Result<(), String> {
+        if *amount < BigDecimal::from(0) {
+            return Err(format!("Cannot credit a negative amount of {}: {}", asset, amount));
+        }
+        self.balance_mut(asset).available += amount.clone();
+        Ok(())
+    }
+
+    /// Adds a realized profit to, or takes a realized loss from, the available balance of `asset`.
+    /// A loss may take it below zero; liquidation covers that.
+    pub fn adjust(&mut self, asset: &str, amount: &BigDecimal) {
+        self.balance_mut(asset).available += amount.clone();
+    }
+
+    /// Moves `amount` of `asset` from available to locked, failing if too little is available.
+    pub fn lock(&mut self, asset: &str, amount: &BigDecimal) -> Result<(), String> {
+        if self.available(asset) < *amount {
+            return Err(format!("Insufficient {} balance for trader: {:?}", asset, self.trader_address));
+        }
+        let balance = self.balance_mut(asset);
+        balance.available -= amount.clone();
+        balance.locked += amount.clone();
+        Ok(())
+    }
+
+    /// Moves up to `amount` of `asset` back from locked to available.
+    pub fn release(&mut self, asset: &str, amount: &BigDecimal) {
+        let balance = self.balance_mut(asset);
+        let released = amount.clone().min(balance.locked.clone());
+        balance.locked -= released.clone();
+        balance.available += released;
+    }
+
+    /// Pays `amount` of `asset` out of locked funds, failing if more than is locked.
+    pub fn spend_locked(&mut self, asset: &str, amount: &BigDecimal) -> Result<(), String> {
+        let locked = self.locked(asset);
+        if locked < *amount {
+            return Err(format!(
+                "Cannot spend {} {} with only {} locked for trader: {:?}",
+                amount, asset, locked, self.trader_address
+            ));
+        }
+        self.balance_mut(asset).locked -= amount.clone();
+        Ok(())
+    }
 }
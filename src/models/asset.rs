@@ -0,0 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+/// An asset accounts can hold and markets can trade, e.g. USD or ETH.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Asset {
+    pub symbol: String,
+    pub decimals: i32, // Decimal places amounts of this asset may have
+}
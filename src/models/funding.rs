@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Utc};
+
+/// One funding payment in a perpetual market. Every open position paid or received
+/// `size * mark_price * rate`, longs paying shorts when the rate is positive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FundingRate {
+    pub id: i64,
+    pub market_id: i32,
+    pub index_price: BigDecimal,
+    pub mark_price: BigDecimal,
+    pub rate: BigDecimal,
+    pub created_at: DateTime<Utc>,
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+use bigdecimal::BigDecimal;
+use std::fmt;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Ledger account that takes the other side of admin adjustments and opening balances.
+pub const ADJUSTMENTS_ACCOUNT: &str = "adjustments";
+
+/// Ledger account for funds held outside the exchange: deposits come from it, withdrawals go to it.
+pub const EXTERNAL_ACCOUNT: &str = "external";
+
+/// Ledger account for the PnL of open perpetual positions: it pays out realized profits and
+/// collects realized losses, so its balance is what traders with open positions are owed in total.
+pub const CLEARING_ACCOUNT: &str = "clearing";
+
+/// Part of a trader's balance an entry moves.
+pub const AVAILABLE: &str = "available";
+pub const LOCKED: &str = "locked";
+
+/// Why balances moved. Every ledger transaction has exactly one cause.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum LedgerCause {
+    Fill,           // A trade; linked to the fill id
+    PerpetualFill,  // A trade in a perpetual market: margin released and PnL realized; linked to the fill id
+    Funding,        // Funding paid between longs and shorts, any residual to clearing; linked to the funding rate id
+    Liquidation,    // Positions taken over by the insurance fund, with its fee or cover; linked to the liquidation event id
+    AutoDeleverage, // Insurance fund position closed against a profitable trader; linked to the liquidation event id
+    Hold,           // Funds locked for or released by an order; linked to the order id
+    Adjustment,     // Balances set by an admin; linked to the request id
+    Deposit,        // Linked to the transfer id
+    Withdrawal,     // Requested, completed or rejected; linked to the transfer id
+    OpeningBalance, // Balances accounts held before the ledger existed; linked to the trader address
+}
+
+impl LedgerCause {
+    /// The ledger account that balances movements which bring funds in or take them out, `None`
+    /// for causes that only move funds between traders or within one account.
+    pub fn offset_account(&self) -> Option<&'static str> {
+        match self {
+            LedgerCause::Fill | LedgerCause::Hold => None,
+            LedgerCause::Adjustment | LedgerCause::OpeningBalance => Some(ADJUSTMENTS_ACCOUNT),
+            LedgerCause::Deposit | LedgerCause::Withdrawal => Some(EXTERNAL_ACCOUNT),
+            LedgerCause::PerpetualFill | LedgerCause::Funding | LedgerCause::Liquidation | LedgerCause::AutoDeleverage => {
+                Some(CLEARING_ACCOUNT)
+            }
+        }
+    }
+}
+
+impl fmt::Display for LedgerCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// One side of a balance movement. Credits are positive, debits negative, and the entries of a
+/// transaction sum to zero for each asset.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LedgerEntry {
+    pub id: i64,
+    pub transaction_id: Uuid,
+    pub account: String, // Trader address, or a system account such as `ADJUSTMENTS_ACCOUNT`
+    pub asset: String,
+    pub bucket: String,  // `AVAILABLE` or `LOCKED`
+    pub amount: BigDecimal,
+    pub cause: String,
+    pub cause_id: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A balance as the sum of a trader's ledger entries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LedgerBalance {
+    pub asset: String,
+    pub bucket: String,
+    pub amount: BigDecimal,
+}
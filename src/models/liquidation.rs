@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+use bigdecimal::BigDecimal;
+use std::fmt;
+use chrono::{DateTime, Utc};
+
+/// Address of the insurance fund's account. It takes over liquidated positions and closes them
+/// in the book, collects liquidation fees and covers what bankrupt traders cannot pay.
+pub const INSURANCE_FUND_ADDRESS: &str = "0x0000000000000000000000000000000000000001";
+
+/// One step of a liquidation.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum LiquidationEventKind {
+    MarginCall,       // A trader's equity fell below maintenance margin; amount is the equity
+    OrdersCancelled,  // The trader's open orders in the affected markets were cancelled
+    PositionTakeover, // The insurance fund took over a position at the mark price
+    InsuranceCover,   // The insurance fund paid a negative balance off; amount is what it paid
+    LiquidationFee,   // Remaining equity, up to the maintenance margin, went to the insurance fund
+    LiquidationOrder, // An order was sent to close a liquidated position, or one the insurance fund took over
+    AutoDeleverage,   // The insurance fund closed a position against a profitable trader
+}
+
+impl fmt::Display for LiquidationEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LiquidationEvent {
+    pub id: i64,
+    pub kind: String,           // `LiquidationEventKind`
+    pub trader_address: String, // Liquidated trader, or the deleveraged one
+    pub market_id: Option<i32>,
+    pub size: Option<BigDecimal>,   // Position size moved, signed as the trader held it
+    pub price: Option<BigDecimal>,
+    pub amount: Option<BigDecimal>, // Quote asset paid or received
+    pub detail: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A trader's margin in the perpetual markets of one collateral asset, at mark prices.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarginSummary {
+    pub trader_address: String,
+    pub collateral_asset: String,
+    pub balance: BigDecimal,        // Available collateral plus the margin held by perpetual orders
+    pub unrealized_pnl: BigDecimal,
+    pub equity: BigDecimal,         // Balance plus unrealized PnL
+    pub notional: BigDecimal,       // Open positions valued at the mark price
+    pub maintenance_margin: BigDecimal,
+}
+
+impl MarginSummary {
+    /// Equity as a fraction of open notional, `None` without open positions.
+    pub fn margin_ratio(&self) -> Option<BigDecimal> {
+        if self.notional == BigDecimal::from(0) {
+            return None;
+        }
+        Some(self.equity.clone() / self.notional.clone())
+    }
+
+    pub fn is_below_maintenance(&self) -> bool {
+        self.notional > BigDecimal::from(0) && self.equity < self.maintenance_margin
+    }
+}
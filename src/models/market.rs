@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use bigdecimal::BigDecimal;
+use std::fmt;
+use std::str::FromStr;
+use chrono::{DateTime, Utc};
+
+/// A tradable pair, e.g. DDX-USD: `base_asset` is what is bought and sold,
+/// `quote_asset` is what prices are expressed in. In a perpetual market the base asset never
+/// changes hands: fills move positions, and the quote asset is the collateral.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Market {
+    pub id: i32,
+    pub symbol: String,
+    pub base_asset: String,
+    pub quote_asset: String,
+    pub kind: MarketKind,
+    pub initial_margin: BigDecimal, // Perpetual only: fraction of notional posted as collateral to open
+    pub maintenance_margin: BigDecimal, // Perpetual only: fraction of notional below which positions are liquidated
+    pub funding: FundingRules,      // Perpetual only
+    pub rules: TradingRules,
+    pub matching_algorithm: MatchingAlgorithm,
+    pub fifo_slice: BigDecimal, // Pro-rata only: fraction of each level's fill given out in time priority first
+    pub protection: PriceProtection,
+}
+
+/// What trading in a market exchanges.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum MarketKind {
+    Spot,      // Fills swap base and quote balances
+    Perpetual, // Fills open and close positions against quote-asset collateral
+}
+
+impl fmt::Display for MarketKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for MarketKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "spot" => Ok(MarketKind::Spot),
+            "perpetual" | "perp" => Ok(MarketKind::Perpetual),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How often a perpetual market's longs and shorts pay each other funding, and how much.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FundingRules {
+    pub interval_secs: i64,
+    pub max_rate: BigDecimal, // Cap on the size of one interval's rate, e.g. 0.0075 for 0.75%
+}
+
+/// Limits on how far from the last trade orders may be priced, and how fast the price may move.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PriceProtection {
+    pub price_band: BigDecimal,        // Max fraction away from the last trade price, 0 disables
+    pub breaker_threshold: BigDecimal, // Max fraction the price may move within the window, 0 disables
+    pub breaker_window_secs: i64,
+    pub breaker_action: BreakerAction,
+}
+
+/// What the circuit breaker does to a market that moved too far too fast.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum BreakerAction {
+    Halt,    // Stop trading until resumed
+    Auction, // Switch to a volatility auction
+}
+
+impl fmt::Display for BreakerAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for BreakerAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "halt" => Ok(BreakerAction::Halt),
+            "auction" => Ok(BreakerAction::Auction),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How an incoming order's fill at one price level is shared between the resting orders there.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum MatchingAlgorithm {
+    Fifo,    // Price-time priority: oldest order first
+    ProRata, // In proportion to resting size
+}
+
+impl fmt::Display for MatchingAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for MatchingAlgorithm {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fifo" => Ok(MatchingAlgorithm::Fifo),
+            "prorata" | "pro_rata" => Ok(MatchingAlgorithm::ProRata),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Order size and price constraints enforced when orders are placed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradingRules {
+    pub price_tick: BigDecimal,   // Prices must be a multiple of this
+    pub amount_step: BigDecimal,  // Amounts must be a multiple of this
+    pub min_amount: BigDecimal,
+    pub max_amount: BigDecimal,
+    pub min_notional: BigDecimal, // Minimum amount * price, in the quote asset
+}
+
+impl Market {
+    pub fn is_perpetual(&self) -> bool {
+        self.kind == MarketKind::Perpetual
+    }
+
+    /// Collateral needed to hold a position or order of `notional` in a perpetual market.
+    pub fn margin_for(&self, notional: &BigDecimal) -> BigDecimal {
+        notional.abs() * self.initial_margin.clone()
+    }
+
+    /// Equity a position of `notional` needs to stay open in a perpetual market.
+    pub fn maintenance_margin_for(&self, notional: &BigDecimal) -> BigDecimal {
+        notional.abs() * self.maintenance_margin.clone()
+    }
+
+    /// Mark price of a perpetual market: the book mid, kept within the maximum funding rate of
+    /// the index price so a thin or lopsided book cannot drag it away. Without both sides of the
+    /// book it is the index price.
+    pub fn mark_price(&self, index_price: &BigDecimal, mid_price: Option<BigDecimal>) -> BigDecimal {
+        let max_premium = index_price.clone() * self.funding.max_rate.clone();
+        match mid_price {
+            Some(mid) => mid
+                .min(index_price.clone() + max_premium.clone())
+                .max(index_price.clone() - max_premium),
+            None => index_price.clone(),
+        }
+    }
+
+    /// Funding rate for one interval: the premium of the mark price over the index price, capped
+    /// at the market's maximum rate either way. Positive rates make longs pay shorts.
+    pub fn funding_rate(&self, mark_price: &BigDecimal, index_price: &BigDecimal) -> BigDecimal {
+        if *index_price <= BigDecimal::from(0) {
+            return BigDecimal::from(0);
+        }
+        let max_rate = self.funding.max_rate.clone();
+        let premium = ((mark_price.clone() - index_price.clone()) / index_price.clone()).with_scale(8);
+        premium.min(max_rate.clone()).max(-max_rate)
+    }
+
+    /// Checks a price against the tick size.
+    pub fn check_price(&self, label: &str, price: &BigDecimal) -> Result<(), String> {
+        let tick = &self.rules.price_tick;
+        if *tick > BigDecimal::from(0) && price.clone() % tick.clone() != BigDecimal::from(0) {
+            return Err(format!("{} {} is not a multiple of the tick size {}", label, price, tick));
+        }
+        Ok(())
+    }
+
+    /// Checks an amount against the lot size and the minimum and maximum order size.
+    pub fn check_amount(&self, label: &str, amount: &BigDecimal) -> Result<(), String> {
+        let step = &self.rules.amount_step;
+        if *step > BigDecimal::from(0) && amount.clone() % step.clone() != BigDecimal::from(0) {
+            return Err(format!("{} {} is not a multiple of the lot size {}", label, amount, step));
+        }
+        if *amount < self.rules.min_amount {
+            return Err(format!("{} {} is below the minimum of {}", label, amount, self.rules.min_amount));
+        }
+        if *amount > self.rules.max_amount {
+            return Err(format!("{} {} is above the maximum of {}", label, amount, self.rules.max_amount));
+        }
+        Ok(())
+    }
+
+    /// Checks a price against the band around the reference (last trade) price.
+    pub fn check_band(&self, label: &str, price: &BigDecimal, reference: Option<&BigDecimal>) -> Result<(), String> {
+        if let Some((low, high)) = self.price_band(reference) {
+            if *price < low || *price > high {
+                return Err(format!("{} {} is outside the price band {} - {}", label, price, low, high));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lowest and highest acceptable prices, if the market has a band and a reference price.
+    pub fn price_band(&self, reference: Option<&BigDecimal>) -> Option<(BigDecimal, BigDecimal)> {
+        let band = &self.protection.price_band;
+        match reference {
+            Some(reference) if *band > BigDecimal::from(0) => Some((
+                reference.clone() * (BigDecimal::from(1) - band.clone()),
+                reference.clone() * (BigDecimal::from(1) + band.clone()),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Start of the circuit breaker's look-back window.
+    pub fn breaker_window_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - chrono::Duration::seconds(self.protection.breaker_window_secs)
+    }
+
+    /// Whether trading at `price` moves the market further from `reference` than the breaker allows.
+    pub fn trips_breaker(&self, price: &BigDecimal, reference: &BigDecimal) -> bool {
+        let threshold = &self.protection.breaker_threshold;
+        *threshold > BigDecimal::from(0)
+            && *reference > BigDecimal::from(0)
+            && (price.clone() - reference.clone()).abs() > reference.clone() * threshold.clone()
+    }
+
+    /// Checks that amount * price reaches the minimum notional.
+    pub fn check_notional(&self, amount: &BigDecimal, price: &BigDecimal) -> Result<(), String> {
+        let notional = amount.clone() * price.clone();
+        if notional < self.rules.min_notional {
+            return Err(format!(
+                "Order value {} {} is below the minimum of {}",
+                notional, self.quote_asset, self.rules.min_notional
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether a market's book is matching continuously or collecting orders for a call auction.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub enum TradingPhase {
+    #[default]
+    Continuous,
+    Auction, // Orders rest without matching until the auction is uncrossed
+    Halted,  // No orders are accepted or matched until trading resumes
+}
+
+impl fmt::Display for TradingPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A change of trading phase, e.g. a circuit breaker halt or a resume.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketEvent {
+    pub id: i32,
+    pub market_id: i32,
+    pub phase: String, // Phase the market moved to
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
@@ -1,3 +1,10 @@
 pub mod account;
+pub mod asset;
 pub mod types;
-pub mod order;
\ No newline at end of file
+pub mod order;
+pub mod market;
+pub mod ledger;
+pub mod transfer;
+pub mod position;
+pub mod funding;
+pub mod liquidation;
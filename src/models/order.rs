@@ -1,12 +1,16 @@
 use serde::{Serialize, Deserialize};
 use bigdecimal::BigDecimal;
 use crate::models::types::Hash;
-use sha3::{Digest, Keccak256}; // For keccak hashing
-use ethereum_types::{H160, H256}; // Ethereum types
+use ethereum_types::{H160, H256, U256}; // Ethereum types
+use crate::eip712::{self, EIP712Order};
 use crate::models::types::Address;
 use crate::models::types::EIP712DomainSeparator;
 use std::fmt;
 use std::str::FromStr;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::cmp::Reverse;
+use chrono::{DateTime, Utc};
+use crate::models::market::TradingPhase;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum OrderSide {
@@ -32,101 +36,734 @@ impl FromStr for OrderSide {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Order {
-    pub amount: BigDecimal,  // Amount of asset
-    pub nonce: Hash,         // Unique order identifier (nonce)
-    pub price: BigDecimal,   // Price per unit
-    pub side: OrderSide,     // 'Bid' or 'Ask'
-    pub trader_address: Address, // Trader's Ethereum address
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum OrderType {
+    Limit,
+    Market,
+    StopMarket, // Becomes a market order once the trigger price trades
+    StopLimit,  // Becomes a limit order once the trigger price trades
 }
 
-impl Order {
-    // EIP-712 hash generation
-    pub fn eip712_hash(&self, domain: &EIP712DomainSeparator) -> Hash {
-        let domain_hash = self.get_domain_separator_hash(domain);
-        let order_hash = self.get_order_hash();
+impl fmt::Display for OrderType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
 
-        // Combine domain separator hash and order hash for final message hash
-        let combined = [domain_hash.as_bytes(), order_hash.as_bytes()].concat();
-        let final_hash = Keccak256::digest(&combined);
+impl FromStr for OrderType {
+    type Err = ();
 
-        H256::from_slice(&final_hash)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "limit" => Ok(OrderType::Limit),
+            "market" => Ok(OrderType::Market),
+            "stop_market" => Ok(OrderType::StopMarket),
+            "stop_limit" => Ok(OrderType::StopLimit),
+            _ => Err(()),
+        }
     }
+}
 
-    fn get_domain_separator_hash(&self, domain: &EIP712DomainSeparator) -> H256 {
-        // Generate the domain separator as per EIP-712 standard
-        let domain_data = format!("{}:{}", domain.name, domain.version);
-        let domain_hash = Keccak256::digest(domain_data.as_bytes());
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum TimeInForce {
+    GTC, // Good till cancel
+    IOC, // Immediate or cancel
+    FOK, // Fill or kill
+    GTD, // Good till date
+}
 
-        H256::from_slice(&domain_hash)
+impl fmt::Display for TimeInForce {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
     }
+}
 
-    fn get_order_hash(&self) -> H256 {
-        // Serialize the order fields according to EIP-712
-        let serialized_order = format!(
-            "{}:{}:{}:{}:{}",
-            self.amount.to_string(),
-            self.nonce.to_string(),
-            self.price.to_string(),
-            self.side.to_string(),
-            self.trader_address.to_string()
-        );
+impl FromStr for TimeInForce {
+    type Err = ();
 
-        let order_hash = Keccak256::digest(serialized_order.as_bytes());
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gtc" => Ok(TimeInForce::GTC),
+            "ioc" => Ok(TimeInForce::IOC),
+            "fok" => Ok(TimeInForce::FOK),
+            "gtd" => Ok(TimeInForce::GTD),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How to resolve an incoming order meeting a resting order from the same trader.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum SelfTradePrevention {
+    CancelNewest,       // Cancel what is left of the incoming order (default)
+    CancelOldest,       // Cancel the resting order and keep matching
+    CancelBoth,         // Cancel the resting order and the rest of the incoming order
+    DecrementAndCancel, // Reduce both by the smaller size without trading
+}
+
+impl fmt::Display for SelfTradePrevention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for SelfTradePrevention {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cancelnewest" | "cancel_newest" => Ok(SelfTradePrevention::CancelNewest),
+            "canceloldest" | "cancel_oldest" => Ok(SelfTradePrevention::CancelOldest),
+            "cancelboth" | "cancel_both" => Ok(SelfTradePrevention::CancelBoth),
+            "decrementandcancel" | "decrement_and_cancel" => Ok(SelfTradePrevention::DecrementAndCancel),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do with a post-only order that would cross the book.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum PostOnlyAction {
+    Reject,
+    Reprice, // Move the order one tick behind the best opposite price
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Order {
+    pub market_id: i32,      // Market the order trades in
+    #[serde(default)]
+    pub order_id: i64,       // Assigned by the engine on acceptance, not part of the order hash
+    pub amount: BigDecimal,  // Amount of asset
+    pub nonce: Hash,         // Chosen by the trader, never reused by the same trader
+    pub price: BigDecimal,   // Price per unit (ignored for market orders)
+    pub side: OrderSide,     // 'Bid' or 'Ask'
+    pub trader_address: Address, // Trader's Ethereum address
+    pub order_type: OrderType,   // 'Limit', 'Market', 'StopMarket' or 'StopLimit'
+    pub trigger_price: Option<BigDecimal>, // Stop orders: last trade price that activates the order
+    pub worst_price: Option<BigDecimal>,  // Market orders: never trade beyond this price
+    pub max_slippage: Option<BigDecimal>, // Market orders: max fraction away from the best price on arrival
+    pub display_amount: Option<BigDecimal>, // Iceberg orders: visible slice, the rest stays hidden
+    pub time_in_force: TimeInForce,
+    pub expires_at: Option<DateTime<Utc>>, // Required for GTD orders
+    pub post_only: Option<PostOnlyAction>, // Maker-only orders, None for regular orders
+    pub self_trade_prevention: Option<SelfTradePrevention>, // Falls back to the account setting
+    #[serde(skip)]
+    pub liquidation: bool, // Sent by the liquidation engine to close a position; reserves nothing
+}
+
+impl Order {
+    /// EIP-712 hash of the order: the digest the trader signs with `eth_signTypedData_v4`.
+    pub fn eip712_hash(&self, domain: &EIP712DomainSeparator) -> Result<Hash, String> {
+        Ok(eip712::typed_data_hash(&domain.hash(), &self.to_eip712()?.hash()))
+    }
 
-        H256::from_slice(&order_hash)
+    /// The order as an EIP-712 `Order` struct, see `eip712::ORDER_TYPE`. Fails if an amount or
+    /// price has no exact 18-decimal fixed-point value.
+    pub fn to_eip712(&self) -> Result<EIP712Order, String> {
+        let zero = BigDecimal::from(0);
+        let fixed = |field: &str, value: &BigDecimal| eip712::to_fixed_point(value).map_err(|e| format!("{} {}", field, e));
+        Ok(EIP712Order {
+            trader: self.trader_address,
+            market_id: self.market_id as u64,
+            side: match self.side {
+                OrderSide::Bid => 0,
+                OrderSide::Ask => 1,
+            },
+            order_type: match self.order_type {
+                OrderType::Limit => 0,
+                OrderType::Market => 1,
+                OrderType::StopMarket => 2,
+                OrderType::StopLimit => 3,
+            },
+            time_in_force: match self.time_in_force {
+                TimeInForce::GTC => 0,
+                TimeInForce::IOC => 1,
+                TimeInForce::FOK => 2,
+                TimeInForce::GTD => 3,
+            },
+            amount: fixed("Amount", &self.amount)?,
+            price: fixed("Price", &self.price)?,
+            trigger_price: fixed("Trigger price", self.trigger_price.as_ref().unwrap_or(&zero))?,
+            worst_price: fixed("Worst price", self.worst_price.as_ref().unwrap_or(&zero))?,
+            // A zero slippage bound is valid, so no bound is signed as the largest value instead
+            max_slippage: match &self.max_slippage {
+                Some(max_slippage) => fixed("Max slippage", max_slippage)?,
+                None => U256::MAX,
+            },
+            display_amount: fixed("Display amount", self.display_amount.as_ref().unwrap_or(&zero))?,
+            post_only: match self.post_only {
+                None => 0,
+                Some(PostOnlyAction::Reject) => 1,
+                Some(PostOnlyAction::Reprice) => 2,
+            },
+            self_trade_prevention: match self.self_trade_prevention {
+                None => 0,
+                Some(SelfTradePrevention::CancelNewest) => 1,
+                Some(SelfTradePrevention::CancelOldest) => 2,
+                Some(SelfTradePrevention::CancelBoth) => 3,
+                Some(SelfTradePrevention::DecrementAndCancel) => 4,
+            },
+            expiry: U256::from(self.expires_at.map_or(0, |expires_at| expires_at.timestamp().max(0) as u64)),
+            nonce: U256::from_big_endian(self.nonce.as_bytes()),
+        })
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OrderEntry {
+    pub market_id: i32,
+    #[serde(default)]
+    pub order_id: i64, // Assigned by the engine when the order was accepted
     pub amount: BigDecimal,
     pub price: BigDecimal,
     pub trader_address: Address,
     pub eip712_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub display_amount: Option<BigDecimal>, // Iceberg orders: size of each visible slice
+    #[serde(skip)]
+    pub hidden_amount: BigDecimal,          // Iceberg orders: reserve never shown in the book
 }
 
 impl OrderEntry {
-    // EIP-712 hash generation for OrderEntry
-    pub fn eip712_hash(&self, domain: &EIP712DomainSeparator) -> Hash {
-        let domain_hash = self.get_domain_separator_hash(domain);
-        let order_hash = self.get_order_hash();
+    /// Visible plus hidden amount still to be filled.
+    pub fn total_amount(&self) -> BigDecimal {
+        self.amount.clone() + self.hidden_amount.clone()
+    }
+
+    /// The order's canonical hash, as computed by `Order::eip712_hash` when it was placed.
+    pub fn hash(&self) -> Hash {
+        H256::from_str(&self.eip712_hash).unwrap_or_default()
+    }
+}
 
-        // Combine domain separator hash and order hash for final message hash
-        let combined = [domain_hash.as_bytes(), order_hash.as_bytes()].concat();
-        let final_hash = Keccak256::digest(&combined);
+/// A single price level: the aggregate resting amount plus a FIFO queue of order hashes.
+/// Cancelled orders are removed lazily from the queue, `live` counts the entries still resting.
+#[derive(Debug, Clone, Default)]
+pub struct PriceLevel {
+    pub total_amount: BigDecimal, // Visible amount only
+    hidden_amount: BigDecimal,    // Iceberg reserves resting at this price
+    live: usize,
+    queue: VecDeque<(u64, String)>, // (sequence, eip712_hash)
+}
 
-        H256::from_slice(&final_hash)
+impl PriceLevel {
+    fn compact(&mut self, orders: &HashMap<String, RestingOrder>) {
+        // Only rebuild the queue once stale entries outnumber live ones
+        if self.queue.len() > 2 * self.live + 16 {
+            self.queue.retain(|(seq, hash)| orders.get(hash).is_some_and(|o| o.sequence == *seq));
+        }
     }
+}
 
-    fn get_domain_separator_hash(&self, domain: &EIP712DomainSeparator) -> H256 {
-        // Generate the domain separator as per EIP-712 standard
-        let domain_data = format!("{}:{}", domain.name, domain.version);
-        let domain_hash = Keccak256::digest(domain_data.as_bytes());
+#[derive(Debug, Clone)]
+struct RestingOrder {
+    entry: OrderEntry,
+    side: OrderSide,
+    sequence: u64,
+}
+
+/// Price-level indexed order book. Levels are kept sorted by price, each level is a FIFO queue,
+/// and every resting order is indexed by its hash so cancels don't have to scan the book.
+#[derive(Debug, Clone, Default)]
+pub struct L2OrderBook {
+    bids: BTreeMap<BigDecimal, PriceLevel>,
+    asks: BTreeMap<BigDecimal, PriceLevel>,
+    orders: HashMap<String, RestingOrder>,
+    expiries: BTreeSet<(DateTime<Utc>, String)>, // GTD orders by expiry time
+    next_sequence: u64,
+    last_trade_price: Option<BigDecimal>,
+    index_price: Option<BigDecimal>, // Perpetual only: last price from the oracle
+    mark_price: Option<BigDecimal>,  // Perpetual only: price positions are valued and margined at
+    recent_trades: VecDeque<(DateTime<Utc>, BigDecimal)>, // Trade prices for the circuit breaker window
+    unchecked_trades: VecDeque<BigDecimal>, // Trade prices not yet checked against the stops, oldest first
+    pub stops: TriggerBook, // Stop orders waiting for their trigger price
+    pub phase: TradingPhase,
+}
 
-        H256::from_slice(&domain_hash)
+impl L2OrderBook {
+    pub fn new() -> Self {
+        L2OrderBook::default()
+    }
+
+    fn levels_mut(&mut self, side: &OrderSide) -> &mut BTreeMap<BigDecimal, PriceLevel> {
+        match side {
+            OrderSide::Bid => &mut self.bids,
+            OrderSide::Ask => &mut self.asks,
+        }
     }
 
-    fn get_order_hash(&self) -> H256 {
-        // Serialize the order fields according to EIP-712
-        let serialized_order = format!(
-            "{}:{}:{}",
-            self.amount.to_string(),
-            self.price.to_string(),
-            self.trader_address.to_string()
+    /// Adds an order at the back of its price level.
+    pub fn insert(&mut self, side: &OrderSide, entry: OrderEntry) {
+        if self.orders.contains_key(&entry.eip712_hash) {
+            self.remove(&entry.eip712_hash);
+        }
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let level = self.levels_mut(side).entry(entry.price.clone()).or_default();
+        level.total_amount += entry.amount.clone();
+        level.hidden_amount += entry.hidden_amount.clone();
+        level.live += 1;
+        level.queue.push_back((sequence, entry.eip712_hash.clone()));
+
+        if let Some(expires_at) = entry.expires_at {
+            self.expiries.insert((expires_at, entry.eip712_hash.clone()));
+        }
+
+        self.orders.insert(
+            entry.eip712_hash.clone(),
+            RestingOrder { entry, side: side.clone(), sequence },
         );
+    }
+
+    /// Removes an order by hash, returning it if it was resting in the book.
+    pub fn remove(&mut self, eip712_hash: &str) -> Option<OrderEntry> {
+        let resting = self.orders.remove(eip712_hash)?;
+        if let Some(expires_at) = resting.entry.expires_at {
+            self.expiries.remove(&(expires_at, eip712_hash.to_string()));
+        }
+        let levels = match resting.side {
+            OrderSide::Bid => &mut self.bids,
+            OrderSide::Ask => &mut self.asks,
+        };
+        if let Some(level) = levels.get_mut(&resting.entry.price) {
+            level.total_amount -= resting.entry.amount.clone();
+            level.hidden_amount -= resting.entry.hidden_amount.clone();
+            level.live -= 1;
+            if level.live == 0 {
+                levels.remove(&resting.entry.price);
+            } else {
+                level.compact(&self.orders);
+            }
+        }
+        Some(resting.entry)
+    }
+
+    pub fn get(&self, eip712_hash: &str) -> Option<&OrderEntry> {
+        self.orders.get(eip712_hash).map(|o| &o.entry)
+    }
+
+    pub fn side_of(&self, eip712_hash: &str) -> Option<OrderSide> {
+        self.orders.get(eip712_hash).map(|o| o.side.clone())
+    }
+
+    /// Changes the total remaining amount of an order in place, keeping its time priority.
+    /// Iceberg orders keep at most their current visible slice and put the rest in reserve.
+    /// An amount of zero or less removes the order.
+    pub fn set_amount(&mut self, eip712_hash: &str, amount: BigDecimal) {
+        let visible = match self.get(eip712_hash) {
+            Some(entry) if entry.display_amount.is_some() => entry.amount.clone().min(amount.clone()),
+            Some(_) => amount.clone(),
+            None => return,
+        };
+        let hidden = amount - visible.clone();
+        self.set_parts(eip712_hash, visible, hidden);
+    }
+
+    fn set_parts(&mut self, eip712_hash: &str, visible: BigDecimal, hidden: BigDecimal) {
+        if visible <= BigDecimal::from(0) {
+            self.remove(eip712_hash);
+            return;
+        }
+        let (side, price, visible_delta, hidden_delta) = match self.orders.get_mut(eip712_hash) {
+            Some(resting) => {
+                let entry = &mut resting.entry;
+                let visible_delta = visible.clone() - entry.amount.clone();
+                let hidden_delta = hidden.clone() - entry.hidden_amount.clone();
+                entry.amount = visible;
+                entry.hidden_amount = hidden;
+                (resting.side.clone(), entry.price.clone(), visible_delta, hidden_delta)
+            }
+            None => return,
+        };
+        if let Some(level) = self.levels_mut(&side).get_mut(&price) {
+            level.total_amount += visible_delta;
+            level.hidden_amount += hidden_delta;
+        }
+    }
+
+    /// Takes `amount` off the visible part of a resting order. Once an iceberg's visible slice
+    /// is used up it is refilled from the reserve and the order goes to the back of its level.
+    pub fn fill(&mut self, eip712_hash: &str, amount: BigDecimal) {
+        let mut entry = match self.get(eip712_hash) {
+            Some(entry) => entry.clone(),
+            None => return,
+        };
+        let visible = entry.amount.clone() - amount;
+        if visible > BigDecimal::from(0) || entry.hidden_amount <= BigDecimal::from(0) {
+            self.set_parts(eip712_hash, visible, entry.hidden_amount);
+            return;
+        }
+
+        // Replenish the iceberg from its reserve, losing time priority
+        let side = match self.side_of(eip712_hash) {
+            Some(side) => side,
+            None => return,
+        };
+        let slice = entry.display_amount.clone().unwrap_or_default().min(entry.hidden_amount.clone());
+        entry.hidden_amount -= slice.clone();
+        entry.amount = slice;
+        self.remove(eip712_hash);
+        self.insert(&side, entry);
+    }
+
+    /// Removes every GTD order whose expiry is at or before `now`, with the side it rested on.
+    pub fn remove_expired(&mut self, now: DateTime<Utc>) -> Vec<(OrderSide, OrderEntry)> {
+        let expired: Vec<String> = self
+            .expiries
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .map(|(_, hash)| hash.clone())
+            .collect();
+        expired
+            .iter()
+            .filter_map(|hash| {
+                let side = self.side_of(hash)?;
+                self.remove(hash).map(|entry| (side, entry))
+            })
+            .collect()
+    }
+
+    pub fn last_trade_price(&self) -> Option<&BigDecimal> {
+        self.last_trade_price.as_ref()
+    }
+
+    pub fn index_price(&self) -> Option<&BigDecimal> {
+        self.index_price.as_ref()
+    }
+
+    /// Mark price if the funding engine has set one, else the last trade price.
+    pub fn mark_price(&self) -> Option<&BigDecimal> {
+        self.mark_price.as_ref().or(self.last_trade_price.as_ref())
+    }
+
+    pub fn set_prices(&mut self, index_price: BigDecimal, mark_price: BigDecimal) {
+        self.index_price = Some(index_price);
+        self.mark_price = Some(mark_price);
+    }
+
+    pub fn record_trade(&mut self, price: BigDecimal) {
+        self.recent_trades.push_back((Utc::now(), price.clone()));
+        self.unchecked_trades.push_back(price.clone());
+        self.last_trade_price = Some(price);
+    }
+
+    /// Price to check the stops against next: the oldest trade they have not been checked
+    /// against yet, or the last trade price once they have been checked against every trade.
+    pub fn trigger_check_price(&self) -> Option<&BigDecimal> {
+        self.unchecked_trades.front().or(self.last_trade_price.as_ref())
+    }
+
+    /// Marks the oldest unchecked trade as checked. Returns `false` if there was none left.
+    pub fn finish_trigger_check(&mut self) -> bool {
+        self.unchecked_trades.pop_front().is_some()
+    }
+
+    /// Forgets the trades the stops have not been checked against, e.g. when trading stops.
+    pub fn skip_trigger_checks(&mut self) {
+        self.unchecked_trades.clear();
+    }
+
+    /// Starts the circuit breaker window afresh from the last trade price, e.g. after a halt.
+    pub fn restart_price_window(&mut self) {
+        self.recent_trades.clear();
+        if let Some(price) = &self.last_trade_price {
+            self.recent_trades.push_back((Utc::now(), price.clone()));
+        }
+    }
+
+    /// The price the market stood at `since`: the last trade before it, or the first trade after
+    /// it if there was none before. Trades older than that are forgotten.
+    pub fn reference_price_since(&mut self, since: DateTime<Utc>) -> Option<BigDecimal> {
+        while self.recent_trades.len() > 1 && self.recent_trades[1].0 <= since {
+            self.recent_trades.pop_front();
+        }
+        self.recent_trades.front().map(|(_, price)| price.clone())
+    }
+
+    pub fn best_bid(&self) -> Option<&BigDecimal> {
+        self.bids.keys().next_back()
+    }
+
+    pub fn best_ask(&self) -> Option<&BigDecimal> {
+        self.asks.keys().next()
+    }
+
+    /// Halfway between the best bid and ask, if both sides have orders.
+    pub fn mid_price(&self) -> Option<BigDecimal> {
+        Some((self.best_bid()?.clone() + self.best_ask()?.clone()) / BigDecimal::from(2))
+    }
+
+    /// Returns the order at the front of the best price level on the given side.
+    pub fn front(&mut self, side: &OrderSide) -> Option<OrderEntry> {
+        let orders = &self.orders;
+        let levels = match side {
+            OrderSide::Bid => &mut self.bids,
+            OrderSide::Ask => &mut self.asks,
+        };
+        let level = match side {
+            OrderSide::Bid => levels.values_mut().next_back()?,
+            OrderSide::Ask => levels.values_mut().next()?,
+        };
+        // Drop cancelled entries sitting at the front of the queue
+        while let Some((seq, hash)) = level.queue.front() {
+            match orders.get(hash) {
+                Some(resting) if resting.sequence == *seq => return Some(resting.entry.clone()),
+                _ => {
+                    level.queue.pop_front();
+                }
+            }
+        }
+        None
+    }
+
+    /// Aggregated visible (price, amount) levels in priority order, best price first.
+    pub fn depth(&self, side: &OrderSide) -> Vec<(BigDecimal, BigDecimal)> {
+        let levels = match side {
+            OrderSide::Bid => &self.bids,
+            OrderSide::Ask => &self.asks,
+        };
+        let iter: Box<dyn Iterator<Item = (&BigDecimal, &PriceLevel)>> = match side {
+            OrderSide::Bid => Box::new(levels.iter().rev()),
+            OrderSide::Ask => Box::new(levels.iter()),
+        };
+        iter.map(|(price, level)| (price.clone(), level.total_amount.clone())).collect()
+    }
 
-        let order_hash = Keccak256::digest(serialized_order.as_bytes());
+    /// Visible plus hidden (price, amount) levels, best price first. Only for sizing what an
+    /// incoming order can fill; never publish this as market data.
+    pub fn total_depth(&self, side: &OrderSide) -> Vec<(BigDecimal, BigDecimal)> {
+        let levels = match side {
+            OrderSide::Bid => &self.bids,
+            OrderSide::Ask => &self.asks,
+        };
+        let iter: Box<dyn Iterator<Item = (&BigDecimal, &PriceLevel)>> = match side {
+            OrderSide::Bid => Box::new(levels.iter().rev()),
+            OrderSide::Ask => Box::new(levels.iter()),
+        };
+        iter.map(|(price, level)| (price.clone(), level.total_amount.clone() + level.hidden_amount.clone()))
+            .collect()
+    }
+
+    /// The single price at which crossing orders would uncross in a call auction, with the volume
+    /// that would trade there. Maximises traded volume; ties go to the smallest imbalance between
+    /// the two sides, then to the price closest to the last trade, then to the lower price.
+    /// Hidden iceberg reserves take part, as they do at the uncross, so never publish this.
+    pub fn equilibrium(&self) -> Option<(BigDecimal, BigDecimal)> {
+        self.equilibrium_of(&self.total_depth(&OrderSide::Bid), &self.total_depth(&OrderSide::Ask))
+    }
+
+    /// Indicative auction price and volume for market data: the equilibrium of the displayed
+    /// depth only, so hidden iceberg reserves cannot be read off it. The uncross may trade more.
+    pub fn indicative_equilibrium(&self) -> Option<(BigDecimal, BigDecimal)> {
+        self.equilibrium_of(&self.depth(&OrderSide::Bid), &self.depth(&OrderSide::Ask))
+    }
+
+    fn equilibrium_of(&self, bids: &[(BigDecimal, BigDecimal)], asks: &[(BigDecimal, BigDecimal)]) -> Option<(BigDecimal, BigDecimal)> {
+        let zero = BigDecimal::from(0);
+        let mut best: Option<(BigDecimal, BigDecimal, BigDecimal, BigDecimal)> = None; // price, volume, imbalance, distance
 
-        H256::from_slice(&order_hash)
+        for price in bids.iter().chain(asks.iter()).map(|(price, _)| price) {
+            let demand = bids
+                .iter()
+                .take_while(|(bid_price, _)| bid_price >= price)
+                .fold(zero.clone(), |total, (_, amount)| total + amount.clone());
+            let supply = asks
+                .iter()
+                .take_while(|(ask_price, _)| ask_price <= price)
+                .fold(zero.clone(), |total, (_, amount)| total + amount.clone());
+            let volume = demand.clone().min(supply.clone());
+            if volume <= zero {
+                continue;
+            }
+            let imbalance = (demand - supply).abs();
+            let distance = match &self.last_trade_price {
+                Some(last) => (price.clone() - last.clone()).abs(),
+                None => zero.clone(),
+            };
+
+            let better = match &best {
+                None => true,
+                Some((best_price, best_volume, best_imbalance, best_distance)) => {
+                    (volume.clone(), Reverse(imbalance.clone()), Reverse(distance.clone()), Reverse(price.clone()))
+                        > (best_volume.clone(), Reverse(best_imbalance.clone()), Reverse(best_distance.clone()), Reverse(best_price.clone()))
+                }
+            };
+            if better {
+                best = Some((price.clone(), volume, imbalance, distance));
+            }
+        }
+        best.map(|(price, volume, _, _)| (price, volume))
+    }
+
+    /// All resting orders on one side in price-time priority.
+    pub fn entries(&self, side: &OrderSide) -> Vec<OrderEntry> {
+        let levels = match side {
+            OrderSide::Bid => &self.bids,
+            OrderSide::Ask => &self.asks,
+        };
+        let iter: Box<dyn Iterator<Item = &PriceLevel>> = match side {
+            OrderSide::Bid => Box::new(levels.values().rev()),
+            OrderSide::Ask => Box::new(levels.values()),
+        };
+        iter.flat_map(|level| level.queue.iter())
+            .filter_map(|(seq, hash)| {
+                self.orders
+                    .get(hash)
+                    .filter(|o| o.sequence == *seq)
+                    .map(|o| o.entry.clone())
+            })
+            .collect()
+    }
+
+    /// Resting orders at one price level, oldest first.
+    pub fn level_entries(&self, side: &OrderSide, price: &BigDecimal) -> Vec<OrderEntry> {
+        let levels = match side {
+            OrderSide::Bid => &self.bids,
+            OrderSide::Ask => &self.asks,
+        };
+        let level = match levels.get(price) {
+            Some(level) => level,
+            None => return Vec::new(),
+        };
+        level
+            .queue
+            .iter()
+            .filter_map(|(seq, hash)| {
+                self.orders
+                    .get(hash)
+                    .filter(|o| o.sequence == *seq)
+                    .map(|o| o.entry.clone())
+            })
+            .collect()
+    }
+
+    /// Hashes of one trader's resting orders.
+    pub fn hashes_of(&self, trader_address: &Address) -> Vec<String> {
+        self.orders
+            .iter()
+            .filter(|(_, resting)| resting.entry.trader_address == *trader_address)
+            .map(|(hash, _)| hash.clone())
+            .collect()
+    }
+
+    pub fn bids(&self) -> Vec<OrderEntry> {
+        self.entries(&OrderSide::Bid)
+    }
+
+    pub fn asks(&self) -> Vec<OrderEntry> {
+        self.entries(&OrderSide::Ask)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct L2OrderBook {
-    pub bids: Vec<OrderEntry>, 
-    pub asks: Vec<OrderEntry>, 
+// Only the tests and the benchmark need these
+#[allow(dead_code)]
+impl L2OrderBook {
+    pub fn contains(&self, eip712_hash: &str) -> bool {
+        self.orders.contains_key(eip712_hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PendingStop {
+    order: Order,
+    sequence: u64,
+}
+
+/// Stop orders waiting to be activated, keyed by trigger price. Buy stops trigger once the last
+/// trade price rises to their trigger, sell stops once it falls to it.
+#[derive(Debug, Clone, Default)]
+pub struct TriggerBook {
+    buy_stops: BTreeMap<(BigDecimal, u64), String>,           // Lowest trigger first
+    sell_stops: BTreeMap<(Reverse<BigDecimal>, u64), String>, // Highest trigger first
+    orders: HashMap<String, PendingStop>,
+    next_sequence: u64,
+}
+
+impl TriggerBook {
+    pub fn insert(&mut self, eip712_hash: String, order: Order) {
+        if self.orders.contains_key(&eip712_hash) {
+            self.remove(&eip712_hash);
+        }
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let trigger_price = order.trigger_price.clone().unwrap_or_default();
+        match order.side {
+            OrderSide::Bid => self.buy_stops.insert((trigger_price, sequence), eip712_hash.clone()),
+            OrderSide::Ask => self.sell_stops.insert((Reverse(trigger_price), sequence), eip712_hash.clone()),
+        };
+        self.orders.insert(eip712_hash, PendingStop { order, sequence });
+    }
+
+    pub fn remove(&mut self, eip712_hash: &str) -> Option<Order> {
+        let pending = self.orders.remove(eip712_hash)?;
+        let trigger_price = pending.order.trigger_price.clone().unwrap_or_default();
+        match pending.order.side {
+            OrderSide::Bid => self.buy_stops.remove(&(trigger_price, pending.sequence)),
+            OrderSide::Ask => self.sell_stops.remove(&(Reverse(trigger_price), pending.sequence)),
+        };
+        Some(pending.order)
+    }
+
+    pub fn get(&self, eip712_hash: &str) -> Option<&Order> {
+        self.orders.get(eip712_hash).map(|p| &p.order)
+    }
+
+    /// Hashes of one trader's pending stops.
+    pub fn hashes_of(&self, trader_address: &Address) -> Vec<String> {
+        self.orders
+            .iter()
+            .filter(|(_, pending)| pending.order.trader_address == *trader_address)
+            .map(|(hash, _)| hash.clone())
+            .collect()
+    }
+
+    /// Removes every GTD stop whose expiry is at or before `now`.
+    pub fn remove_expired(&mut self, now: DateTime<Utc>) -> Vec<(String, Order)> {
+        let expired: Vec<String> = self
+            .orders
+            .iter()
+            .filter(|(_, pending)| pending.order.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|hash| self.remove(&hash).map(|order| (hash, order)))
+            .collect()
+    }
+
+    /// Takes the next stop triggered by `last_price`. Buy stops go first, lowest trigger first,
+    /// then sell stops, highest trigger first; stops sharing a trigger price go in arrival order.
+    pub fn pop_triggered(&mut self, last_price: &BigDecimal) -> Option<(String, Order)> {
+        let buy = self
+            .buy_stops
+            .iter()
+            .next()
+            .filter(|((trigger, _), _)| trigger <= last_price)
+            .map(|(_, hash)| hash.clone());
+        let sell = || {
+            self.sell_stops
+                .iter()
+                .next()
+                .filter(|((Reverse(trigger), _), _)| trigger >= last_price)
+                .map(|(_, hash)| hash.clone())
+        };
+        let eip712_hash = buy.or_else(sell)?;
+        let order = self.remove(&eip712_hash)?;
+        Some((eip712_hash, order))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -139,11 +776,345 @@ pub struct L2OrderBookGetResponse {
 impl Default for Order {
     fn default() -> Self {
         Order {
+            market_id: 1,                     // Default to the DDX-USD market
+            order_id: 0,                      // Not yet accepted
             amount: BigDecimal::from(0),
             price: BigDecimal::from(0),
             side: OrderSide::Bid,
             nonce: H256::zero(),             // Default nonce to 0
             trader_address: H160::zero(),     // Default trader address to zero
+            order_type: OrderType::Limit,
+            trigger_price: None,
+            worst_price: None,
+            max_slippage: None,
+            display_amount: None,
+            time_in_force: TimeInForce::GTC,
+            expires_at: None,
+            post_only: None,
+            self_trade_prevention: None,
+            liquidation: false,
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).unwrap()
+    }
+
+    fn entry(hash: &str, price: &str, amount: &str) -> OrderEntry {
+        OrderEntry {
+            market_id: 1,
+            order_id: 0,
+            amount: dec(amount),
+            price: dec(price),
+            trader_address: H160::from_low_u64_be(1),
+            eip712_hash: hash.to_string(),
+            expires_at: None,
+            display_amount: None,
+            hidden_amount: BigDecimal::from(0),
+        }
+    }
+
+    fn hashes(entries: &[OrderEntry]) -> Vec<String> {
+        entries.iter().map(|entry| entry.eip712_hash.clone()).collect()
+    }
+
+    // The Vec-based book the price-level book replaced: each side kept sorted best price first,
+    // with a stable sort so orders at one price stay in arrival order
+    #[derive(Default)]
+    struct VecBook {
+        bids: Vec<OrderEntry>,
+        asks: Vec<OrderEntry>,
+    }
+
+    impl VecBook {
+        fn insert(&mut self, side: &OrderSide, entry: OrderEntry) {
+            match side {
+                OrderSide::Bid => {
+                    self.bids.push(entry);
+                    self.bids.sort_by(|a, b| b.price.cmp(&a.price));
+                }
+                OrderSide::Ask => {
+                    self.asks.push(entry);
+                    self.asks.sort_by(|a, b| a.price.cmp(&b.price));
+                }
+            }
+        }
+
+        fn remove(&mut self, eip712_hash: &str) {
+            self.bids.retain(|entry| entry.eip712_hash != eip712_hash);
+            self.asks.retain(|entry| entry.eip712_hash != eip712_hash);
+        }
+
+        fn take(&mut self, side: &OrderSide, price: &BigDecimal, amount: &BigDecimal) -> Vec<(String, BigDecimal, BigDecimal)> {
+            let mut fills = Vec::new();
+            let mut remaining = amount.clone();
+            let opposite = match side {
+                OrderSide::Bid => &mut self.asks,
+                OrderSide::Ask => &mut self.bids,
+            };
+            while remaining > BigDecimal::from(0) && !opposite.is_empty() {
+                let crosses = match side {
+                    OrderSide::Bid => opposite[0].price <= *price,
+                    OrderSide::Ask => opposite[0].price >= *price,
+                };
+                if !crosses {
+                    break;
+                }
+                let fill_amount = remaining.clone().min(opposite[0].amount.clone());
+                fills.push((opposite[0].eip712_hash.clone(), opposite[0].price.clone(), fill_amount.clone()));
+                remaining -= fill_amount.clone();
+                opposite[0].amount -= fill_amount;
+                if opposite[0].amount <= BigDecimal::from(0) {
+                    opposite.remove(0);
+                }
+            }
+            fills
+        }
+    }
+
+    // Takes liquidity the way `match_order` walks a FIFO market: always the front order of the
+    // best opposite level
+    fn take(book: &mut L2OrderBook, side: &OrderSide, price: &BigDecimal, amount: &BigDecimal) -> Vec<(String, BigDecimal, BigDecimal)> {
+        let mut fills = Vec::new();
+        let mut remaining = amount.clone();
+        let opposite = match side {
+            OrderSide::Bid => OrderSide::Ask,
+            OrderSide::Ask => OrderSide::Bid,
+        };
+        while remaining > BigDecimal::from(0) {
+            let resting = match book.front(&opposite) {
+                Some(resting) => resting,
+                None => break,
+            };
+            let crosses = match side {
+                OrderSide::Bid => resting.price <= *price,
+                OrderSide::Ask => resting.price >= *price,
+            };
+            if !crosses {
+                break;
+            }
+            let fill_amount = remaining.clone().min(resting.amount.clone());
+            fills.push((resting.eip712_hash.clone(), resting.price.clone(), fill_amount.clone()));
+            remaining -= fill_amount.clone();
+            book.fill(&resting.eip712_hash, fill_amount);
+        }
+        fills
+    }
+
+    #[test]
+    fn insert_orders_by_price_then_time() {
+        let mut book = L2OrderBook::new();
+        book.insert(&OrderSide::Bid, entry("b1", "99", "1"));
+        book.insert(&OrderSide::Bid, entry("b2", "100", "1"));
+        book.insert(&OrderSide::Bid, entry("b3", "99", "1"));
+        book.insert(&OrderSide::Ask, entry("a1", "102", "1"));
+        book.insert(&OrderSide::Ask, entry("a2", "101", "1"));
+        book.insert(&OrderSide::Ask, entry("a3", "101", "2"));
+
+        assert_eq!(hashes(&book.bids()), ["b2", "b1", "b3"]);
+        assert_eq!(hashes(&book.asks()), ["a2", "a3", "a1"]);
+        assert_eq!(book.best_bid(), Some(&dec("100")));
+        assert_eq!(book.best_ask(), Some(&dec("101")));
+        assert_eq!(book.depth(&OrderSide::Ask), vec![(dec("101"), dec("3")), (dec("102"), dec("1"))]);
+        assert_eq!(book.len(), 6);
+    }
+
+    #[test]
+    fn cancel_removes_order_and_empty_level() {
+        let mut book = L2OrderBook::new();
+        book.insert(&OrderSide::Ask, entry("a1", "101", "1"));
+        book.insert(&OrderSide::Ask, entry("a2", "101", "2"));
+        book.insert(&OrderSide::Ask, entry("a3", "102", "1"));
+
+        assert_eq!(book.remove("a1").map(|entry| entry.eip712_hash), Some("a1".to_string()));
+        assert!(book.remove("a1").is_none());
+        assert!(!book.contains("a1"));
+        assert_eq!(book.depth(&OrderSide::Ask), vec![(dec("101"), dec("2")), (dec("102"), dec("1"))]);
+        assert_eq!(book.front(&OrderSide::Ask).map(|entry| entry.eip712_hash), Some("a2".to_string()));
+
+        book.remove("a2");
+        assert_eq!(book.best_ask(), Some(&dec("102")));
+        assert_eq!(book.depth(&OrderSide::Ask), vec![(dec("102"), dec("1"))]);
+    }
+
+    #[test]
+    fn cancelled_entries_are_compacted_lazily() {
+        let mut book = L2OrderBook::new();
+        for i in 0..100 {
+            book.insert(&OrderSide::Bid, entry(&format!("b{}", i), "100", "1"));
+        }
+        for i in 0..99 {
+            book.remove(&format!("b{}", i));
+        }
+
+        // Stale queue entries are dropped once they outnumber live ones, not on every cancel
+        let level = &book.bids[&dec("100")];
+        assert_eq!(level.live, 1);
+        assert!(level.queue.len() <= 2 * level.live + 16);
+        assert_eq!(level.total_amount, dec("1"));
+        assert_eq!(book.front(&OrderSide::Bid).map(|entry| entry.eip712_hash), Some("b99".to_string()));
+        assert_eq!(hashes(&book.bids()), ["b99"]);
+    }
+
+    #[test]
+    fn reinserted_order_goes_to_back_of_level() {
+        let mut book = L2OrderBook::new();
+        book.insert(&OrderSide::Ask, entry("a1", "101", "1"));
+        book.insert(&OrderSide::Ask, entry("a2", "101", "1"));
+        book.insert(&OrderSide::Ask, entry("a1", "101", "1"));
+
+        assert_eq!(hashes(&book.level_entries(&OrderSide::Ask, &dec("101"))), ["a2", "a1"]);
+        assert_eq!(book.depth(&OrderSide::Ask), vec![(dec("101"), dec("2"))]);
+    }
+
+    #[test]
+    fn partial_fill_and_size_decrease_keep_time_priority() {
+        let mut book = L2OrderBook::new();
+        book.insert(&OrderSide::Ask, entry("a1", "101", "5"));
+        book.insert(&OrderSide::Ask, entry("a2", "101", "5"));
+
+        book.fill("a1", dec("2"));
+        book.set_amount("a1", dec("1"));
+        assert_eq!(hashes(&book.level_entries(&OrderSide::Ask, &dec("101"))), ["a1", "a2"]);
+        assert_eq!(book.get("a1").map(|entry| entry.amount.clone()), Some(dec("1")));
+        assert_eq!(book.depth(&OrderSide::Ask), vec![(dec("101"), dec("6"))]);
+
+        book.fill("a1", dec("1"));
+        assert!(!book.contains("a1"));
+        assert_eq!(hashes(&book.asks()), ["a2"]);
+    }
+
+    #[test]
+    fn replenished_iceberg_loses_time_priority() {
+        let mut book = L2OrderBook::new();
+        let mut iceberg = entry("a1", "101", "2");
+        iceberg.display_amount = Some(dec("2"));
+        iceberg.hidden_amount = dec("4");
+        book.insert(&OrderSide::Ask, iceberg);
+        book.insert(&OrderSide::Ask, entry("a2", "101", "3"));
+
+        assert_eq!(book.depth(&OrderSide::Ask), vec![(dec("101"), dec("5"))]);
+        assert_eq!(book.total_depth(&OrderSide::Ask), vec![(dec("101"), dec("9"))]);
+
+        book.fill("a1", dec("2"));
+        assert_eq!(hashes(&book.asks()), ["a2", "a1"]);
+        let refilled = book.get("a1").unwrap();
+        assert_eq!((refilled.amount.clone(), refilled.hidden_amount.clone()), (dec("2"), dec("2")));
+        assert_eq!(book.total_depth(&OrderSide::Ask), vec![(dec("101"), dec("7"))]);
+    }
+
+    #[test]
+    fn indicative_equilibrium_hides_iceberg_reserve() {
+        let mut book = L2OrderBook::new();
+        let mut iceberg = entry("a1", "100", "1");
+        iceberg.display_amount = Some(dec("1"));
+        iceberg.hidden_amount = dec("9");
+        book.insert(&OrderSide::Ask, iceberg);
+        book.insert(&OrderSide::Bid, entry("b1", "100", "6"));
+
+        assert_eq!(book.indicative_equilibrium(), Some((dec("100"), dec("1"))));
+        assert_eq!(book.equilibrium(), Some((dec("100"), dec("6"))));
+    }
+
+    #[test]
+    fn expired_stops_leave_the_trigger_book() {
+        let now = Utc::now();
+        let stop = |expires_at| Order {
+            order_type: OrderType::StopMarket,
+            trigger_price: Some(dec("105")),
+            time_in_force: TimeInForce::GTD,
+            expires_at: Some(expires_at),
+            ..Default::default()
+        };
+        let mut stops = TriggerBook::default();
+        stops.insert("s1".to_string(), stop(now - chrono::Duration::seconds(1)));
+        stops.insert("s2".to_string(), stop(now + chrono::Duration::seconds(60)));
+
+        let expired: Vec<String> = stops.remove_expired(now).into_iter().map(|(hash, _)| hash).collect();
+        assert_eq!(expired, ["s1"]);
+        assert!(stops.get("s1").is_none());
+        assert!(stops.pop_triggered(&dec("105")).is_some());
+    }
+
+    #[test]
+    fn stops_are_checked_against_every_trade_in_order() {
+        let mut book = L2OrderBook::new();
+        let buy_stop = Order {
+            order_type: OrderType::StopMarket,
+            trigger_price: Some(dec("101")),
+            ..Default::default()
+        };
+        book.stops.insert("s1".to_string(), buy_stop);
+        book.record_trade(dec("100"));
+        book.record_trade(dec("102"));
+        book.record_trade(dec("99"));
+
+        let mut triggered = Vec::new();
+        while let Some(price) = book.trigger_check_price().cloned() {
+            match book.stops.pop_triggered(&price) {
+                Some((hash, _)) => triggered.push((hash, price)),
+                None if book.finish_trigger_check() => continue,
+                None => break,
+            }
+        }
+        // The last trade price alone would never have reached the trigger
+        assert_eq!(triggered, [("s1".to_string(), dec("102"))]);
+        assert_eq!(book.trigger_check_price(), Some(&dec("99")));
+    }
+
+    #[test]
+    fn matches_like_the_vec_book() {
+        enum Step {
+            Rest(OrderSide, &'static str, &'static str, &'static str),
+            Cancel(&'static str),
+            Take(OrderSide, &'static str, &'static str),
+        }
+        use OrderSide::{Ask, Bid};
+        let steps = [
+            Step::Rest(Ask, "a1", "101", "3"),
+            Step::Rest(Ask, "a2", "100", "2"),
+            Step::Rest(Ask, "a3", "101", "1"),
+            Step::Rest(Ask, "a4", "100", "4"),
+            Step::Rest(Bid, "b1", "98", "5"),
+            Step::Rest(Bid, "b2", "99", "1"),
+            Step::Rest(Bid, "b3", "99", "2"),
+            Step::Take(Bid, "100", "3"),
+            Step::Cancel("a3"),
+            Step::Rest(Ask, "a5", "101", "2"),
+            Step::Take(Bid, "101.5", "5"),
+            Step::Take(Ask, "98", "2.5"),
+            Step::Cancel("b1"),
+            Step::Rest(Bid, "b4", "99", "1"),
+            Step::Take(Ask, "90", "10"),
+            Step::Take(Bid, "200", "10"),
+        ];
+
+        let mut book = L2OrderBook::new();
+        let mut reference = VecBook::default();
+        for step in steps {
+            match step {
+                Step::Rest(side, hash, price, amount) => {
+                    book.insert(&side, entry(hash, price, amount));
+                    reference.insert(&side, entry(hash, price, amount));
+                }
+                Step::Cancel(hash) => {
+                    book.remove(hash);
+                    reference.remove(hash);
+                }
+                Step::Take(side, price, amount) => {
+                    assert_eq!(
+                        take(&mut book, &side, &dec(price), &dec(amount)),
+                        reference.take(&side, &dec(price), &dec(amount))
+                    );
+                }
+            }
+            assert_eq!(hashes(&book.bids()), hashes(&reference.bids));
+            assert_eq!(hashes(&book.asks()), hashes(&reference.asks));
+        }
+        assert!(book.is_empty());
+    }
+}
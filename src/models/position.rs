@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+use bigdecimal::{BigDecimal, Signed};
+use chrono::{DateTime, Utc};
+
+/// A trader's exposure in one perpetual market. `size` is signed: positive is long, negative
+/// short, zero flat.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Position {
+    pub trader_address: String,
+    pub market_id: i32,
+    pub size: BigDecimal,
+    pub entry_price: BigDecimal,  // Average price the open size was entered at, 0 when flat
+    pub realized_pnl: BigDecimal, // Profit and loss locked in by reducing the position, in the quote asset
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Position {
+    /// A flat position.
+    pub fn flat(trader_address: String, market_id: i32) -> Self {
+        Position {
+            trader_address,
+            market_id,
+            size: BigDecimal::from(0),
+            entry_price: BigDecimal::from(0),
+            realized_pnl: BigDecimal::from(0),
+            updated_at: Utc::now(),
+        }
+    }
+
+    /// Applies a fill of `delta` (positive bought, negative sold) at `price` and returns the PnL
+    /// it realized. Fills in the direction of the position average into the entry price; fills
+    /// against it close at the entry price, and any excess opens the other way at `price`.
+    pub fn apply_fill(&mut self, delta: &BigDecimal, price: &BigDecimal) -> BigDecimal {
+        let zero = BigDecimal::from(0);
+        let mut realized = zero.clone();
+        if self.size == zero || self.size.is_positive() == delta.is_positive() {
+            let size = self.size.clone() + delta.clone();
+            self.entry_price = (self.size.abs() * self.entry_price.clone() + delta.abs() * price.clone()) / size.abs();
+            self.size = size;
+        } else {
+            let closing = delta.abs().min(self.size.abs());
+            let direction = if self.size.is_positive() { BigDecimal::from(1) } else { BigDecimal::from(-1) };
+            realized = closing * (price.clone() - self.entry_price.clone()) * direction;
+            self.size += delta.clone();
+            if self.size == zero {
+                self.entry_price = zero.clone();
+            } else if self.size.is_positive() == delta.is_positive() {
+                self.entry_price = price.clone();
+            }
+        }
+        self.realized_pnl += realized.clone();
+        self.updated_at = Utc::now();
+        realized
+    }
+
+    /// Profit or loss of closing the open size at `mark_price`.
+    pub fn unrealized_pnl(&self, mark_price: &BigDecimal) -> BigDecimal {
+        self.size.clone() * (mark_price.clone() - self.entry_price.clone())
+    }
+
+    /// Value of the open size at `price`.
+    pub fn notional(&self, price: &BigDecimal) -> BigDecimal {
+        self.size.abs() * price.clone()
+    }
+}
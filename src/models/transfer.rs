@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+use bigdecimal::BigDecimal;
+use std::fmt;
+use chrono::{DateTime, Utc};
+
+/// Direction of a transfer between a trader's account and the outside world.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum TransferKind {
+    Deposit,
+    Withdrawal,
+}
+
+impl fmt::Display for TransferKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Deposits complete straight away. Withdrawals start out pending with their funds locked, then
+/// are either completed, paying the funds out, or rejected, giving them back.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum TransferStatus {
+    Pending,
+    Completed,
+    Rejected,
+}
+
+impl fmt::Display for TransferStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A deposit or withdrawal, as the back office reconciles it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transfer {
+    pub id: i64,
+    pub trader_address: String,
+    pub kind: String,   // `TransferKind`
+    pub asset: String,
+    pub amount: BigDecimal,
+    pub status: String, // `TransferStatus`
+    pub reason: Option<String>, // Why a withdrawal was rejected
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
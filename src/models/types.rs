@@ -1,16 +1,19 @@
 use serde::{Serialize, Deserialize};
-use ethereum_types::{H160, H256, U256};  // Common types for Ethereum-based projects
+use bigdecimal::BigDecimal;
+use ethereum_types::{H160, H256};  // Common types for Ethereum-based projects
 
     pub type Address = H160;  // Ethereum address as a 20-byte hexadecimal type
 pub type Hash = H256;     // 32-byte hash, often used for transaction or data hashes
-pub type Decimal = U256;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Fill {
+    pub market_id: i32,
     pub maker_hash: Hash,
     pub taker_hash: Hash,
-    pub fill_amount: Decimal,
-    pub price: Decimal,
+    pub maker_order_id: i64,
+    pub taker_order_id: i64,
+    pub fill_amount: BigDecimal, // Stored as NUMERIC, so fractional amounts and prices are kept exactly
+    pub price: BigDecimal,
 }
 
 
@@ -19,9 +22,12 @@ pub struct Fill {
 pub struct EIP712DomainSeparator {
     pub name: String,
     pub version: String,
+    pub chain_id: u64,
+    pub verifying_contract: Address,
 }
 impl EIP712DomainSeparator {
-    pub fn new(name: String, version: String) -> Self {
-        EIP712DomainSeparator { name, version }
+    /// EIP-712 domain separator, `hashStruct(EIP712Domain)`.
+    pub fn hash(&self) -> Hash {
+        crate::eip712::domain_separator(&self.name, &self.version, self.chain_id, &self.verifying_contract)
     }
-}
\ No newline at end of file
+}
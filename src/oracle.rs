@@ -0,0 +1,50 @@
+use bigdecimal::BigDecimal;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// A source of index prices for perpetual markets, keyed by market symbol. Lookups may block;
+/// the funding engine runs them on the blocking thread pool.
+pub trait PriceOracle: Send + Sync {
+    /// Current index price of `symbol`, `None` if the source has no price for it.
+    fn index_price(&self, symbol: &str) -> Result<Option<BigDecimal>, String>;
+}
+
+/// Reads index prices from a JSON file of symbol to price, e.g. `{"ETH-USD": "3150.25"}`, on
+/// every call, so whatever rewrites the file drives the prices.
+pub struct FileOracle {
+    pub path: String,
+}
+
+impl PriceOracle for FileOracle {
+    fn index_price(&self, symbol: &str) -> Result<Option<BigDecimal>, String> {
+        let contents = std::fs::read_to_string(&self.path).map_err(|e| format!("Failed to read {}: {}", self.path, e))?;
+        let prices: HashMap<String, String> =
+            serde_json::from_str(&contents).map_err(|e| format!("Invalid price file {}: {}", self.path, e))?;
+        match prices.get(&symbol.to_uppercase()) {
+            Some(price) => BigDecimal::from_str(price)
+                .map(Some)
+                .map_err(|_| format!("Invalid index price for {}: {}", symbol, price)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Index prices set in memory, for tests and local runs without a price feed.
+#[derive(Default)]
+pub struct StaticOracle {
+    prices: RwLock<HashMap<String, BigDecimal>>,
+}
+
+impl StaticOracle {
+    #[allow(dead_code)] // The server starts it empty; tests and local runs fill it in
+    pub fn set_price(&self, symbol: &str, price: BigDecimal) {
+        self.prices.write().unwrap().insert(symbol.to_uppercase(), price);
+    }
+}
+
+impl PriceOracle for StaticOracle {
+    fn index_price(&self, symbol: &str) -> Result<Option<BigDecimal>, String> {
+        Ok(self.prices.read().unwrap().get(&symbol.to_uppercase()).cloned())
+    }
+}
@@ -1,13 +1,44 @@
 use actix_web::{web, HttpResponse};
 use ethereum_types::H160;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use bigdecimal::BigDecimal;
+use uuid::Uuid;
+use crate::db::pool;
+use crate::routes::order_routes::AppState;
 use crate::services::account_service;
+use crate::services::asset_service;
+use crate::services::ledger_service;
+use crate::services::liquidation_service;
 use crate::models::account::Account;
+use crate::models::ledger::{LedgerBalance, LedgerEntry};
 use crate::services::account_service::get_account_from_db;
 use crate::services::account_service::delete_account_from_db;
 
-pub async fn create_account(account: web::Json<Account>) -> HttpResponse {
-    let account_inner = account.into_inner();
+// Balances can only be held in registered assets
+async fn check_assets(app_state: &AppState, account: &Account) -> Result<(), HttpResponse> {
+    for asset in account.balances.keys() {
+        match asset_service::get_asset(&app_state.db_pool, asset).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(HttpResponse::BadRequest().body(format!("Unknown asset: {}", asset))),
+            Err(_) => return Err(HttpResponse::InternalServerError().body("Failed to look up assets")),
+        }
+    }
+    Ok(())
+}
+
+pub async fn create_account(account: web::Json<Account>, app_state: web::Data<AppState>) -> HttpResponse {
+    let mut account_inner = account.into_inner();
+    if account_inner.trader_address == liquidation_service::insurance_fund() {
+        return HttpResponse::Conflict().body("The insurance fund's account is reserved");
+    }
+    if let Err(response) = check_assets(&app_state, &account_inner).await {
+        return response;
+    }
+    // A new account has no open orders, so nothing is locked
+    for balance in account_inner.balances.values_mut() {
+        balance.locked = BigDecimal::from(0);
+    }
     match account_service::create_account_in_db(&account_inner).await {
         Ok(_) => HttpResponse::Created().json(account_inner),
         Err(_) => HttpResponse::InternalServerError().body("Failed to create account"),
@@ -29,7 +60,7 @@ pub async fn get_account(trader_address: web::Path<String>) -> HttpResponse {
     }
 }
 
-pub async fn delete_account(trader_address: web::Path<String>) -> HttpResponse {
+pub async fn delete_account(trader_address: web::Path<String>, app_state: web::Data<AppState>) -> HttpResponse {
     let trader_address_str = trader_address.into_inner(); // Extract trader_address
 
     // Convert trader_address from String to H160
@@ -38,27 +69,73 @@ pub async fn delete_account(trader_address: web::Path<String>) -> HttpResponse {
         Err(_) => return HttpResponse::BadRequest().body("Invalid Ethereum address"),
     };
 
+    // Every book stays locked until the account is gone, so no order can be placed meanwhile.
+    // Resting orders hold funds, which the service checks, but waiting stop orders hold nothing.
+    let markets = app_state.all_markets();
+    let mut order_books = Vec::new();
+    for market_state in &markets {
+        let order_book = market_state.order_book.lock().await;
+        if !order_book.hashes_of(&trader_address_h160).is_empty() || !order_book.stops.hashes_of(&trader_address_h160).is_empty() {
+            return HttpResponse::Conflict().body(format!("Account still has open orders in {}", market_state.market.symbol));
+        }
+        order_books.push(order_book);
+    }
+
     match delete_account_from_db(&trader_address_h160).await {
-        Ok(_) => HttpResponse::NoContent().finish(), // Return no content on success
-        Err(_) => HttpResponse::NotFound().body("Account not found"),
+        Ok(true) => HttpResponse::NoContent().finish(), // Return no content on success
+        Ok(false) => HttpResponse::NotFound().body("Account not found"),
+        Err(reason) => HttpResponse::Conflict().body(reason),
     }
 }
 
 
 
-pub async fn update_account(account: web::Json<Account>) -> HttpResponse {
+pub async fn update_account(account: web::Json<Account>, app_state: web::Data<AppState>) -> HttpResponse {
     let account_inner = account.into_inner();
-    
-    // Convert the trader_address from Account back to H160 for validation
-    let trader_address_h160 = match H160::from_str(&format!("{:?}", account_inner.trader_address)) {
+    if let Err(response) = check_assets(&app_state, &account_inner).await {
+        return response;
+    }
+
+    // Each update is an admin adjustment in the ledger, under its own request id
+    let request_id = Uuid::new_v4().to_string();
+    match account_service::adjust_account_in_db(&account_inner, &request_id).await {
+        Ok(account) => HttpResponse::Ok().json(account), // Return the updated account as JSON
+        Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().body("Account not found"),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to update account"),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LedgerQuery {
+    pub limit: Option<i64>,     // Most recent entries to return, 100 by default
+    pub asset: Option<String>,  // Only entries for this asset, e.g. USD
+}
+
+#[derive(Serialize)]
+pub struct LedgerResponse {
+    pub balances: Vec<LedgerBalance>, // Balances derived from the whole ledger
+    pub entries: Vec<LedgerEntry>,
+}
+
+pub async fn get_account_ledger(trader_address: web::Path<String>, query: web::Query<LedgerQuery>) -> HttpResponse {
+    let trader_address_str = trader_address.into_inner(); // Extract trader_address
+
+    // Convert trader_address from String to H160
+    let trader_address_h160 = match H160::from_str(&trader_address_str) {
         Ok(address) => address,
         Err(_) => return HttpResponse::BadRequest().body("Invalid Ethereum address"),
     };
 
-    // Update the account in the database
-    match account_service::update_account_in_db(&account_inner).await {
-        Ok(_) => HttpResponse::Ok().json(account_inner), // Return the updated account as JSON
-        Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().body("Account not found"),
-        Err(_) => HttpResponse::InternalServerError().body("Failed to update account"),
+    let db_pool = match pool::create_pool().await {
+        Ok(db_pool) => db_pool,
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to connect to the database"),
+    };
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    match (
+        ledger_service::ledger_balances(&db_pool, &trader_address_h160).await,
+        ledger_service::get_ledger(&db_pool, &trader_address_h160, query.asset.as_deref(), limit).await,
+    ) {
+        (Ok(balances), Ok(entries)) => HttpResponse::Ok().json(LedgerResponse { balances, entries }),
+        _ => HttpResponse::InternalServerError().body("Failed to fetch ledger"),
     }
-}
\ No newline at end of file
+}
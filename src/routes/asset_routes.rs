@@ -0,0 +1,31 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use crate::models::asset::Asset;
+use crate::routes::order_routes::AppState;
+use crate::services::asset_service;
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateAssetRequest {
+    pub symbol: String,        // e.g. ETH
+    pub decimals: Option<i32>, // Defaults to 18
+}
+
+pub async fn list_assets(app_state: web::Data<AppState>) -> HttpResponse {
+    match asset_service::load_assets(&app_state.db_pool).await {
+        Ok(assets) => HttpResponse::Ok().json(assets),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to fetch assets"),
+    }
+}
+
+pub async fn create_asset(asset_data: web::Json<CreateAssetRequest>, app_state: web::Data<AppState>) -> HttpResponse {
+    let decimals = asset_data.decimals.unwrap_or(18);
+    if !(0..=18).contains(&decimals) {
+        return HttpResponse::BadRequest().body("Decimals must be between 0 and 18");
+    }
+    let asset = Asset { symbol: asset_data.symbol.to_uppercase(), decimals };
+    match asset_service::create_asset_in_db(&app_state.db_pool, &asset).await {
+        Ok(true) => HttpResponse::Created().json(asset),
+        Ok(false) => HttpResponse::Conflict().body("Asset already exists"),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to create asset"),
+    }
+}
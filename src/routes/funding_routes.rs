@@ -0,0 +1,115 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Utc};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::models::funding::FundingRate;
+use crate::oracle::PriceOracle;
+use crate::routes::order_routes::AppState;
+use crate::services::funding_service;
+
+#[derive(Deserialize)]
+pub struct FundingQuery {
+    pub limit: Option<i64>, // Defaults to 100
+}
+
+#[derive(Serialize)]
+pub struct FundingResponse {
+    pub index_price: Option<BigDecimal>, // None until the oracle has priced the market
+    pub mark_price: Option<BigDecimal>,
+    pub next_funding_at: Option<DateTime<Utc>>, // None before the first payment
+    pub history: Vec<FundingRate>,              // Most recent first
+}
+
+// Current index and mark price of a perpetual market and its funding rate history
+pub async fn get_funding(
+    symbol: web::Path<String>,
+    query: web::Query<FundingQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let symbol = symbol.into_inner();
+    let market_state = match app_state.market(&symbol) {
+        Some(market_state) => market_state,
+        None => return HttpResponse::NotFound().body(format!("Market {} not found", symbol)),
+    };
+    if !market_state.market.is_perpetual() {
+        return HttpResponse::BadRequest().body(format!("{} is not a perpetual market", symbol));
+    }
+
+    let (index_price, mark_price) = {
+        let order_book = market_state.order_book.lock().await;
+        (order_book.index_price().cloned(), order_book.mark_price().cloned())
+    };
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    match funding_service::get_funding_history(&app_state.db_pool, market_state.market.id, limit).await {
+        Ok(history) => HttpResponse::Ok().json(FundingResponse {
+            index_price,
+            mark_price,
+            next_funding_at: history
+                .first()
+                .map(|funding| funding.created_at + chrono::Duration::seconds(market_state.market.funding.interval_secs)),
+            history,
+        }),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to fetch funding history"),
+    }
+}
+
+// Refreshes the index and mark price of every perpetual market once a second, and pays funding
+// in a market once its interval has passed since the last payment. A market's first interval
+// starts when the engine first prices it.
+pub async fn run_funding_engine(app_state: web::Data<AppState>, oracle: Arc<dyn PriceOracle>) {
+    let mut interval = actix_web::rt::time::interval(Duration::from_secs(1));
+    let mut last_funding: HashMap<i32, DateTime<Utc>> = HashMap::new();
+    loop {
+        interval.tick().await;
+        for market_state in app_state.all_markets() {
+            let market = &market_state.market;
+            if !market.is_perpetual() {
+                continue;
+            }
+            // Oracles may block on I/O, e.g. reading the price file, so they run off the async workers
+            let (lookup, symbol) = (oracle.clone(), market.symbol.clone());
+            let index_price = tokio::task::spawn_blocking(move || lookup.index_price(&symbol))
+                .await
+                .unwrap_or_else(|e| Err(format!("Price lookup failed: {}", e)));
+            let index_price = match index_price {
+                Ok(Some(price)) => price,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("Failed to fetch index price for {}: {}", market.symbol, e);
+                    continue;
+                }
+            };
+            if let Entry::Vacant(entry) = last_funding.entry(market.id) {
+                match funding_service::latest_funding(&app_state.db_pool, market.id).await {
+                    Ok(latest) => {
+                        entry.insert(latest.map_or_else(Utc::now, |funding| funding.created_at));
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to fetch latest funding for {}: {}", market.symbol, e);
+                        continue;
+                    }
+                }
+            }
+
+            // The book stays locked while funding is paid so no fill moves a position meanwhile
+            let mut order_book = market_state.order_book.lock().await;
+            let mark_price = market.mark_price(&index_price, order_book.mid_price());
+            order_book.set_prices(index_price.clone(), mark_price.clone());
+            if Utc::now() < last_funding[&market.id] + chrono::Duration::seconds(market.funding.interval_secs) {
+                continue;
+            }
+            let rate = market.funding_rate(&mark_price, &index_price);
+            match funding_service::settle_funding(&app_state.db_pool, market, &index_price, &mark_price, &rate).await {
+                Ok(funding) => {
+                    eprintln!("Funding paid in {}: rate {} at mark price {}", market.symbol, rate, mark_price);
+                    last_funding.insert(market.id, funding.created_at);
+                }
+                Err(e) => eprintln!("Failed to pay funding in {}: {}", market.symbol, e),
+            }
+        }
+    }
+}
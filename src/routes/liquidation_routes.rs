@@ -0,0 +1,274 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use bigdecimal::{BigDecimal, Signed};
+use ethereum_types::{H160, H256};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+use crate::models::account::Balance;
+use crate::models::liquidation::{LiquidationEventKind, MarginSummary};
+use crate::models::market::{Market, TradingPhase};
+use crate::models::order::{Order, OrderSide, OrderType, TimeInForce};
+use crate::models::position::Position;
+use crate::routes::order_routes::{AppState, MarketState};
+use crate::services::account_service;
+use crate::services::liquidation_service;
+use crate::services::order_service::{add_order_to_book, cancel_resting_order, update_order_book};
+use crate::services::position_service;
+
+#[derive(Deserialize)]
+pub struct LiquidationsQuery {
+    pub trader: Option<String>, // Only this trader's events
+    pub limit: Option<i64>,     // Defaults to 100
+}
+
+#[derive(Serialize)]
+pub struct InsuranceFundResponse {
+    pub trader_address: String,
+    pub balances: BTreeMap<String, Balance>,
+    pub positions: Vec<Position>, // Positions taken over in liquidations and not yet closed
+}
+
+// Liquidation audit trail, newest first
+pub async fn get_liquidations(query: web::Query<LiquidationsQuery>, app_state: web::Data<AppState>) -> HttpResponse {
+    let trader = match query.trader.as_deref().map(H160::from_str) {
+        None => None,
+        Some(Ok(address)) => Some(address),
+        Some(Err(_)) => return HttpResponse::BadRequest().body("Invalid Ethereum address"),
+    };
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    match liquidation_service::get_events(&app_state.db_pool, trader.as_ref(), limit).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to fetch liquidations"),
+    }
+}
+
+// Insurance fund balances and open positions
+pub async fn get_insurance_fund(app_state: web::Data<AppState>) -> HttpResponse {
+    let fund = liquidation_service::insurance_fund();
+    let account = match account_service::get_account_from_db(&fund).await {
+        Ok(account) => account,
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to fetch the insurance fund"),
+    };
+    match position_service::get_positions(&app_state.db_pool, &fund).await {
+        Ok(positions) => HttpResponse::Ok().json(InsuranceFundResponse {
+            trader_address: format!("{:?}", fund),
+            balances: account.balances,
+            positions: positions.into_iter().filter(|position| position.size != BigDecimal::from(0)).collect(),
+        }),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to fetch the insurance fund"),
+    }
+}
+
+// Once a second, liquidates every trader whose equity is below maintenance margin at the current
+// mark prices, then has the insurance fund close the positions it has taken over
+pub async fn run_liquidation_engine(app_state: web::Data<AppState>) {
+    let mut interval = actix_web::rt::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let market_states: Vec<_> = app_state
+            .all_markets()
+            .into_iter()
+            .filter(|state| state.market.is_perpetual())
+            .collect();
+        let markets: Vec<Market> = market_states.iter().map(|state| state.market.clone()).collect();
+        let mut mark_prices = HashMap::new();
+        let mut order_margin: HashMap<(H160, String), BigDecimal> = HashMap::new();
+        for state in &market_states {
+            let order_book = state.order_book.lock().await;
+            if let Some(mark_price) = order_book.mark_price() {
+                mark_prices.insert(state.market.id, mark_price.clone());
+            }
+            for entry in order_book.bids().into_iter().chain(order_book.asks()) {
+                let margin = state.market.margin_for(&(entry.total_amount() * entry.price.clone()));
+                *order_margin.entry((entry.trader_address, state.market.quote_asset.clone())).or_default() += margin;
+            }
+        }
+        if mark_prices.is_empty() {
+            continue;
+        }
+
+        let summaries = match liquidation_service::margin_summaries(&app_state.db_pool, &markets, &mark_prices, &order_margin).await {
+            Ok(summaries) => summaries,
+            Err(e) => {
+                eprintln!("Failed to check margins: {}", e);
+                continue;
+            }
+        };
+        for summary in summaries.iter().filter(|summary| summary.is_below_maintenance()) {
+            liquidate(&app_state, &market_states, summary, &markets, &mark_prices).await;
+        }
+
+        for state in &market_states {
+            if mark_prices.contains_key(&state.market.id) {
+                close_fund_position(&app_state, state, &mark_prices).await;
+            }
+        }
+    }
+}
+
+// Cancels the trader's orders in the markets margined in the same asset, releasing their holds,
+// then closes their positions in the book with IOC market orders sent on their behalf. Whatever
+// the book could not take is handed to the insurance fund. The trader's margin is checked again
+// before each order, and once more when the fund takes over, so a trader the fills brought back
+// above maintenance margin keeps what is left.
+async fn liquidate(
+    app_state: &AppState,
+    market_states: &[std::sync::Arc<MarketState>],
+    summary: &MarginSummary,
+    markets: &[Market],
+    mark_prices: &HashMap<i32, BigDecimal>,
+) {
+    let db = &app_state.db_pool;
+    let trader = match H160::from_str(&summary.trader_address) {
+        Ok(address) => address,
+        Err(_) => return,
+    };
+    eprintln!(
+        "Liquidating {:?}: equity {} {} below maintenance margin {}",
+        trader, summary.equity, summary.collateral_asset, summary.maintenance_margin
+    );
+
+    for state in market_states.iter().filter(|state| state.market.quote_asset == summary.collateral_asset) {
+        let mut order_book = state.order_book.lock().await;
+        let resting = order_book.hashes_of(&trader);
+        let stops = order_book.stops.hashes_of(&trader);
+        if resting.is_empty() && stops.is_empty() {
+            continue;
+        }
+        for eip712_hash in &resting {
+            cancel_resting_order(&state.market, &mut order_book, eip712_hash, db).await;
+        }
+        for eip712_hash in &stops {
+            order_book.stops.remove(eip712_hash);
+        }
+        if let Err(e) = update_order_book(db, state.market.id, &order_book).await {
+            eprintln!("Failed to update order book in database: {}", e);
+        }
+        let detail = format!("Cancelled {} resting and {} stop orders", resting.len(), stops.len());
+        if let Err(e) = liquidation_service::log_event(db, LiquidationEventKind::OrdersCancelled, &trader, Some(state.market.id), None, &detail).await {
+            eprintln!("Failed to record liquidation event: {}", e);
+        }
+    }
+
+    for state in market_states.iter().filter(|state| state.market.quote_asset == summary.collateral_asset) {
+        let market = &state.market;
+        if !mark_prices.contains_key(&market.id) {
+            continue;
+        }
+        match liquidation_service::margin_summary(db, &trader, &summary.collateral_asset, markets, mark_prices).await {
+            Ok(current) if current.is_below_maintenance() => {}
+            Ok(_) => break,
+            Err(e) => {
+                eprintln!("Failed to check the margin of {:?}: {}", trader, e);
+                return;
+            }
+        }
+        let position = match position_service::get_position(db, &trader, market.id).await {
+            Ok(Some(position)) if position.size != BigDecimal::from(0) => position,
+            Ok(_) => continue,
+            Err(e) => {
+                eprintln!("Failed to fetch the position of {:?} in {}: {}", trader, market.symbol, e);
+                continue;
+            }
+        };
+
+        let mut order_book = state.order_book.lock().await;
+        if order_book.phase != TradingPhase::Continuous {
+            continue;
+        }
+        let order = Order {
+            market_id: market.id,
+            amount: position.size.abs(),
+            nonce: H256::from_slice(&[*Uuid::new_v4().as_bytes(), *Uuid::new_v4().as_bytes()].concat()),
+            side: if position.size.is_positive() { OrderSide::Ask } else { OrderSide::Bid },
+            trader_address: trader,
+            order_type: OrderType::Market,
+            max_slippage: Some(market.maintenance_margin.clone()),
+            time_in_force: TimeInForce::IOC,
+            liquidation: true,
+            ..Default::default()
+        };
+        let placement = add_order_to_book(order, market, &mut order_book, &app_state.domain_separator, mark_prices, db).await;
+        let detail = match &placement.rejection {
+            Some(reason) => format!("Liquidation order for {} {} was refused: {}", position.size, market.symbol, reason),
+            None => format!("Liquidation order for {} {} traded {} fills", position.size, market.symbol, placement.fills.len()),
+        };
+        if let Err(e) = liquidation_service::log_event(db, LiquidationEventKind::LiquidationOrder, &trader, Some(market.id), Some(&position.size), &detail).await {
+            eprintln!("Failed to record liquidation event: {}", e);
+        }
+    }
+
+    match liquidation_service::take_over_positions(db, summary, markets, mark_prices).await {
+        Ok(Some(_)) => {}
+        Ok(None) => eprintln!("{:?} is back above maintenance margin, the rest of their positions stay open", trader),
+        Err(e) => eprintln!("Failed to liquidate {:?}: {}", trader, e),
+    }
+}
+
+// Sends an IOC market order for the insurance fund's position in one market. If the fund has no
+// balance left to margin the order, or the order is refused or finds nothing to trade with, the
+// position is auto-deleveraged instead. Nothing happens while the market is halted or in an auction.
+async fn close_fund_position(app_state: &AppState, state: &MarketState, mark_prices: &HashMap<i32, BigDecimal>) {
+    let db = &app_state.db_pool;
+    let market = &state.market;
+    let mark_price = &mark_prices[&market.id];
+    let fund = liquidation_service::insurance_fund();
+    let position = match position_service::get_position(db, &fund, market.id).await {
+        Ok(Some(position)) if position.size != BigDecimal::from(0) => position,
+        Ok(_) => return,
+        Err(e) => {
+            eprintln!("Failed to fetch the insurance fund's position in {}: {}", market.symbol, e);
+            return;
+        }
+    };
+    let balance = match account_service::get_balance(db, &fund, &market.quote_asset).await {
+        Ok(balance) => balance,
+        Err(e) => {
+            eprintln!("Failed to fetch the insurance fund's balance: {}", e);
+            return;
+        }
+    };
+
+    let mut order_book = state.order_book.lock().await;
+    if order_book.phase != TradingPhase::Continuous {
+        return;
+    }
+
+    let amount = position.size.abs();
+    if balance.available > BigDecimal::from(0) && amount >= market.rules.min_amount {
+        let nonce = H256::from_slice(&[*Uuid::new_v4().as_bytes(), *Uuid::new_v4().as_bytes()].concat());
+        let order = Order {
+            market_id: market.id,
+            amount: amount.clone(),
+            nonce,
+            side: if position.size.is_positive() { OrderSide::Ask } else { OrderSide::Bid },
+            trader_address: fund,
+            order_type: OrderType::Market,
+            max_slippage: Some(market.maintenance_margin.clone()),
+            time_in_force: TimeInForce::IOC,
+            ..Default::default()
+        };
+        let placement = add_order_to_book(order, market, &mut order_book, &app_state.domain_separator, mark_prices, db).await;
+        let detail = match &placement.rejection {
+            Some(reason) => format!("Order to close {} {} was refused: {}", position.size, market.symbol, reason),
+            None => format!("Order to close {} {} traded {} fills", position.size, market.symbol, placement.fills.len()),
+        };
+        if let Err(e) = liquidation_service::log_event(db, LiquidationEventKind::LiquidationOrder, &fund, Some(market.id), Some(&position.size), &detail).await {
+            eprintln!("Failed to record liquidation event: {}", e);
+        }
+        // Only an order that traded reduced the position; otherwise there was no liquidity to close into
+        if placement.rejection.is_none() && !placement.fills.is_empty() {
+            return;
+        }
+    }
+
+    match liquidation_service::auto_deleverage(db, market, mark_price).await {
+        Ok(closed) if closed > BigDecimal::from(0) => {
+            eprintln!("Auto-deleveraged {} {} of the insurance fund's position", closed, market.symbol);
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to auto-deleverage in {}: {}", market.symbol, e),
+    }
+}
@@ -0,0 +1,326 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use crate::models::market::{BreakerAction, FundingRules, Market, MarketKind, MatchingAlgorithm, PriceProtection, TradingPhase, TradingRules};
+use bigdecimal::BigDecimal;
+use std::str::FromStr;
+use crate::routes::order_routes::AppState;
+use crate::services::market_service;
+use crate::services::asset_service;
+use crate::services::order_service::ensure_empty_order_book;
+use crate::services::order_service::uncross_auction;
+use crate::services::order_service::set_phase;
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateMarketRequest {
+    pub symbol: String,      // e.g. ETH-USD
+    pub base_asset: String,  // e.g. ETH
+    pub quote_asset: String, // e.g. USD
+    pub price_tick: Option<String>,   // Defaults to 0.01
+    pub amount_step: Option<String>,  // Defaults to 0.001
+    pub min_amount: Option<String>,   // Defaults to the amount step
+    pub max_amount: Option<String>,   // Defaults to 1000000
+    pub min_notional: Option<String>, // Defaults to 1
+    pub matching_algorithm: Option<String>, // 'fifo' (default) or 'pro_rata'
+    pub fifo_slice: Option<String>,         // Pro-rata only: 0 to 1, defaults to 0
+    pub price_band: Option<String>,          // e.g. "0.1" for 10% around the last trade, defaults to 0 (off)
+    pub breaker_threshold: Option<String>,   // e.g. "0.05" for a 5% move, defaults to 0 (off)
+    pub breaker_window_secs: Option<i64>,    // Defaults to 60
+    pub breaker_action: Option<String>,      // 'halt' (default) or 'auction'
+    pub kind: Option<String>,           // 'spot' (default) or 'perpetual'
+    pub initial_margin: Option<String>, // Perpetual only: 0 to 1, defaults to 0.1 (10x leverage)
+    pub maintenance_margin: Option<String>, // Perpetual only: above 0 and below the initial margin, defaults to half of it
+    pub funding_interval_secs: Option<i64>, // Perpetual only: defaults to 3600
+    pub max_funding_rate: Option<String>,   // Perpetual only: cap per interval, defaults to 0.0075
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PhaseChangeRequest {
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    pub limit: Option<i64>, // Defaults to 100
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuctionStatusResponse {
+    pub phase: TradingPhase,
+    pub indicative_price: Option<BigDecimal>,  // Price the auction would uncross at right now
+    pub indicative_volume: Option<BigDecimal>, // Displayed volume that would trade at that price
+}
+
+// Parses an optional decimal field, falling back to a default
+fn decimal_or(value: &Option<String>, default: &str) -> Result<BigDecimal, String> {
+    match value {
+        Some(value) => BigDecimal::from_str(value).map_err(|_| format!("Invalid decimal: {}", value)),
+        None => Ok(BigDecimal::from_str(default).unwrap()),
+    }
+}
+
+fn parse_protection(market_data: &CreateMarketRequest) -> Result<PriceProtection, String> {
+    let price_band = decimal_or(&market_data.price_band, "0")?;
+    let breaker_threshold = decimal_or(&market_data.breaker_threshold, "0")?;
+    if price_band < BigDecimal::from(0) || breaker_threshold < BigDecimal::from(0) {
+        return Err("Price band and breaker threshold cannot be negative".to_string());
+    }
+    let breaker_window_secs = market_data.breaker_window_secs.unwrap_or(60);
+    if breaker_window_secs <= 0 {
+        return Err("Breaker window must be at least one second".to_string());
+    }
+    let breaker_action = match market_data.breaker_action.as_deref() {
+        None => BreakerAction::Halt,
+        Some(value) => value.parse().map_err(|_| format!("Invalid breaker action: {}", value))?,
+    };
+    Ok(PriceProtection { price_band, breaker_threshold, breaker_window_secs, breaker_action })
+}
+
+fn parse_rules(market_data: &CreateMarketRequest) -> Result<TradingRules, String> {
+    let price_tick = decimal_or(&market_data.price_tick, "0.01")?;
+    let amount_step = decimal_or(&market_data.amount_step, "0.001")?;
+    if price_tick <= BigDecimal::from(0) || amount_step <= BigDecimal::from(0) {
+        return Err("Tick size and lot size must be greater than zero".to_string());
+    }
+    let min_amount = decimal_or(&market_data.min_amount, &amount_step.to_string())?;
+    let max_amount = decimal_or(&market_data.max_amount, "1000000")?;
+    if min_amount <= BigDecimal::from(0) || min_amount > max_amount {
+        return Err("Minimum amount must be greater than zero and at most the maximum amount".to_string());
+    }
+    let min_notional = decimal_or(&market_data.min_notional, "1")?;
+    if min_notional < BigDecimal::from(0) {
+        return Err("Minimum order value cannot be negative".to_string());
+    }
+    Ok(TradingRules { price_tick, amount_step, min_amount, max_amount, min_notional })
+}
+
+fn parse_funding(market_data: &CreateMarketRequest) -> Result<FundingRules, String> {
+    let interval_secs = market_data.funding_interval_secs.unwrap_or(3600);
+    if interval_secs <= 0 {
+        return Err("Funding interval must be at least one second".to_string());
+    }
+    let max_rate = decimal_or(&market_data.max_funding_rate, "0.0075")?;
+    if max_rate < BigDecimal::from(0) {
+        return Err("Maximum funding rate cannot be negative".to_string());
+    }
+    Ok(FundingRules { interval_secs, max_rate })
+}
+
+pub async fn list_markets(app_state: web::Data<AppState>) -> HttpResponse {
+    let markets: Vec<Market> = app_state
+        .all_markets()
+        .iter()
+        .map(|state| state.market.clone())
+        .collect();
+    HttpResponse::Ok().json(markets)
+}
+
+pub async fn create_market(
+    market_data: web::Json<CreateMarketRequest>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    if app_state.market(&market_data.symbol).is_some() {
+        return HttpResponse::Conflict().body("Market already exists");
+    }
+
+    // Both assets have to be in the asset registry
+    for asset in [&market_data.base_asset, &market_data.quote_asset] {
+        match asset_service::get_asset(&app_state.db_pool, &asset.to_uppercase()).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return HttpResponse::BadRequest().body(format!("Unknown asset: {}", asset)),
+            Err(_) => return HttpResponse::InternalServerError().body("Failed to look up assets"),
+        }
+    }
+
+    let rules = match parse_rules(&market_data) {
+        Ok(rules) => rules,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let matching_algorithm = match market_data.matching_algorithm.as_deref() {
+        None => MatchingAlgorithm::Fifo,
+        Some(value) => match value.parse() {
+            Ok(algorithm) => algorithm,
+            Err(_) => return HttpResponse::BadRequest().body(format!("Invalid matching algorithm: {}", value)),
+        },
+    };
+    let fifo_slice = match decimal_or(&market_data.fifo_slice, "0") {
+        Ok(slice) if slice >= BigDecimal::from(0) && slice <= BigDecimal::from(1) => slice,
+        Ok(_) => return HttpResponse::BadRequest().body("FIFO slice must be between 0 and 1"),
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let protection = match parse_protection(&market_data) {
+        Ok(protection) => protection,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let kind = match market_data.kind.as_deref() {
+        None => MarketKind::Spot,
+        Some(value) => match value.parse() {
+            Ok(kind) => kind,
+            Err(_) => return HttpResponse::BadRequest().body(format!("Invalid market kind: {}", value)),
+        },
+    };
+    let initial_margin = match (&kind, decimal_or(&market_data.initial_margin, "0.1")) {
+        (MarketKind::Spot, _) => BigDecimal::from(0),
+        (MarketKind::Perpetual, Ok(margin)) if margin > BigDecimal::from(0) && margin <= BigDecimal::from(1) => margin,
+        (MarketKind::Perpetual, Ok(_)) => return HttpResponse::BadRequest().body("Initial margin must be above 0 and at most 1"),
+        (MarketKind::Perpetual, Err(e)) => return HttpResponse::BadRequest().body(e),
+    };
+    let maintenance_margin = match (&kind, decimal_or(&market_data.maintenance_margin, &(initial_margin.clone() / BigDecimal::from(2)).to_string())) {
+        (MarketKind::Spot, _) => BigDecimal::from(0),
+        (MarketKind::Perpetual, Ok(margin)) if margin > BigDecimal::from(0) && margin < initial_margin => margin,
+        (MarketKind::Perpetual, Ok(_)) => return HttpResponse::BadRequest().body("Maintenance margin must be above 0 and below the initial margin"),
+        (MarketKind::Perpetual, Err(e)) => return HttpResponse::BadRequest().body(e),
+    };
+    let funding = match parse_funding(&market_data) {
+        Ok(funding) => funding,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let market = Market {
+        id: 0, // Assigned by the database
+        symbol: market_data.symbol.clone(),
+        base_asset: market_data.base_asset.clone(),
+        quote_asset: market_data.quote_asset.clone(),
+        kind,
+        initial_margin,
+        maintenance_margin,
+        funding,
+        rules,
+        matching_algorithm,
+        fifo_slice,
+        protection,
+    };
+    let market = match market_service::create_market_in_db(&app_state.db_pool, &market).await {
+        Ok(market) => market,
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to create market"),
+    };
+    if let Err(e) = ensure_empty_order_book(&app_state.db_pool, market.id).await {
+        eprintln!("Error ensuring empty order book: {}", e);
+    }
+
+    app_state.add_market(market.clone());
+    HttpResponse::Created().json(market)
+}
+
+// Indicative uncross price and volume while the market is in a call auction
+pub async fn get_auction(symbol: web::Path<String>, app_state: web::Data<AppState>) -> HttpResponse {
+    let symbol = symbol.into_inner();
+    let market_state = match app_state.market(&symbol) {
+        Some(market_state) => market_state,
+        None => return HttpResponse::NotFound().body(format!("Market {} not found", symbol)),
+    };
+
+    let order_book = market_state.order_book.lock().await;
+    let indicative = match order_book.phase {
+        TradingPhase::Auction => order_book.indicative_equilibrium(),
+        _ => None,
+    };
+    HttpResponse::Ok().json(AuctionStatusResponse {
+        phase: order_book.phase.clone(),
+        indicative_price: indicative.as_ref().map(|(price, _)| price.clone()),
+        indicative_volume: indicative.map(|(_, volume)| volume),
+    })
+}
+
+// Stop continuous matching and start collecting orders for a call auction
+pub async fn start_auction(symbol: web::Path<String>, app_state: web::Data<AppState>) -> HttpResponse {
+    let symbol = symbol.into_inner();
+    let market_state = match app_state.market(&symbol) {
+        Some(market_state) => market_state,
+        None => return HttpResponse::NotFound().body(format!("Market {} not found", symbol)),
+    };
+
+    let mut order_book = market_state.order_book.lock().await;
+    if order_book.phase != TradingPhase::Continuous {
+        return HttpResponse::Conflict().body(format!("Market is in the {} phase", order_book.phase));
+    }
+    set_phase(&market_state.market, &mut order_book, TradingPhase::Auction, "Call auction started", &app_state.db_pool).await;
+    HttpResponse::Ok().body("Auction started")
+}
+
+// Uncross the auction at the equilibrium price and resume continuous trading
+pub async fn uncross_auction_route(symbol: web::Path<String>, app_state: web::Data<AppState>) -> HttpResponse {
+    let symbol = symbol.into_inner();
+    let market_state = match app_state.market(&symbol) {
+        Some(market_state) => market_state,
+        None => return HttpResponse::NotFound().body(format!("Market {} not found", symbol)),
+    };
+
+    let mark_prices = app_state.mark_prices().await;
+    let mut order_book = market_state.order_book.lock().await;
+    if order_book.phase != TradingPhase::Auction {
+        return HttpResponse::Conflict().body("Market is not in an auction");
+    }
+    let uncross = uncross_auction(&market_state.market, &mut order_book, &mark_prices, &app_state.db_pool).await;
+    if uncross.settlement_error.is_some() {
+        return HttpResponse::InternalServerError().json(uncross);
+    }
+    HttpResponse::Ok().json(uncross)
+}
+
+// Stop all trading in a market until it is resumed
+pub async fn halt_market(
+    symbol: web::Path<String>,
+    request: Option<web::Json<PhaseChangeRequest>>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let symbol = symbol.into_inner();
+    let market_state = match app_state.market(&symbol) {
+        Some(market_state) => market_state,
+        None => return HttpResponse::NotFound().body(format!("Market {} not found", symbol)),
+    };
+    let reason = request.and_then(|r| r.into_inner().reason).unwrap_or_else(|| "Halted by operator".to_string());
+
+    let mut order_book = market_state.order_book.lock().await;
+    if order_book.phase == TradingPhase::Halted {
+        return HttpResponse::Conflict().body("Market is already halted");
+    }
+    set_phase(&market_state.market, &mut order_book, TradingPhase::Halted, &reason, &app_state.db_pool).await;
+    HttpResponse::Ok().body("Market halted")
+}
+
+// Resume a halted market. A book left crossed by the halt is resolved through an auction first.
+pub async fn resume_market(
+    symbol: web::Path<String>,
+    request: Option<web::Json<PhaseChangeRequest>>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let symbol = symbol.into_inner();
+    let market_state = match app_state.market(&symbol) {
+        Some(market_state) => market_state,
+        None => return HttpResponse::NotFound().body(format!("Market {} not found", symbol)),
+    };
+    let reason = request.and_then(|r| r.into_inner().reason).unwrap_or_else(|| "Resumed by operator".to_string());
+
+    let mut order_book = market_state.order_book.lock().await;
+    if order_book.phase != TradingPhase::Halted {
+        return HttpResponse::Conflict().body("Market is not halted");
+    }
+    let crossed = match (order_book.best_bid(), order_book.best_ask()) {
+        (Some(best_bid), Some(best_ask)) => best_bid >= best_ask,
+        _ => false,
+    };
+    let phase = if crossed { TradingPhase::Auction } else { TradingPhase::Continuous };
+    set_phase(&market_state.market, &mut order_book, phase.clone(), &reason, &app_state.db_pool).await;
+    HttpResponse::Ok().body(format!("Market resumed in the {} phase", phase))
+}
+
+// Halts, resumes and auctions, most recent first
+pub async fn get_market_events(
+    symbol: web::Path<String>,
+    query: web::Query<EventsQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let symbol = symbol.into_inner();
+    let market_state = match app_state.market(&symbol) {
+        Some(market_state) => market_state,
+        None => return HttpResponse::NotFound().body(format!("Market {} not found", symbol)),
+    };
+
+    match market_service::get_market_events(&app_state.db_pool, market_state.market.id, query.limit.unwrap_or(100)).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to fetch market events"),
+    }
+}
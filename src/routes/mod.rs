@@ -1,2 +1,8 @@
 pub mod account_routes;
 pub mod order_routes;
+pub mod market_routes;
+pub mod transfer_routes;
+pub mod asset_routes;
+pub mod position_routes;
+pub mod funding_routes;
+pub mod liquidation_routes;
@@ -1,139 +1,638 @@
     use actix_web::{web, HttpResponse, Responder};
     use serde::{Serialize, Deserialize};
-    use crate::models::order::{Order, L2OrderBook};
-    use crate::models::types::{Fill, EIP712DomainSeparator, Hash};
-    use std::sync::Mutex; // Mutex for thread safety
-    use ethereum_types::{H160, H256};
+    use crate::models::order::{Order, OrderEntry, OrderType, TimeInForce, PostOnlyAction, L2OrderBook};
+    use crate::models::types::{Fill, EIP712DomainSeparator};
+    use std::sync::{Arc, RwLock};
+    use tokio::sync::Mutex; // Async mutex, the order book stays locked across database calls
+    use std::collections::HashMap;
+    use ethereum_types::{H160, H256, U256};
     use std::str::FromStr;
     use crate::db::pool;
     use crate::services::order_service::{add_order_to_book}; // Import necessary service functions
-    use crate::services::order_service::AppState as OtherAppState; // Import AppState from order_service
     use crate::services::order_service::get_order_entry_by_hash;
     use crate::services::order_service::delete_order_entry_by_hash; 
     use crate::services::order_service::get_order_book_snapshot;
+    use crate::services::order_service::sweep_expired_orders;
+    use crate::services::order_service::SelfTradePrevented;
+    use crate::services::order_service::amend_order;
+    use crate::services::order_service::register_amendment;
+    use crate::services::order_service::cancel_resting_order;
+    use crate::services::order_service::release_order_hold;
+    use crate::services::order_service::restore_order_book;
+    use crate::services::market_service::load_markets;
+    use crate::models::market::{Market, TradingPhase};
+    use crate::eip712;
     use sqlx::PgPool;
     use anyhow::Result;
+    use bigdecimal::BigDecimal;
+    use chrono::{DateTime, Utc};
+    use std::time::Duration;
 
 
     #[derive(Serialize, Deserialize)]
     pub struct CreateOrderRequest {
         pub side: String,  // Bid or Ask
         pub amount: String, // Amount as a string to handle large numbers
-        pub price: String,  // Price as a string
+        #[serde(default)]
+        pub price: String,  // Price as a string, may be omitted for market orders
         pub trader_address: String, // Ethereum address
+        pub nonce: String,          // Unique per trader: a decimal integer or 0x-prefixed 32-byte hex
+        pub signature: String,      // Trader's eth_signTypedData_v4 signature of the EIP-712 order
+        pub order_type: Option<String>,   // Limit (default), Market, Stop_Market or Stop_Limit
+        pub trigger_price: Option<String>, // Stop orders: last trade price that activates the order
+        pub worst_price: Option<String>,  // Market orders: worst acceptable execution price
+        pub max_slippage: Option<String>, // Market orders: e.g. "0.01" for 1% from the best price
+        pub display_amount: Option<String>, // Iceberg orders: amount shown in the book at a time
+        pub time_in_force: Option<String>, // GTC (default), IOC, FOK or GTD
+        pub expires_at: Option<String>,    // GTD orders: RFC 3339 timestamp
+        pub post_only: Option<bool>,         // Never take liquidity
+        pub post_only_reprice: Option<bool>, // Re-price instead of rejecting a crossing post-only order
+        pub self_trade_prevention: Option<String>, // cancel_newest, cancel_oldest, cancel_both or decrement_and_cancel
     }
 
-    #[derive(Serialize, Deserialize)]
+    #[derive(Serialize, Deserialize, Default)]
     pub struct CreateOrderResponse {
         pub success: bool,
         pub message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub order_id: Option<i64>,      // Engine-assigned order id
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub order_hash: Option<String>, // Canonical hash to use with the order routes
         pub fills: Option<Vec<Fill>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub repriced_to: Option<BigDecimal>, // Set when a post-only order was moved behind the best price
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub self_trades: Option<Vec<SelfTradePrevented>>, // Self-trades cancelled instead of filled
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub circuit_breaker: Option<TradingPhase>, // Phase the market switched to if this order tripped the breaker
+    }
+
+    const STOP_WAITING: &str = "Stop order accepted, waiting for trigger. No funds are reserved until it triggers; it is rejected then if the balance does not cover it";
+
+    /// What anyone can see of a pending stop order. Its size and trigger stay private until it
+    /// triggers, so nobody can trade the market towards them.
+    #[derive(Serialize, Deserialize)]
+    pub struct StopOrderView {
+        pub order_hash: String,
+        pub market_id: i32,
+        pub order_type: OrderType,
+        pub expires_at: Option<DateTime<Utc>>,
+        pub message: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct AmendOrderRequest {
+        pub price: Option<String>,  // New price, keeps the current one if omitted
+        pub amount: Option<String>, // New total amount, keeps the current one if omitted
+        pub nonce: String,          // Unique per amend of this order, same format as order nonces
+        pub expires_at: String,     // RFC 3339 timestamp after which the signature is refused
+        pub signature: String,      // Trader's signature of the EIP-712 Amend message
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct CancelOrderQuery {
+        pub expires_at: String, // RFC 3339 timestamp after which the signature is refused
+        pub signature: String,  // Trader's signature of the EIP-712 Cancel message
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct AmendOrderResponse {
+        pub success: bool,
+        pub message: String,
+        pub order: Option<OrderEntry>,
+    }
+
+    /// A listed market and its order book.
+    pub struct MarketState {
+        pub market: Market,
+        pub order_book: Mutex<L2OrderBook>, // Held across awaits, so a std mutex would block the worker thread
     }
 
     pub struct AppState {
-        pub order_book: Mutex<L2OrderBook>, // Using Mutex to ensure thread safety for the order book
+        pub markets: RwLock<HashMap<String, Arc<MarketState>>>, // Keyed by market symbol
         pub domain_separator: EIP712DomainSeparator,
         pub db_pool: PgPool,
     }
 
     impl AppState {
-        fn new(domain_separator: EIP712DomainSeparator , db_pool: PgPool) -> Self {
-            AppState {
-                order_book: Mutex::new(L2OrderBook { asks: Vec::new(), bids: Vec::new() }),
+        fn new(domain_separator: EIP712DomainSeparator , db_pool: PgPool, markets: Vec<Market>) -> Self {
+            let app_state = AppState {
+                markets: RwLock::new(HashMap::new()),
                 domain_separator,
                 db_pool,
+            };
+            for market in markets {
+                app_state.add_market(market);
+            }
+            app_state
+        }
+
+        pub fn market(&self, symbol: &str) -> Option<Arc<MarketState>> {
+            self.markets.read().unwrap().get(&symbol.to_uppercase()).cloned()
+        }
+
+        pub fn all_markets(&self) -> Vec<Arc<MarketState>> {
+            let mut markets: Vec<_> = self.markets.read().unwrap().values().cloned().collect();
+            markets.sort_by_key(|state| state.market.id);
+            markets
+        }
+
+        /// Mark price of every market that has one, keyed by market id. Books are locked one at a
+        /// time, so callers must not hold a book lock themselves.
+        pub async fn mark_prices(&self) -> HashMap<i32, BigDecimal> {
+            let mut mark_prices = HashMap::new();
+            for market_state in self.all_markets() {
+                if let Some(mark_price) = market_state.order_book.lock().await.mark_price() {
+                    mark_prices.insert(market_state.market.id, mark_price.clone());
+                }
             }
+            mark_prices
+        }
+
+        pub fn add_market(&self, market: Market) {
+            let state = MarketState { market: market.clone(), order_book: Mutex::new(L2OrderBook::new()) };
+            self.markets.write().unwrap().insert(market.symbol.to_uppercase(), Arc::new(state));
+        }
+    }
+
+    // Nonces are decimal integers or 0x-prefixed 32-byte hex
+    pub(crate) fn parse_nonce(nonce: &str) -> Option<H256> {
+        if nonce.starts_with("0x") {
+            return H256::from_str(nonce).ok();
         }
+        let nonce = U256::from_dec_str(nonce).ok()?;
+        Some(H256::from(nonce.to_big_endian()))
+    }
+
+    // Checks that the EIP-712 message with hash `struct_hash` was signed by `trader`
+    pub(crate) fn verify_signature(domain: &EIP712DomainSeparator, struct_hash: &H256, signature: &str, trader: &H160) -> Result<(), String> {
+        let digest = eip712::typed_data_hash(&domain.hash(), struct_hash);
+        let signer = eip712::recover_signer(&digest, signature)?;
+        if signer != *trader {
+            return Err(format!("Signature is from {:?}, not the trader {:?}", signer, trader));
+        }
+        Ok(())
+    }
+
+    // Cancel, amend and withdrawal signatures carry a deadline, signed as Unix seconds
+    pub(crate) fn parse_deadline(expires_at: &str) -> Result<U256, String> {
+        let deadline = DateTime::parse_from_rfc3339(expires_at)
+            .map_err(|_| "Invalid expiry time".to_string())?
+            .with_timezone(&Utc);
+        if deadline <= Utc::now() {
+            return Err("Signature has expired".to_string());
+        }
+        Ok(U256::from(deadline.timestamp() as u64))
+    }
+
+    fn parse_field<T: FromStr>(label: &str, value: &str) -> Result<T, String> {
+        value.parse().map_err(|_| format!("Invalid {}: {}", label, value))
+    }
+
+    fn parse_optional<T: FromStr>(label: &str, value: &Option<String>) -> Result<Option<T>, String> {
+        value.as_deref().map(|value| parse_field(label, value)).transpose()
+    }
+
+    // Builds the order a create request describes; anything malformed is the client's error
+    fn parse_order(market_id: i32, order_data: &CreateOrderRequest) -> Result<Order, String> {
+        let order_type = parse_optional("order type", &order_data.order_type)?.unwrap_or(OrderType::Limit);
+        let price = match order_type {
+            OrderType::Market | OrderType::StopMarket if order_data.price.is_empty() => BigDecimal::from(0),
+            _ => parse_field("price", &order_data.price)?,
+        };
+        let expires_at = match &order_data.expires_at {
+            Some(expires_at) => Some(
+                DateTime::parse_from_rfc3339(expires_at)
+                    .map_err(|_| format!("Invalid expiry time: {}", expires_at))?
+                    .with_timezone(&Utc),
+            ),
+            None => None,
+        };
+        Ok(Order {
+            market_id,
+            side: parse_field("side", &order_data.side)?,
+            amount: parse_field("amount", &order_data.amount)?,
+            price,
+            trader_address: parse_field("Ethereum address", &order_data.trader_address)?,
+            order_id: 0, // Assigned when the order is accepted
+            nonce: parse_nonce(&order_data.nonce).ok_or_else(|| "Invalid nonce".to_string())?,
+            order_type,
+            trigger_price: parse_optional("trigger price", &order_data.trigger_price)?,
+            worst_price: parse_optional("worst price", &order_data.worst_price)?,
+            max_slippage: parse_optional("max slippage", &order_data.max_slippage)?,
+            display_amount: parse_optional("display amount", &order_data.display_amount)?,
+            time_in_force: parse_optional("time in force", &order_data.time_in_force)?.unwrap_or(TimeInForce::GTC),
+            expires_at,
+            post_only: match (order_data.post_only, order_data.post_only_reprice) {
+                (Some(true), Some(true)) => Some(PostOnlyAction::Reprice),
+                (Some(true), _) => Some(PostOnlyAction::Reject),
+                _ => None,
+            },
+            self_trade_prevention: parse_optional("self-trade prevention mode", &order_data.self_trade_prevention)?,
+            liquidation: false,
+        })
+    }
+
+    fn market_not_found(symbol: &str) -> HttpResponse {
+        HttpResponse::NotFound().body(format!("Market {} not found", symbol))
     }
 
     // Route to create a new order
     pub async fn create_order(
+        symbol: web::Path<String>,
         order_data: web::Json<CreateOrderRequest>,
         app_state: web::Data<AppState>,
     ) -> impl Responder {
-        // Parse the request data and create an order object
-        let order = Order {
-            side: order_data.side.clone().parse().expect("Invalid side"), // Expect side to be valid
-            amount: order_data.amount.clone().parse().expect("Invalid amount"),
-            price: order_data.price.clone().parse().expect("Invalid price"),
-            trader_address: H160::from_str(&order_data.trader_address).expect("Invalid Ethereum address"),
-            nonce: H256::from_low_u64_le(0), // Convert integer 0 to H256
-            
+        let symbol = symbol.into_inner();
+        let market_state = match app_state.market(&symbol) {
+            Some(market_state) => market_state,
+            None => return market_not_found(&symbol),
         };
+        let order = match parse_order(market_state.market.id, &order_data) {
+            Ok(order) => order,
+            Err(reason) => return HttpResponse::BadRequest().body(reason),
+        };
+        let order_type = order.order_type.clone();
+        let time_in_force = order.time_in_force.clone();
+
+        let signed_order = match order.to_eip712() {
+            Ok(signed_order) => signed_order,
+            Err(reason) => return HttpResponse::BadRequest().body(reason),
+        };
+
+        // Only the owner of the trader address can place orders for it
+        if let Err(reason) = verify_signature(&app_state.domain_separator, &signed_order.hash(), &order_data.signature, &order.trader_address) {
+            return HttpResponse::Unauthorized().json(CreateOrderResponse {
+                success: false,
+                message: reason,
+                ..Default::default()
+            });
+        }
 
         // Create a database pool
         let db_pool = pool::create_pool().await.expect("Failed to create DB pool");
 
+        // Other markets' mark prices value the trader's positions there when checking margin
+        let mark_prices = app_state.mark_prices().await;
+
         // Lock the Mutex to access the order book
-        let mut order_book = app_state.order_book.lock().unwrap();
-        let order_book_ref = &mut *order_book;
+        let mut order_book = market_state.order_book.lock().await;
 
         // Add order to the order book and try matching
-        let fills = add_order_to_book(order, &mut order_book, &app_state.domain_separator, &db_pool).await;
+        let placement = add_order_to_book(order, &market_state.market, &mut order_book, &app_state.domain_separator, &mark_prices, &db_pool).await;
+        if let Some(reason) = placement.rejection {
+            return HttpResponse::BadRequest().json(CreateOrderResponse {
+                success: false,
+                message: reason,
+                ..Default::default()
+            });
+        }
+        let accepted = || CreateOrderResponse {
+            order_id: placement.order_id,
+            order_hash: placement.order_hash.clone(),
+            ..Default::default()
+        };
+        let fills = placement.fills;
+        let repriced_to = placement.repriced_to;
+        let self_trades = if placement.self_trades.is_empty() { None } else { Some(placement.self_trades) };
+
+        // Fills before the failure stand; the rest of the order was cancelled
+        if let Some(reason) = placement.settlement_error {
+            return HttpResponse::InternalServerError().json(CreateOrderResponse {
+                success: false,
+                message: reason,
+                fills: if fills.is_empty() { None } else { Some(fills) },
+                self_trades,
+                ..accepted()
+            });
+        }
+
+        if let Some(phase) = placement.circuit_breaker {
+            return HttpResponse::Ok().json(CreateOrderResponse {
+                success: !fills.is_empty(),
+                message: format!("Circuit breaker tripped, market is now {}", phase),
+                fills: if fills.is_empty() { None } else { Some(fills) },
+                self_trades,
+                circuit_breaker: Some(phase),
+                ..accepted()
+            });
+        }
+
         // Return success or failure message
-        if fills.is_empty() {
+        if matches!(order_type, OrderType::StopMarket | OrderType::StopLimit) {
             HttpResponse::Created().json(CreateOrderResponse {
                 success: true,
-                message: "Order placed successfully".to_string(),
-                fills: None,
+                message: STOP_WAITING.to_string(),
+                ..accepted()
+            })
+        } else if fills.is_empty() && self_trades.is_some() {
+            HttpResponse::Ok().json(CreateOrderResponse {
+                success: false,
+                message: "Order not filled: self-trade prevented".to_string(),
+                self_trades,
+                ..accepted()
+            })
+        } else if fills.is_empty() && order_type == OrderType::Market {
+            HttpResponse::Ok().json(CreateOrderResponse {
+                success: false,
+                message: "Market order not filled: no liquidity within the price bound".to_string(),
+                ..accepted()
+            })
+        } else if fills.is_empty() && time_in_force == TimeInForce::IOC {
+            HttpResponse::Ok().json(CreateOrderResponse {
+                success: false,
+                message: "Immediate-or-cancel order found no match and was cancelled".to_string(),
+                ..accepted()
+            })
+        } else if fills.is_empty() {
+            let message = match &repriced_to {
+                Some(price) => format!("Post-only order re-priced to {} and placed", price),
+                None => "Order placed successfully".to_string(),
+            };
+            HttpResponse::Created().json(CreateOrderResponse {
+                success: true,
+                message,
+                repriced_to,
+                ..accepted()
             })
         } else {
             HttpResponse::Created().json(CreateOrderResponse {
                 success: true,
                 message: "Order matched and filled".to_string(),
                 fills: Some(fills),
+                self_trades,
+                ..accepted()
             })
         }
     }
 
-    // Example of initializing EIP712DomainSeparator (ensure you fill in other required fields)
-    pub fn initialize_app_state(db_pool: PgPool) -> AppState {
+    // Atomically change the price and/or amount of a resting order
+    pub async fn amend_order_route(
+        path: web::Path<(String, String)>,
+        amend_data: web::Json<AmendOrderRequest>,
+        app_state: web::Data<AppState>,
+    ) -> impl Responder {
+        let (symbol, hash_str) = path.into_inner(); // Get the market and hash from the path
+        let market_state = match app_state.market(&symbol) {
+            Some(market_state) => market_state,
+            None => return market_not_found(&symbol),
+        };
+        let order_hash = match H256::from_str(&hash_str) {
+            Ok(order_hash) => order_hash,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid order hash"),
+        };
+        let new_price = match amend_data.price.as_ref().map(|p| p.parse::<BigDecimal>()).transpose() {
+            Ok(price) => price,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid price"),
+        };
+        let new_amount = match amend_data.amount.as_ref().map(|a| a.parse::<BigDecimal>()).transpose() {
+            Ok(amount) => amount,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid amount"),
+        };
+        let nonce = match parse_nonce(&amend_data.nonce) {
+            Some(nonce) => nonce,
+            None => return HttpResponse::BadRequest().body("Invalid nonce"),
+        };
+        let expiry = match parse_deadline(&amend_data.expires_at) {
+            Ok(expiry) => expiry,
+            Err(reason) => return HttpResponse::BadRequest().body(reason),
+        };
+
+        // Same lock as order placement, so no match can run between the checks and the update
+        let mut order_book = market_state.order_book.lock().await;
+        let trader = match order_book.get(&format!("{:?}", order_hash)) {
+            Some(entry) => entry.trader_address,
+            None => return HttpResponse::NotFound().body("Order not found"),
+        };
+        let (signed_price, signed_amount) = match (
+            eip712::to_fixed_point(new_price.as_ref().unwrap_or(&BigDecimal::from(0))),
+            eip712::to_fixed_point(new_amount.as_ref().unwrap_or(&BigDecimal::from(0))),
+        ) {
+            (Ok(price), Ok(amount)) => (price, amount),
+            (Err(reason), _) => return HttpResponse::BadRequest().body(format!("Price {}", reason)),
+            (_, Err(reason)) => return HttpResponse::BadRequest().body(format!("Amount {}", reason)),
+        };
+        let amend_hash = eip712::amend_hash(&order_hash, signed_price, signed_amount, U256::from_big_endian(nonce.as_bytes()), expiry);
+        if let Err(reason) = verify_signature(&app_state.domain_separator, &amend_hash, &amend_data.signature, &trader) {
+            return HttpResponse::Unauthorized().body(reason);
+        }
+        // The nonce is used up even if the amend is then refused, like an order nonce
+        match register_amendment(&app_state.db_pool, &format!("{:?}", order_hash), &nonce).await {
+            Ok(true) => {}
+            Ok(false) => return HttpResponse::BadRequest().body(format!("Nonce {:?} has already amended this order", nonce)),
+            Err(e) => {
+                eprintln!("Failed to register amendment: {}", e);
+                return HttpResponse::InternalServerError().body("Failed to register amendment");
+            }
+        }
+        match amend_order(&format!("{:?}", order_hash), new_price, new_amount, &market_state.market, &mut order_book, &app_state.db_pool).await {
+            Ok(amended) => HttpResponse::Ok().json(AmendOrderResponse {
+                success: true,
+                message: if amended.kept_priority {
+                    "Order amended, time priority kept".to_string()
+                } else {
+                    "Order amended, time priority lost".to_string()
+                },
+                order: Some(amended.entry),
+            }),
+            Err(reason) if reason == "Order not found" => HttpResponse::NotFound().body(reason),
+            Err(reason) => HttpResponse::BadRequest().json(AmendOrderResponse {
+                success: false,
+                message: reason,
+                order: None,
+            }),
+        }
+    }
+
+    // A pending stop order, without its size or trigger
+    pub async fn get_stop_order_route(path: web::Path<(String, String)>, app_state: web::Data<AppState>) -> impl Responder {
+        let (symbol, hash_str) = path.into_inner(); // Get the market and hash from the path
+        let market_state = match app_state.market(&symbol) {
+            Some(market_state) => market_state,
+            None => return market_not_found(&symbol),
+        };
+        let order_hash = match H256::from_str(&hash_str) {
+            Ok(order_hash) => order_hash,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid order hash"),
+        };
+
+        let order_book = market_state.order_book.lock().await;
+        match order_book.stops.get(&format!("{:?}", order_hash)) {
+            Some(stop) => HttpResponse::Ok().json(StopOrderView {
+                order_hash: format!("{:?}", order_hash),
+                market_id: stop.market_id,
+                order_type: stop.order_type.clone(),
+                expires_at: stop.expires_at,
+                message: STOP_WAITING.to_string(),
+            }),
+            None => HttpResponse::NotFound().body("Stop order not found"),
+        }
+    }
+
+    pub async fn delete_stop_order_route(
+        path: web::Path<(String, String)>,
+        query: web::Query<CancelOrderQuery>,
+        app_state: web::Data<AppState>,
+    ) -> impl Responder {
+        let (symbol, hash_str) = path.into_inner(); // Get the market and hash from the path
+        let market_state = match app_state.market(&symbol) {
+            Some(market_state) => market_state,
+            None => return market_not_found(&symbol),
+        };
+        let order_hash = match H256::from_str(&hash_str) {
+            Ok(order_hash) => order_hash,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid order hash"),
+        };
+
+        let expiry = match parse_deadline(&query.expires_at) {
+            Ok(expiry) => expiry,
+            Err(reason) => return HttpResponse::BadRequest().body(reason),
+        };
+
+        let mut order_book = market_state.order_book.lock().await;
+        let trader = match order_book.stops.get(&format!("{:?}", order_hash)) {
+            Some(stop) => stop.trader_address,
+            None => return HttpResponse::NotFound().body("Stop order not found"),
+        };
+        if let Err(reason) = verify_signature(&app_state.domain_separator, &eip712::cancel_hash(&order_hash, expiry), &query.signature, &trader) {
+            return HttpResponse::Unauthorized().body(reason);
+        }
+        match order_book.stops.remove(&format!("{:?}", order_hash)) {
+            Some(_) => HttpResponse::Ok().body("Stop order deleted successfully"),
+            None => HttpResponse::NotFound().body("Stop order not found"),
+        }
+    }
+
+    // Background task removing expired GTD orders from the book
+    pub async fn run_expiry_sweeper(app_state: web::Data<AppState>) {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            for market_state in app_state.all_markets() {
+                let mut order_book = market_state.order_book.lock().await;
+                let expired = sweep_expired_orders(&market_state.market, &mut order_book, Utc::now(), &app_state.db_pool).await;
+                for eip712_hash in expired {
+                    eprintln!("GTD order expired in {}: {}", market_state.market.symbol, eip712_hash);
+                }
+            }
+        }
+    }
+
+    // The EIP-712 signing domain takes its chain id and verifying contract from CHAIN_ID and
+    // VERIFYING_CONTRACT, defaulting to mainnet and the zero address
+    pub async fn initialize_app_state(db_pool: PgPool) -> AppState {
         let domain_separator = EIP712DomainSeparator {
             name: "DDX take-home".to_string(),
             version: "0.1.0".to_string(),
-            // Add other required fields here...
+            chain_id: std::env::var("CHAIN_ID").ok().and_then(|id| id.parse().ok()).unwrap_or(1),
+            verifying_contract: std::env::var("VERIFYING_CONTRACT")
+                .ok()
+                .and_then(|address| H160::from_str(&address).ok())
+                .unwrap_or_default(),
         };
         
-        AppState::new(domain_separator,db_pool)
+        let markets = load_markets(&db_pool).await.expect("Failed to load markets");
+        let app_state = AppState::new(domain_separator, db_pool, markets);
+
+        // Resting orders survive a restart, so the funds they hold stay backed by orders in the book
+        for market_state in app_state.all_markets() {
+            let mut order_book = market_state.order_book.lock().await;
+            match restore_order_book(&app_state.db_pool, market_state.market.id, &mut order_book).await {
+                Ok(restored) if restored > 0 => eprintln!("Restored {} resting orders in {}", restored, market_state.market.symbol),
+                Ok(_) => {}
+                Err(e) => panic!("Failed to restore the {} order book: {}", market_state.market.symbol, e),
+            }
+        }
+        app_state
     }
 
-    pub async fn get_order_by_hash_route(hash: web::Path<String>, app_state: web::Data<AppState>) -> impl Responder {
+    pub async fn get_order_by_hash_route(path: web::Path<(String, String)>, app_state: web::Data<AppState>) -> impl Responder {
         let db_pool = pool::create_pool().await.expect("Failed to create DB pool");
-        let hash_str = hash.into_inner(); // Get the hash from the path
+        let (symbol, hash_str) = path.into_inner(); // Get the market and hash from the path
+        let market_state = match app_state.market(&symbol) {
+            Some(market_state) => market_state,
+            None => return market_not_found(&symbol),
+        };
+        let order_hash = match H256::from_str(&hash_str) {
+            Ok(order_hash) => order_hash,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid order hash"),
+        };
 
-        match get_order_entry_by_hash(&db_pool, &H256::from_str(&hash_str).unwrap()).await {
+        match get_order_entry_by_hash(&db_pool, market_state.market.id, &order_hash).await {
             Ok(Some(order_entry)) => HttpResponse::Ok().json(order_entry),
             Ok(None) => HttpResponse::NotFound().body("Order not found"),
             Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
         }
     }
 
-    pub async fn delete_order_entry_by_hash_route(hash: web::Path<String>, app_state: web::Data<AppState>) -> impl Responder {
+    pub async fn delete_order_entry_by_hash_route(
+        path: web::Path<(String, String)>,
+        query: web::Query<CancelOrderQuery>,
+        app_state: web::Data<AppState>,
+    ) -> impl Responder {
         let db_pool = pool::create_pool().await.expect("Failed to create DB pool");
-        let hash_str = hash.into_inner(); // Get the hash from the path
+        let (symbol, hash_str) = path.into_inner(); // Get the market and hash from the path
+        let market_state = match app_state.market(&symbol) {
+            Some(market_state) => market_state,
+            None => return market_not_found(&symbol),
+        };
+        let order_hash = match H256::from_str(&hash_str) {
+            Ok(order_hash) => order_hash,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid order hash"),
+        };
+        let expiry = match parse_deadline(&query.expires_at) {
+            Ok(expiry) => expiry,
+            Err(reason) => return HttpResponse::BadRequest().body(reason),
+        };
 
-        match delete_order_entry_by_hash(&db_pool, &H256::from_str(&hash_str).unwrap()).await {
-            Ok(deleted) if deleted => HttpResponse::Ok().body("Order deleted successfully"),
-            Ok(_) => HttpResponse::NotFound().body("Order not found"),
+        // Only the order's trader may cancel it
+        let resting_trader = market_state
+            .order_book
+            .lock()
+            .await
+            .get(&format!("{:?}", order_hash))
+            .map(|entry| entry.trader_address);
+        let trader = match resting_trader {
+            Some(trader) => trader,
+            None => match get_order_entry_by_hash(&db_pool, market_state.market.id, &order_hash).await {
+                Ok(Some(entry)) => entry.trader_address,
+                Ok(None) => return HttpResponse::NotFound().body("Order not found"),
+                Err(_) => return HttpResponse::InternalServerError().body("Internal Server Error"),
+            },
+        };
+        if let Err(reason) = verify_signature(&app_state.domain_separator, &eip712::cancel_hash(&order_hash, expiry), &query.signature, &trader) {
+            return HttpResponse::Unauthorized().body(reason);
+        }
+
+        // Drop the order from the in-memory book through its hash index so it can no longer match,
+        // and give the trader back the funds it held
+        let mut order_book = market_state.order_book.lock().await;
+        let in_book = cancel_resting_order(&market_state.market, &mut order_book, &format!("{:?}", order_hash), &db_pool).await.is_some();
+
+        match delete_order_entry_by_hash(&db_pool, market_state.market.id, &order_hash).await {
             Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+            Ok(_) if in_book => HttpResponse::Ok().body("Order deleted successfully"),
+            // Only stored, not in the book: its hold is released here instead
+            Ok(Some((side, entry))) => {
+                release_order_hold(&db_pool, &market_state.market, &side, &entry).await;
+                HttpResponse::Ok().body("Order deleted successfully")
+            }
+            Ok(None) => HttpResponse::NotFound().body("Order not found"),
         }
     }
 
 
-    pub async fn get_order_book(app_state: web::Data<AppState>) -> impl Responder {
+    pub async fn get_order_book(symbol: web::Path<String>, app_state: web::Data<AppState>) -> impl Responder {
         let db_pool = pool::create_pool().await.expect("Failed to create DB pool");
-        match get_order_book_snapshot(&db_pool).await {
+        let symbol = symbol.into_inner();
+        let market_state = match app_state.market(&symbol) {
+            Some(market_state) => market_state,
+            None => return market_not_found(&symbol),
+        };
+        match get_order_book_snapshot(&db_pool, market_state.market.id).await {
             Ok(order_book) => HttpResponse::Ok().json(order_book),
             Err(e) => {
-                // Print error message and backtrace if available
                 eprintln!("Error fetching order book: {:?}", e);
-                if let backtrace = e.backtrace() {
-                    eprintln!("Backtrace: {:?}", backtrace);
-                }
-    
-                // Print debug information about `e`
-                dbg!(&e);
-    
                 HttpResponse::InternalServerError().finish()
             }
         }
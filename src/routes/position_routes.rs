@@ -0,0 +1,52 @@
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use bigdecimal::BigDecimal;
+use ethereum_types::H160;
+use std::str::FromStr;
+use crate::models::position::Position;
+use crate::routes::order_routes::AppState;
+use crate::services::position_service;
+
+#[derive(Serialize)]
+pub struct PositionResponse {
+    pub market: String, // Market symbol
+    #[serde(flatten)]
+    pub position: Position,
+    pub mark_price: Option<BigDecimal>,     // None until the market has an index price or a trade
+    pub unrealized_pnl: Option<BigDecimal>, // PnL of closing the position at the mark price
+    pub initial_margin: BigDecimal,         // Collateral the position ties up, at its entry price
+}
+
+// A trader's positions in perpetual markets, with their PnL at each market's mark price
+pub async fn get_positions(trader_address: web::Path<String>, app_state: web::Data<AppState>) -> HttpResponse {
+    let trader_address = match H160::from_str(&trader_address) {
+        Ok(address) => address,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid Ethereum address"),
+    };
+
+    let positions = match position_service::get_positions(&app_state.db_pool, &trader_address).await {
+        Ok(positions) => positions,
+        Err(e) => {
+            eprintln!("Failed to fetch positions: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to fetch positions");
+        }
+    };
+
+    let markets = app_state.all_markets();
+    let mut response = Vec::new();
+    for position in positions {
+        let market_state = match markets.iter().find(|state| state.market.id == position.market_id) {
+            Some(market_state) => market_state,
+            None => continue,
+        };
+        let mark_price = market_state.order_book.lock().await.mark_price().cloned();
+        response.push(PositionResponse {
+            market: market_state.market.symbol.clone(),
+            unrealized_pnl: mark_price.as_ref().map(|mark_price| position.unrealized_pnl(mark_price)),
+            initial_margin: market_state.market.margin_for(&position.notional(&position.entry_price)),
+            mark_price,
+            position,
+        });
+    }
+    HttpResponse::Ok().json(response)
+}
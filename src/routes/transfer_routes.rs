@@ -0,0 +1,163 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use bigdecimal::BigDecimal;
+use ethereum_types::{H160, U256};
+use std::str::FromStr;
+use crate::eip712;
+use crate::models::transfer::TransferStatus;
+use crate::routes::order_routes::{parse_deadline, parse_nonce, verify_signature, AppState};
+use crate::services::transfer_service;
+
+#[derive(Serialize, Deserialize)]
+pub struct TransferRequest {
+    pub asset: String,  // e.g. USD
+    pub amount: String, // Amount as a string to handle large numbers
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WithdrawalRequest {
+    pub asset: String,      // e.g. USD
+    pub amount: String,     // Amount as a string to handle large numbers
+    pub nonce: String,      // Unique per trader: a decimal integer or 0x-prefixed 32-byte hex
+    pub expires_at: String, // RFC 3339; the signature is refused after this
+    pub signature: String,  // Trader's eth_signTypedData_v4 signature of the EIP-712 withdrawal
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RejectWithdrawalRequest {
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TransfersQuery {
+    pub limit: Option<i64>, // Defaults to 100
+}
+
+// Parses the trader address from the path and the amount from the body
+fn parse_transfer(trader_address: &str, amount: &str) -> Result<(H160, BigDecimal), HttpResponse> {
+    let trader_address = H160::from_str(trader_address)
+        .map_err(|_| HttpResponse::BadRequest().body("Invalid Ethereum address"))?;
+    let amount = BigDecimal::from_str(amount)
+        .map_err(|_| HttpResponse::BadRequest().body(format!("Invalid amount: {}", amount)))?;
+    Ok((trader_address, amount))
+}
+
+// Deposits and withdrawal settlements move real funds, so only the back office may make them.
+// It sends the token in ADMIN_TOKEN as a bearer token; without ADMIN_TOKEN set nobody can.
+fn require_admin(request: &HttpRequest) -> Result<(), HttpResponse> {
+    let token = match std::env::var("ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => token,
+        _ => return Err(HttpResponse::Forbidden().body("Admin access is not configured")),
+    };
+    let bearer = request
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if bearer != Some(token.as_str()) {
+        return Err(HttpResponse::Unauthorized().body("Admin token required"));
+    }
+    Ok(())
+}
+
+// Credit funds to a trader's available balance
+pub async fn deposit(
+    http_request: HttpRequest,
+    trader_address: web::Path<String>,
+    request: web::Json<TransferRequest>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(&http_request) {
+        return response;
+    }
+    let (trader_address, amount) = match parse_transfer(&trader_address, &request.amount) {
+        Ok(parsed) => parsed,
+        Err(response) => return response,
+    };
+    match transfer_service::deposit(&app_state.db_pool, &trader_address, &request.asset, &amount).await {
+        Ok(transfer) => HttpResponse::Created().json(transfer),
+        Err(reason) => HttpResponse::BadRequest().body(reason),
+    }
+}
+
+// Lock funds for a withdrawal, pending until the back office completes or rejects it
+pub async fn request_withdrawal(
+    trader_address: web::Path<String>,
+    request: web::Json<WithdrawalRequest>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let (trader_address, amount) = match parse_transfer(&trader_address, &request.amount) {
+        Ok(parsed) => parsed,
+        Err(response) => return response,
+    };
+    let nonce = match parse_nonce(&request.nonce) {
+        Some(nonce) => nonce,
+        None => return HttpResponse::BadRequest().body("Invalid nonce"),
+    };
+    let expiry = match parse_deadline(&request.expires_at) {
+        Ok(expiry) => expiry,
+        Err(reason) => return HttpResponse::BadRequest().body(reason),
+    };
+    let signed_amount = match eip712::to_fixed_point(&amount) {
+        Ok(signed_amount) => signed_amount,
+        Err(reason) => return HttpResponse::BadRequest().body(format!("Amount {}", reason)),
+    };
+
+    // Only the owner of the trader address can withdraw from it
+    let withdrawal_hash = eip712::withdrawal_hash(&trader_address, &request.asset, signed_amount, U256::from_big_endian(nonce.as_bytes()), expiry);
+    if let Err(reason) = verify_signature(&app_state.domain_separator, &withdrawal_hash, &request.signature, &trader_address) {
+        return HttpResponse::Unauthorized().body(reason);
+    }
+
+    let mark_prices = app_state.mark_prices().await;
+    match transfer_service::request_withdrawal(&app_state.db_pool, &trader_address, &request.asset, &amount, &nonce, &mark_prices).await {
+        Ok(transfer) => HttpResponse::Created().json(transfer),
+        Err(reason) => HttpResponse::BadRequest().body(reason),
+    }
+}
+
+// Deposits and withdrawals with their status, most recent first
+pub async fn get_transfers(
+    trader_address: web::Path<String>,
+    query: web::Query<TransfersQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let trader_address = match H160::from_str(&trader_address) {
+        Ok(address) => address,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid Ethereum address"),
+    };
+    match transfer_service::get_transfers(&app_state.db_pool, &trader_address, query.limit.unwrap_or(100)).await {
+        Ok(transfers) => HttpResponse::Ok().json(transfers),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to fetch transfers"),
+    }
+}
+
+async fn settle_withdrawal(app_state: &AppState, transfer_id: i64, status: TransferStatus, reason: Option<String>) -> HttpResponse {
+    match transfer_service::settle_withdrawal(&app_state.db_pool, transfer_id, status, reason).await {
+        Ok(Some(transfer)) => HttpResponse::Ok().json(transfer),
+        Ok(None) => HttpResponse::NotFound().body(format!("Withdrawal {} not found", transfer_id)),
+        Err(reason) => HttpResponse::Conflict().body(reason),
+    }
+}
+
+// Pay out a pending withdrawal
+pub async fn complete_withdrawal(http_request: HttpRequest, transfer_id: web::Path<i64>, app_state: web::Data<AppState>) -> HttpResponse {
+    if let Err(response) = require_admin(&http_request) {
+        return response;
+    }
+    settle_withdrawal(&app_state, transfer_id.into_inner(), TransferStatus::Completed, None).await
+}
+
+// Turn down a pending withdrawal and return its funds to the available balance
+pub async fn reject_withdrawal(
+    http_request: HttpRequest,
+    transfer_id: web::Path<i64>,
+    request: Option<web::Json<RejectWithdrawalRequest>>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(&http_request) {
+        return response;
+    }
+    let reason = request.and_then(|request| request.into_inner().reason);
+    settle_withdrawal(&app_state, transfer_id.into_inner(), TransferStatus::Rejected, reason).await
+}
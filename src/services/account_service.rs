@@ -1,10 +1,42 @@
 use crate::db::pool::create_pool; // Assuming pool.rs is in the db module
-use crate::models::account::Account;
-use sqlx::{PgPool};
-use bigdecimal::BigDecimal;
+use crate::models::account::{Account, Balance};
+use crate::models::ledger::LedgerCause;
+use crate::services::ledger_service;
+use sqlx::{Executor, PgConnection, PgPool, Postgres};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 use ethereum_types::H160;
 use std::str::FromStr;
+use bigdecimal::BigDecimal;
+
+// An account's balances, one per asset it has held
+async fn fetch_balances<'e, E: Executor<'e, Database = Postgres>>(executor: E, account_id: Uuid) -> Result<BTreeMap<String, Balance>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT asset, available, locked
+        FROM balances
+        WHERE account_id = $1
+        "#,
+        account_id
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.asset, Balance { available: row.available, locked: row.locked }))
+        .collect())
+}
+
+pub(crate) fn decode_address(trader_address: &str) -> Result<H160, sqlx::Error> {
+    H160::from_str(trader_address).map_err(|_| {
+        sqlx::Error::ColumnDecode {
+            index: "trader_address".into(),
+            source: Box::new(sqlx::error::Error::Decode("H160 decode error".into())),
+        }
+    })
+}
+
 pub async fn create_account_in_db(account: &Account) -> Result<(), sqlx::Error> {
     let pool = create_pool().await?;
 
@@ -28,19 +60,28 @@ pub async fn create_account_in_db(account: &Account) -> Result<(), sqlx::Error>
         return Err(sqlx::Error::RowNotFound); // Or create a custom error type
     }
 
-    // Insert the new account into the database
+    // Insert the new account into the database, with its starting balances in the ledger
+    let mut tx = pool.begin().await?;
     sqlx::query!(
         r#"
-        INSERT INTO accounts (id, trader_address, ddx_balance, usd_balance)
-        VALUES ($1, $2, $3, $4)
+        INSERT INTO accounts (id, trader_address, stp_mode)
+        VALUES ($1, $2, $3)
         "#,
         account_id,
         format!("{:?}", account.trader_address), // Ensure the full address is stored
-        account.ddx_balance,
-        account.usd_balance,
+        account.stp_mode.as_ref().map(|mode| mode.to_string()),
+    )
+    .execute(&mut tx)
+    .await?;
+    save_account(&mut tx, account).await?;
+    ledger_service::record_movement(
+        &mut tx,
+        &LedgerCause::Adjustment,
+        &account_id.to_string(),
+        &[(&Account::empty(account.trader_address), account)],
     )
-    .execute(&pool)
     .await?;
+    tx.commit().await?;
 
     Ok(())
 }
@@ -56,7 +97,7 @@ pub async fn get_account_from_db(address: &H160) -> Result<Account, sqlx::Error>
     // Fetch the account from the database
     let row = sqlx::query!(
         r#"
-        SELECT trader_address, ddx_balance, usd_balance
+        SELECT id, trader_address, stp_mode
         FROM accounts
         WHERE LOWER(trader_address) = LOWER($1)
         "#,
@@ -67,22 +108,14 @@ pub async fn get_account_from_db(address: &H160) -> Result<Account, sqlx::Error>
 
     match row {
         Ok(row) => {
-            log::info!("Row fetched: trader_address = {}, ddx_balance = {}, usd_balance = {}",
-                       row.trader_address, row.ddx_balance, row.usd_balance);
-
-            // Convert the address back to H160 from string
-            let trader_address = H160::from_str(&row.trader_address).map_err(|_| {
-                sqlx::Error::ColumnDecode {
-                    index: "trader_address".into(),
-                    source: Box::new(sqlx::error::Error::Decode("H160 decode error".into())),
-                }
-            })?;
+            let balances = fetch_balances(&pool, row.id).await?;
+            log::info!("Row fetched: trader_address = {}, balances = {:?}", row.trader_address, balances);
 
             // Return the account
             Ok(Account {
-                trader_address,
-                ddx_balance: row.ddx_balance,
-                usd_balance: row.usd_balance,
+                trader_address: decode_address(&row.trader_address)?,
+                balances,
+                stp_mode: row.stp_mode.and_then(|mode| mode.parse().ok()),
             })
         }
         Err(_) => {
@@ -93,59 +126,208 @@ pub async fn get_account_from_db(address: &H160) -> Result<Account, sqlx::Error>
 }
 
 
-pub async fn delete_account_from_db(address: &H160) -> Result<(), sqlx::Error> {
-    let pool = create_pool().await?;
-    
-    // Convert H160 address to string format for querying (full address representation)
-    let address_str = format!("{:?}", address); // Ensure correct format: 0x1234567890abcdef1234567890abcdef12345678
+/// Deletes an account that holds nothing: every balance zero, nothing locked by open orders or
+/// pending withdrawals, and no open perpetual position. Deleting anything else would take funds
+/// out of the books without a ledger entry. `false` if there is no such account.
+pub async fn delete_account_from_db(address: &H160) -> Result<bool, String> {
+    let failed = |e: sqlx::Error| format!("Failed to delete account: {}", e);
+    let pool = create_pool().await.map_err(failed)?;
+    let mut tx = pool.begin().await.map_err(failed)?;
+
+    // Locking the account first keeps fills and transfers from changing it until it is gone
+    let account = match lock_account(&mut tx, address).await {
+        Ok(account) => account,
+        Err(sqlx::Error::RowNotFound) => return Ok(false),
+        Err(e) => return Err(failed(e)),
+    };
+    let open_positions = sqlx::query_scalar!(
+        r#"
+        SELECT market_id
+        FROM positions
+        WHERE LOWER(trader_address) = LOWER($1) AND size <> 0
+        ORDER BY market_id
+        "#,
+        format!("{:?}", address)
+    )
+    .fetch_all(&mut tx)
+    .await
+    .map_err(failed)?;
+    check_deletable(&account, &open_positions)?;
 
-    let result = sqlx::query!(
+    sqlx::query!(
         r#"
         DELETE FROM accounts
         WHERE LOWER(trader_address) = LOWER($1)
         "#,
-        address_str
+        format!("{:?}", address)
     )
-    .execute(&pool)
-    .await?;
+    .execute(&mut tx)
+    .await
+    .map_err(failed)?;
+    tx.commit().await.map_err(failed)?;
+    Ok(true)
+}
 
-    if result.rows_affected() == 0 {
-        return Err(sqlx::Error::RowNotFound);
+// An account can only be deleted once it holds nothing and has no open positions
+fn check_deletable(account: &Account, open_positions: &[i32]) -> Result<(), String> {
+    let zero = BigDecimal::from(0);
+    for (asset, balance) in &account.balances {
+        if balance.available != zero || balance.locked != zero {
+            return Err(format!(
+                "Account still holds {} {} ({} locked)",
+                balance.available.clone() + balance.locked.clone(),
+                asset,
+                balance.locked
+            ));
+        }
+    }
+    if let Some(market_id) = open_positions.first() {
+        return Err(format!("Account still has an open position in market {}", market_id));
     }
-
     Ok(())
 }
 
 
 
-// In src/services/account_service.rs
-
-pub async fn update_account_in_db(account: &Account) -> Result<(), sqlx::Error> {
+/// Sets the available balance of each asset listed on `account`, and its self-trade prevention
+/// mode, as an admin adjustment recorded in the ledger under `request_id`. Assets not listed and
+/// locked balances, which belong to open orders and pending withdrawals, are kept.
+pub async fn adjust_account_in_db(account: &Account, request_id: &str) -> Result<Account, sqlx::Error> {
     let pool = create_pool().await?;
+    let mut tx = pool.begin().await?;
+
+    let before = lock_account(&mut tx, &account.trader_address).await?;
+    let mut after = before.clone();
+    for (asset, balance) in &account.balances {
+        after.balance_mut(asset).available = balance.available.clone();
+    }
+    after.stp_mode = account.stp_mode.clone();
+    save_account(&mut tx, &after).await?;
+    ledger_service::record_movement(&mut tx, &LedgerCause::Adjustment, request_id, &[(&before, &after)]).await?;
+    tx.commit().await?;
 
-    // Update the account in the database
-    let result = sqlx::query!(
+    Ok(after)
+}
+
+/// Fetches an account inside a transaction, locking its row until the transaction ends. Every
+/// balance change goes through this lock, so the account's balances cannot change underneath it.
+pub async fn lock_account(conn: &mut PgConnection, address: &H160) -> Result<Account, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT id, trader_address, stp_mode
+        FROM accounts
+        WHERE LOWER(trader_address) = LOWER($1)
+        FOR UPDATE
+        "#,
+        format!("{:?}", address)
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(Account {
+        trader_address: decode_address(&row.trader_address)?,
+        balances: fetch_balances(&mut *conn, row.id).await?,
+        stp_mode: row.stp_mode.and_then(|mode| mode.parse().ok()),
+    })
+}
+
+/// Writes an account's balances and settings inside a transaction. Balance changes must also be
+/// recorded with `ledger_service::record_movement`.
+pub async fn save_account(conn: &mut PgConnection, account: &Account) -> Result<(), sqlx::Error> {
+    let row = sqlx::query!(
         r#"
         UPDATE accounts
-        SET ddx_balance = $1, usd_balance = $2
-        WHERE LOWER(trader_address) = LOWER($3)
+        SET stp_mode = $1
+        WHERE LOWER(trader_address) = LOWER($2)
+        RETURNING id
         "#,
-        account.ddx_balance,
-        account.usd_balance,
-        format!("{:?}", account.trader_address) // Ensure the full address is stored
+        account.stp_mode.as_ref().map(|mode| mode.to_string()),
+        format!("{:?}", account.trader_address),
     )
-    .execute(&pool)
+    .fetch_optional(&mut *conn)
     .await?;
+    let account_id = row.ok_or(sqlx::Error::RowNotFound)?.id;
 
-    if result.rows_affected() == 0 {
-        return Err(sqlx::Error::RowNotFound);
+    for (asset, balance) in &account.balances {
+        sqlx::query!(
+            r#"
+            INSERT INTO balances (account_id, asset, available, locked)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (account_id, asset) DO UPDATE SET available = EXCLUDED.available, locked = EXCLUDED.locked
+            "#,
+            account_id,
+            asset,
+            balance.available,
+            balance.locked,
+        )
+        .execute(&mut *conn)
+        .await?;
     }
 
     Ok(())
 }
 
+/// Adds `amount` of `asset` to an account's available balance inside a transaction, a negative
+/// amount taking it away, even below zero. Returns the account before and after, for the caller
+/// to record in the ledger.
+pub async fn credit_account(
+    conn: &mut PgConnection,
+    address: &H160,
+    asset: &str,
+    amount: &BigDecimal,
+) -> Result<(Account, Account), sqlx::Error> {
+    let before = lock_account(&mut *conn, address).await?;
+    let mut after = before.clone();
+    after.adjust(asset, amount);
+    save_account(&mut *conn, &after).await?;
+    Ok((before, after))
+}
 
+/// A trader's balance of one asset, zero if they never held it. `RowNotFound` if there is no
+/// account for the address.
+pub async fn get_balance(db: &PgPool, address: &H160, asset: &str) -> Result<Balance, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT b.available AS "available?", b.locked AS "locked?"
+        FROM accounts a
+        LEFT JOIN balances b ON b.account_id = a.id AND b.asset = $2
+        WHERE LOWER(a.trader_address) = LOWER($1)
+        "#,
+        format!("{:?}", address),
+        asset,
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(Balance {
+        available: row.available.unwrap_or_default(),
+        locked: row.locked.unwrap_or_default(),
+    })
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn account(balances: &[(&str, &str, &str)]) -> Account {
+        let mut account = Account::empty(H160::from_low_u64_be(1));
+        for (asset, available, locked) in balances {
+            let balance = account.balance_mut(asset);
+            balance.available = BigDecimal::from_str(available).unwrap();
+            balance.locked = BigDecimal::from_str(locked).unwrap();
+        }
+        account
+    }
 
+    #[test]
+    fn only_empty_accounts_can_be_deleted() {
+        assert!(check_deletable(&Account::empty(H160::from_low_u64_be(1)), &[]).is_ok());
+        assert!(check_deletable(&account(&[("USD", "0", "0"), ("ETH", "0.000", "0")]), &[]).is_ok());
 
+        assert!(check_deletable(&account(&[("USD", "0", "0"), ("ETH", "0.5", "0")]), &[]).is_err());
+        // Funds locked by an open order or a pending withdrawal
+        assert!(check_deletable(&account(&[("USD", "0", "25")]), &[]).is_err());
+        // A loss taken below zero still has to be settled
+        assert!(check_deletable(&account(&[("USD", "-3", "0")]), &[]).is_err());
+        assert!(check_deletable(&account(&[("USD", "0", "0")]), &[2]).is_err());
+    }
+}
@@ -0,0 +1,46 @@
+use crate::models::asset::Asset;
+use sqlx::PgPool;
+
+pub async fn load_assets(db: &PgPool) -> Result<Vec<Asset>, sqlx::Error> {
+    sqlx::query_as!(
+        Asset,
+        r#"
+        SELECT symbol, decimals
+        FROM assets
+        ORDER BY symbol
+        "#
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn get_asset(db: &PgPool, symbol: &str) -> Result<Option<Asset>, sqlx::Error> {
+    sqlx::query_as!(
+        Asset,
+        r#"
+        SELECT symbol, decimals
+        FROM assets
+        WHERE symbol = $1
+        "#,
+        symbol
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Registers an asset. Returns `false` if an asset with that symbol already exists.
+pub async fn create_asset_in_db(db: &PgPool, asset: &Asset) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO assets (symbol, decimals)
+        VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+        asset.symbol,
+        asset.decimals
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
@@ -0,0 +1,109 @@
+use crate::models::account::Account;
+use crate::models::funding::FundingRate;
+use crate::models::ledger::LedgerCause;
+use crate::models::market::Market;
+use crate::services::account_service;
+use crate::services::ledger_service;
+use crate::services::position_service;
+use bigdecimal::BigDecimal;
+use sqlx::PgPool;
+
+/// Pays one interval's funding in `market`: every open position pays `size * mark_price * rate`
+/// out of its available quote balance, so longs pay shorts when the rate is positive and shorts
+/// pay longs when it is negative. Long and short sizes are equal, so the payments net to zero;
+/// should they not, the residual is booked to the clearing account and logged. The rate and every
+/// payment are written in one transaction, with each account locked in address order before its
+/// position.
+pub async fn settle_funding(
+    db: &PgPool,
+    market: &Market,
+    index_price: &BigDecimal,
+    mark_price: &BigDecimal,
+    rate: &BigDecimal,
+) -> sqlx::Result<FundingRate> {
+    let mut tx = db.begin().await?;
+    let funding = sqlx::query_as!(
+        FundingRate,
+        r#"
+        INSERT INTO funding_rates (market_id, index_price, mark_price, rate)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, market_id, index_price, mark_price, rate, created_at
+        "#,
+        market.id,
+        index_price,
+        mark_price,
+        rate,
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    let traders = sqlx::query!(
+        r#"
+        SELECT trader_address
+        FROM positions
+        WHERE market_id = $1 AND size <> 0
+        ORDER BY LOWER(trader_address)
+        "#,
+        market.id,
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    let mut changes: Vec<(Account, Account)> = Vec::new();
+    let mut residual = BigDecimal::from(0);
+    for row in traders {
+        let trader_address = account_service::decode_address(&row.trader_address)?;
+        // The account row is locked before the position, in the same order fills lock them
+        account_service::lock_account(&mut tx, &trader_address).await?;
+        let position = position_service::lock_position(&mut tx, &trader_address, market.id).await?;
+        let payment = position.size * mark_price.clone() * rate.clone();
+        residual += payment.clone();
+        changes.push(account_service::credit_account(&mut tx, &trader_address, &market.quote_asset, &-payment).await?);
+    }
+    if residual != BigDecimal::from(0) {
+        eprintln!(
+            "Funding {} in {} did not net to zero: {} {} booked to clearing",
+            funding.id, market.symbol, residual, market.quote_asset
+        );
+    }
+    let changes: Vec<(&Account, &Account)> = changes.iter().map(|(before, after)| (before, after)).collect();
+    ledger_service::record_movement(&mut tx, &LedgerCause::Funding, &funding.id.to_string(), &changes).await?;
+
+    tx.commit().await?;
+    Ok(funding)
+}
+
+/// The most recent funding payment in a market, `None` before its first.
+pub async fn latest_funding(db: &PgPool, market_id: i32) -> sqlx::Result<Option<FundingRate>> {
+    sqlx::query_as!(
+        FundingRate,
+        r#"
+        SELECT id, market_id, index_price, mark_price, rate, created_at
+        FROM funding_rates
+        WHERE market_id = $1
+        ORDER BY id DESC
+        LIMIT 1
+        "#,
+        market_id,
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Most recent funding payments first.
+pub async fn get_funding_history(db: &PgPool, market_id: i32, limit: i64) -> sqlx::Result<Vec<FundingRate>> {
+    sqlx::query_as!(
+        FundingRate,
+        r#"
+        SELECT id, market_id, index_price, mark_price, rate, created_at
+        FROM funding_rates
+        WHERE market_id = $1
+        ORDER BY id DESC
+        LIMIT $2
+        "#,
+        market_id,
+        limit,
+    )
+    .fetch_all(db)
+    .await
+}
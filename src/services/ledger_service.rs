@@ -0,0 +1,109 @@
+use crate::models::account::Account;
+use crate::models::ledger::{LedgerBalance, LedgerCause, LedgerEntry, AVAILABLE, LOCKED};
+use bigdecimal::BigDecimal;
+use std::collections::BTreeSet;
+use ethereum_types::H160;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+/// Records one balance movement as a ledger transaction. `changes` holds each affected account as
+/// it was before and after the movement; every asset and bucket that changed gets an entry. If
+/// the movement brings funds in or takes them out, the cause's offset account takes the other
+/// side, otherwise the movement has to balance on its own. Call this in the same database
+/// transaction that writes the accounts.
+pub async fn record_movement(
+    conn: &mut PgConnection,
+    cause: &LedgerCause,
+    cause_id: &str,
+    changes: &[(&Account, &Account)],
+) -> sqlx::Result<Uuid> {
+    let zero = BigDecimal::from(0);
+    let assets: BTreeSet<&str> = changes
+        .iter()
+        .flat_map(|(before, after)| before.balances.keys().chain(after.balances.keys()))
+        .map(|asset| asset.as_str())
+        .collect();
+    let mut entries: Vec<(String, &str, &str, BigDecimal)> = Vec::new();
+    for asset in assets {
+        let mut net = BigDecimal::from(0);
+        for (before, after) in changes {
+            let account = format!("{:?}", after.trader_address);
+            let available = after.available(asset) - before.available(asset);
+            let locked = after.locked(asset) - before.locked(asset);
+            for (bucket, amount) in [(AVAILABLE, available), (LOCKED, locked)] {
+                if amount != zero {
+                    net += amount.clone();
+                    entries.push((account.clone(), asset, bucket, amount));
+                }
+            }
+        }
+        if net != zero {
+            match cause.offset_account() {
+                Some(offset) => entries.push((offset.to_string(), asset, AVAILABLE, -net)),
+                None => {
+                    return Err(sqlx::Error::Protocol(format!(
+                        "{} {} does not balance: {} {} unaccounted for",
+                        cause, cause_id, net, asset
+                    )));
+                }
+            }
+        }
+    }
+
+    let transaction_id = Uuid::new_v4();
+    for (account, asset, bucket, amount) in entries {
+        sqlx::query!(
+            r#"
+            INSERT INTO ledger_entries (transaction_id, account, asset, bucket, amount, cause, cause_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            transaction_id,
+            account,
+            asset,
+            bucket,
+            amount,
+            cause.to_string(),
+            cause_id,
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(transaction_id)
+}
+
+/// A trader's ledger entries, newest first, optionally for one asset only.
+pub async fn get_ledger(db: &PgPool, trader_address: &H160, asset: Option<&str>, limit: i64) -> Result<Vec<LedgerEntry>, sqlx::Error> {
+    sqlx::query_as!(
+        LedgerEntry,
+        r#"
+        SELECT id, transaction_id, account, asset, bucket, amount, cause, cause_id, created_at
+        FROM ledger_entries
+        WHERE LOWER(account) = LOWER($1) AND ($3::TEXT IS NULL OR asset = $3)
+        ORDER BY id DESC
+        LIMIT $2
+        "#,
+        format!("{:?}", trader_address),
+        limit,
+        asset
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// A trader's balances summed from the ledger. These match the balances on the account.
+pub async fn ledger_balances(db: &PgPool, trader_address: &H160) -> Result<Vec<LedgerBalance>, sqlx::Error> {
+    sqlx::query_as!(
+        LedgerBalance,
+        r#"
+        SELECT asset, bucket, SUM(amount) AS "amount!"
+        FROM ledger_entries
+        WHERE LOWER(account) = LOWER($1)
+        GROUP BY asset, bucket
+        ORDER BY asset, bucket
+        "#,
+        format!("{:?}", trader_address)
+    )
+    .fetch_all(db)
+    .await
+}
@@ -0,0 +1,404 @@
+use crate::models::ledger::LedgerCause;
+use crate::models::liquidation::{LiquidationEvent, LiquidationEventKind, MarginSummary, INSURANCE_FUND_ADDRESS};
+use crate::models::account::Account;
+use crate::models::market::Market;
+use crate::models::position::Position;
+use crate::services::account_service;
+use crate::services::ledger_service;
+use crate::services::position_service;
+use bigdecimal::{BigDecimal, Signed};
+use ethereum_types::H160;
+use sqlx::{PgConnection, PgPool};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+pub fn insurance_fund() -> H160 {
+    H160::from_str(INSURANCE_FUND_ADDRESS).unwrap()
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn record_event(
+    conn: &mut PgConnection,
+    kind: LiquidationEventKind,
+    trader_address: &H160,
+    market_id: Option<i32>,
+    size: Option<&BigDecimal>,
+    price: Option<&BigDecimal>,
+    amount: Option<&BigDecimal>,
+    detail: &str,
+) -> sqlx::Result<i64> {
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO liquidation_events (kind, trader_address, market_id, size, price, amount, detail)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id
+        "#,
+        kind.to_string(),
+        format!("{:?}", trader_address),
+        market_id,
+        size.cloned(),
+        price.cloned(),
+        amount.cloned(),
+        detail,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(row.id)
+}
+
+/// Records a liquidation step that moves no funds, e.g. cancelled orders or an order sent to the book.
+pub async fn log_event(
+    db: &PgPool,
+    kind: LiquidationEventKind,
+    trader_address: &H160,
+    market_id: Option<i32>,
+    size: Option<&BigDecimal>,
+    detail: &str,
+) -> sqlx::Result<i64> {
+    let mut conn = db.acquire().await?;
+    record_event(&mut conn, kind, trader_address, market_id, size, None, None, detail).await
+}
+
+/// Margin of every trader with open positions, per collateral asset, with positions valued at
+/// `mark_prices` (keyed by market id). Positions in markets without a mark price are left out,
+/// and so is the insurance fund. The balance is the available collateral plus what the trader's
+/// resting perpetual orders hold, from `order_margin`: liquidation cancels those orders first.
+/// Other locked funds, spot bids and pending withdrawals, are not collateral.
+pub async fn margin_summaries(
+    db: &PgPool,
+    markets: &[Market],
+    mark_prices: &HashMap<i32, BigDecimal>,
+    order_margin: &HashMap<(H160, String), BigDecimal>,
+) -> sqlx::Result<Vec<MarginSummary>> {
+    let zero = BigDecimal::from(0);
+    let fund = format!("{:?}", insurance_fund());
+    let mut summaries: BTreeMap<(String, String), MarginSummary> = BTreeMap::new();
+    for position in position_service::open_positions(db).await? {
+        let trader_address = position.trader_address.to_lowercase();
+        if trader_address == fund {
+            continue;
+        }
+        let (market, mark_price) = match (
+            markets.iter().find(|market| market.id == position.market_id),
+            mark_prices.get(&position.market_id),
+        ) {
+            (Some(market), Some(mark_price)) => (market, mark_price),
+            _ => continue,
+        };
+        let summary = summaries
+            .entry((trader_address.clone(), market.quote_asset.clone()))
+            .or_insert_with(|| MarginSummary {
+                trader_address,
+                collateral_asset: market.quote_asset.clone(),
+                balance: zero.clone(),
+                unrealized_pnl: zero.clone(),
+                equity: zero.clone(),
+                notional: zero.clone(),
+                maintenance_margin: zero.clone(),
+            });
+        let notional = position.notional(mark_price);
+        summary.unrealized_pnl += position.unrealized_pnl(mark_price);
+        summary.maintenance_margin += market.maintenance_margin_for(&notional);
+        summary.notional += notional;
+    }
+
+    let mut result = Vec::new();
+    for (_, mut summary) in summaries {
+        let trader_address = account_service::decode_address(&summary.trader_address)?;
+        let balance = account_service::get_balance(db, &trader_address, &summary.collateral_asset).await?;
+        let held = order_margin
+            .get(&(trader_address, summary.collateral_asset.clone()))
+            .cloned()
+            .unwrap_or_default();
+        summary.balance = balance.available + held;
+        summary.equity = summary.balance.clone() + summary.unrealized_pnl.clone();
+        result.push(summary);
+    }
+    Ok(result)
+}
+
+/// A trader's margin in the perpetual markets margined in `asset`, from their `account` and their
+/// positions, which stay locked until the transaction ends. Positions are valued at `mark_prices`;
+/// those in markets without a mark price are left out. The balance is the available collateral
+/// only, so the trader's orders should be cancelled first. Also returns the open positions.
+async fn lock_margin_summary<'m>(
+    conn: &mut PgConnection,
+    account: &Account,
+    asset: &str,
+    markets: &'m [Market],
+    mark_prices: &HashMap<i32, BigDecimal>,
+) -> sqlx::Result<(MarginSummary, Vec<(&'m Market, Position)>)> {
+    let zero = BigDecimal::from(0);
+    let mut summary = MarginSummary {
+        trader_address: format!("{:?}", account.trader_address),
+        collateral_asset: asset.to_string(),
+        balance: account.available(asset),
+        unrealized_pnl: zero.clone(),
+        equity: zero.clone(),
+        notional: zero.clone(),
+        maintenance_margin: zero.clone(),
+    };
+    let mut positions = Vec::new();
+    for market in markets.iter().filter(|market| market.is_perpetual() && market.quote_asset == asset) {
+        let mark_price = match mark_prices.get(&market.id) {
+            Some(mark_price) => mark_price,
+            None => continue,
+        };
+        let position = position_service::lock_position(conn, &account.trader_address, market.id).await?;
+        if position.size == zero {
+            continue;
+        }
+        let notional = position.notional(mark_price);
+        summary.unrealized_pnl += position.unrealized_pnl(mark_price);
+        summary.maintenance_margin += market.maintenance_margin_for(&notional);
+        summary.notional += notional;
+        positions.push((market, position));
+    }
+    summary.equity = summary.balance.clone() + summary.unrealized_pnl.clone();
+    Ok((summary, positions))
+}
+
+/// A trader's current margin in `asset`, as `take_over_positions` checks it, for deciding whether
+/// to keep liquidating.
+pub async fn margin_summary(
+    db: &PgPool,
+    trader_address: &H160,
+    asset: &str,
+    markets: &[Market],
+    mark_prices: &HashMap<i32, BigDecimal>,
+) -> sqlx::Result<MarginSummary> {
+    let mut tx = db.begin().await?;
+    let account = account_service::lock_account(&mut tx, trader_address).await?;
+    let (summary, _) = lock_margin_summary(&mut tx, &account, asset, markets, mark_prices).await?;
+    Ok(summary)
+}
+
+/// Finishes liquidating a trader who fell below maintenance margin, once their orders are
+/// cancelled and their liquidation orders have traded what the book would take. In one
+/// transaction, with the accounts and positions locked, it checks their margin again: a trader
+/// whose remaining positions are back above maintenance margin keeps them, and `None` is
+/// returned. Otherwise the insurance fund takes over whatever is still open at the mark price,
+/// which realizes the trader's PnL. A negative balance left over is paid off by the fund;
+/// otherwise what is left, up to the maintenance margin in `summary` (the one that triggered the
+/// liquidation), goes to the fund as a fee. Returns the margin call event id, which the ledger
+/// entries refer to.
+pub async fn take_over_positions(
+    db: &PgPool,
+    summary: &MarginSummary,
+    markets: &[Market],
+    mark_prices: &HashMap<i32, BigDecimal>,
+) -> sqlx::Result<Option<i64>> {
+    let trader = account_service::decode_address(&summary.trader_address)?;
+    let fund = insurance_fund();
+    let asset = &summary.collateral_asset;
+    let mut tx = db.begin().await?;
+
+    // The fund has the lowest address, so locking it first keeps to the address order
+    let fund_before = account_service::lock_account(&mut tx, &fund).await?;
+    let trader_before = account_service::lock_account(&mut tx, &trader).await?;
+    let mut fund_account = fund_before.clone();
+    let mut trader_account = trader_before.clone();
+    let mut fund_positions = HashMap::new();
+    for market in markets.iter().filter(|market| market.is_perpetual() && market.quote_asset == *asset) {
+        fund_positions.insert(market.id, position_service::lock_position(&mut tx, &fund, market.id).await?);
+    }
+    let (current, positions) = lock_margin_summary(&mut tx, &trader_before, asset, markets, mark_prices).await?;
+    if !positions.is_empty() && !current.is_below_maintenance() {
+        return Ok(None);
+    }
+
+    let margin_ratio = current.margin_ratio().unwrap_or_default();
+    let margin_call = record_event(
+        &mut tx,
+        LiquidationEventKind::MarginCall,
+        &trader,
+        None,
+        None,
+        None,
+        Some(&current.equity),
+        &format!(
+            "Equity {} {} below maintenance margin {} on notional {} (margin ratio {}) after the liquidation orders",
+            current.equity, asset, current.maintenance_margin, current.notional, margin_ratio.with_scale(6)
+        ),
+    )
+    .await?;
+
+    for (market, mut trader_position) in positions {
+        let mark_price = &mark_prices[&market.id];
+        let fund_position = match fund_positions.get_mut(&market.id) {
+            Some(fund_position) => fund_position,
+            None => continue,
+        };
+        let size = trader_position.size.clone();
+        trader_account.adjust(asset, &trader_position.apply_fill(&-size.clone(), mark_price));
+        fund_account.adjust(asset, &fund_position.apply_fill(&size, mark_price));
+        position_service::save_position(&mut tx, &trader_position).await?;
+        position_service::save_position(&mut tx, fund_position).await?;
+        record_event(
+            &mut tx,
+            LiquidationEventKind::PositionTakeover,
+            &trader,
+            Some(market.id),
+            Some(&size),
+            Some(mark_price),
+            None,
+            &format!("Insurance fund took over the remaining {} {} at the mark price", size, market.symbol),
+        )
+        .await?;
+    }
+
+    let balance = trader_account.available(asset);
+    if balance < BigDecimal::from(0) {
+        let shortfall = -balance;
+        trader_account.adjust(asset, &shortfall);
+        fund_account.adjust(asset, &-shortfall.clone());
+        record_event(
+            &mut tx,
+            LiquidationEventKind::InsuranceCover,
+            &trader,
+            None,
+            None,
+            None,
+            Some(&shortfall),
+            &format!("Insurance fund covered a shortfall of {} {}", shortfall, asset),
+        )
+        .await?;
+    } else {
+        let fee = balance.min(summary.maintenance_margin.clone());
+        if fee > BigDecimal::from(0) {
+            trader_account.adjust(asset, &-fee.clone());
+            fund_account.adjust(asset, &fee);
+            record_event(
+                &mut tx,
+                LiquidationEventKind::LiquidationFee,
+                &trader,
+                None,
+                None,
+                None,
+                Some(&fee),
+                &format!("Liquidation fee of {} {} paid to the insurance fund", fee, asset),
+            )
+            .await?;
+        }
+    }
+
+    account_service::save_account(&mut tx, &fund_account).await?;
+    account_service::save_account(&mut tx, &trader_account).await?;
+    ledger_service::record_movement(
+        &mut tx,
+        &LedgerCause::Liquidation,
+        &margin_call.to_string(),
+        &[(&trader_before, &trader_account), (&fund_before, &fund_account)],
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(Some(margin_call))
+}
+
+/// Last resort when the insurance fund cannot close a position it took over: closes it against
+/// the traders holding the other side, most profitable at the mark price first. While the fund's
+/// balance is negative they close at a price worse than the mark by up to their unrealized profit,
+/// so the winners of the trade pay off the fund's deficit; otherwise at the mark price. Returns
+/// how much of the fund's position was closed.
+pub async fn auto_deleverage(db: &PgPool, market: &Market, mark_price: &BigDecimal) -> sqlx::Result<BigDecimal> {
+    let zero = BigDecimal::from(0);
+    let fund = insurance_fund();
+    let asset = &market.quote_asset;
+    let mut tx = db.begin().await?;
+
+    let mut fund_account = account_service::lock_account(&mut tx, &fund).await?;
+    let mut fund_position = position_service::lock_position(&mut tx, &fund, market.id).await?;
+    if fund_position.size == zero {
+        return Ok(zero);
+    }
+    let fund_is_long = fund_position.size.is_positive();
+
+    // Traders on the other side who are in profit at the mark, biggest profit first
+    let mut counterparties: Vec<(BigDecimal, String)> = position_service::open_positions(db)
+        .await?
+        .into_iter()
+        .filter(|position| position.market_id == market.id && position.trader_address.to_lowercase() != format!("{:?}", fund))
+        .filter(|position| position.size.is_positive() != fund_is_long)
+        .map(|position| (position.unrealized_pnl(mark_price), position.trader_address))
+        .filter(|(pnl, _)| *pnl > zero)
+        .collect();
+    counterparties.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut deficit = (-fund_account.available(asset)).max(zero.clone());
+    let mut closed = zero.clone();
+    for (_, trader_address) in counterparties {
+        let remaining = fund_position.size.abs();
+        if remaining == zero {
+            break;
+        }
+        let trader = account_service::decode_address(&trader_address)?;
+        let trader_before = account_service::lock_account(&mut tx, &trader).await?;
+        let mut trader_position = position_service::lock_position(&mut tx, &trader, market.id).await?;
+        if trader_position.size == zero || trader_position.size.is_positive() == fund_is_long {
+            continue;
+        }
+        let amount = remaining.min(trader_position.size.abs());
+        let profit = (amount.clone() * (mark_price.clone() - trader_position.entry_price.clone())).abs();
+        let share = deficit.clone().min(profit);
+        let concession = (share.clone() / amount.clone()).with_scale(18);
+        let (price, fund_delta) = if fund_is_long {
+            (mark_price.clone() + concession, -amount.clone())
+        } else {
+            (mark_price.clone() - concession, amount.clone())
+        };
+
+        let fund_before = fund_account.clone();
+        let mut trader_account = trader_before.clone();
+        fund_account.adjust(asset, &fund_position.apply_fill(&fund_delta, &price));
+        trader_account.adjust(asset, &trader_position.apply_fill(&-fund_delta.clone(), &price));
+        position_service::save_position(&mut tx, &trader_position).await?;
+        account_service::save_account(&mut tx, &trader_account).await?;
+
+        let event_id = record_event(
+            &mut tx,
+            LiquidationEventKind::AutoDeleverage,
+            &trader,
+            Some(market.id),
+            Some(&fund_delta),
+            Some(&price),
+            Some(&share),
+            &format!(
+                "Deleveraged {} {} against the insurance fund at {} (mark price {})",
+                amount, market.symbol, price, mark_price
+            ),
+        )
+        .await?;
+        ledger_service::record_movement(
+            &mut tx,
+            &LedgerCause::AutoDeleverage,
+            &event_id.to_string(),
+            &[(&trader_before, &trader_account), (&fund_before, &fund_account)],
+        )
+        .await?;
+        deficit -= share;
+        closed += amount;
+    }
+
+    position_service::save_position(&mut tx, &fund_position).await?;
+    account_service::save_account(&mut tx, &fund_account).await?;
+    tx.commit().await?;
+    Ok(closed)
+}
+
+/// Liquidation events, newest first, optionally only one trader's.
+pub async fn get_events(db: &PgPool, trader_address: Option<&H160>, limit: i64) -> sqlx::Result<Vec<LiquidationEvent>> {
+    sqlx::query_as!(
+        LiquidationEvent,
+        r#"
+        SELECT id, kind, trader_address, market_id, size, price, amount, detail, created_at
+        FROM liquidation_events
+        WHERE $1::TEXT IS NULL OR LOWER(trader_address) = LOWER($1)
+        ORDER BY id DESC
+        LIMIT $2
+        "#,
+        trader_address.map(|address| format!("{:?}", address)),
+        limit,
+    )
+    .fetch_all(db)
+    .await
+}
@@ -0,0 +1,131 @@
+use crate::models::market::{BreakerAction, FundingRules, Market, MarketEvent, MarketKind, MatchingAlgorithm, PriceProtection, TradingPhase, TradingRules};
+use sqlx::PgPool;
+
+pub async fn load_markets(db: &PgPool) -> Result<Vec<Market>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT id, symbol, base_asset, quote_asset,
+               price_tick, amount_step, min_amount, max_amount, min_notional,
+               matching_algorithm, fifo_slice,
+               price_band, breaker_threshold, breaker_window_secs, breaker_action,
+               kind, initial_margin, maintenance_margin, funding_interval_secs, max_funding_rate
+        FROM markets
+        ORDER BY id
+        "#
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| Market {
+            id: row.id,
+            symbol: row.symbol,
+            base_asset: row.base_asset,
+            quote_asset: row.quote_asset,
+            kind: row.kind.parse().unwrap_or(MarketKind::Spot),
+            initial_margin: row.initial_margin,
+            maintenance_margin: row.maintenance_margin,
+            funding: FundingRules {
+                interval_secs: row.funding_interval_secs,
+                max_rate: row.max_funding_rate,
+            },
+            rules: TradingRules {
+                price_tick: row.price_tick,
+                amount_step: row.amount_step,
+                min_amount: row.min_amount,
+                max_amount: row.max_amount,
+                min_notional: row.min_notional,
+            },
+            matching_algorithm: row.matching_algorithm.parse().unwrap_or(MatchingAlgorithm::Fifo),
+            fifo_slice: row.fifo_slice,
+            protection: PriceProtection {
+                price_band: row.price_band,
+                breaker_threshold: row.breaker_threshold,
+                breaker_window_secs: row.breaker_window_secs,
+                breaker_action: row.breaker_action.parse().unwrap_or(BreakerAction::Halt),
+            },
+        })
+        .collect())
+}
+
+/// Inserts a new market and returns it with its assigned id.
+pub async fn create_market_in_db(db: &PgPool, market: &Market) -> Result<Market, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO markets (symbol, base_asset, quote_asset,
+                             price_tick, amount_step, min_amount, max_amount, min_notional,
+                             matching_algorithm, fifo_slice,
+                             price_band, breaker_threshold, breaker_window_secs, breaker_action,
+                             kind, initial_margin, maintenance_margin, funding_interval_secs, max_funding_rate)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19)
+        RETURNING id
+        "#,
+        market.symbol.to_uppercase(),
+        market.base_asset.to_uppercase(),
+        market.quote_asset.to_uppercase(),
+        market.rules.price_tick,
+        market.rules.amount_step,
+        market.rules.min_amount,
+        market.rules.max_amount,
+        market.rules.min_notional,
+        market.matching_algorithm.to_string(),
+        market.fifo_slice,
+        market.protection.price_band,
+        market.protection.breaker_threshold,
+        market.protection.breaker_window_secs,
+        market.protection.breaker_action.to_string(),
+        market.kind.to_string(),
+        market.initial_margin,
+        market.maintenance_margin,
+        market.funding.interval_secs,
+        market.funding.max_rate,
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(Market {
+        id: row.id,
+        symbol: market.symbol.to_uppercase(),
+        base_asset: market.base_asset.to_uppercase(),
+        quote_asset: market.quote_asset.to_uppercase(),
+        kind: market.kind.clone(),
+        initial_margin: market.initial_margin.clone(),
+        maintenance_margin: market.maintenance_margin.clone(),
+        funding: market.funding.clone(),
+        rules: market.rules.clone(),
+        matching_algorithm: market.matching_algorithm.clone(),
+        fifo_slice: market.fifo_slice.clone(),
+        protection: market.protection.clone(),
+    })
+}
+
+pub async fn record_market_event(db: &PgPool, market_id: i32, phase: &TradingPhase, reason: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO market_events (market_id, phase, reason) VALUES ($1, $2, $3)",
+        market_id,
+        phase.to_string(),
+        reason,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Most recent phase changes first.
+pub async fn get_market_events(db: &PgPool, market_id: i32, limit: i64) -> Result<Vec<MarketEvent>, sqlx::Error> {
+    sqlx::query_as!(
+        MarketEvent,
+        r#"
+        SELECT id, market_id, phase, reason, created_at
+        FROM market_events
+        WHERE market_id = $1
+        ORDER BY id DESC
+        LIMIT $2
+        "#,
+        market_id,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
@@ -1,2 +1,9 @@
 pub mod account_service;
+pub mod asset_service;
 pub mod order_service;
+pub mod market_service;
+pub mod ledger_service;
+pub mod transfer_service;
+pub mod position_service;
+pub mod funding_service;
+pub mod liquidation_service;
@@ -1,308 +1,1289 @@
 use serde::{Serialize, Deserialize};
-use bigdecimal::{ ToPrimitive, FromPrimitive, Signed}; // Import Signed for is_negative
-use ethereum_types::{H160, H256, U256};
+use ethereum_types::{H160, H256};
 use crate::models::types::{Address, Hash, Fill}; 
-use crate::models::order::{Order, OrderSide, L2OrderBook , L2OrderBookGetResponse}; 
-use sqlx::{query, PgPool}; 
+use crate::models::order::{Order, OrderSide, OrderType, TimeInForce, PostOnlyAction, SelfTradePrevention, L2OrderBook , L2OrderBookGetResponse}; 
+use sqlx::{query, PgConnection, PgPool}; 
 use serde_json::json;
-use bigdecimal::{BigDecimal, num_bigint::ToBigInt};
+use bigdecimal::BigDecimal;
 use crate::models::order::OrderEntry;
+use crate::models::market::{BreakerAction, Market, MatchingAlgorithm, TradingPhase};
+use crate::services::market_service;
 use crate::models::types::EIP712DomainSeparator;
 use crate::services::account_service;
+use crate::services::ledger_service;
+use crate::services::position_service;
+use crate::services::liquidation_service;
+use crate::models::account::Account;
+use crate::models::ledger::LedgerCause;
+use std::collections::HashMap;
 use std::str::FromStr;
 use sqlx::Error;
 use serde_json::Value;
+use chrono::{DateTime, Utc};
 
 
 
 
-/// Converts U256 to BigDecimal
-fn u256_to_bigdecimal(value: U256) -> BigDecimal {
-    let value_str = value.to_string();
-    BigDecimal::parse_bytes(value_str.as_bytes(), 10)
-        .expect("Failed to convert U256 to BigDecimal")
+/// The worst price an incoming order may trade at. Limit orders are bounded by their own price;
+/// market orders by `worst_price`, or by `max_slippage` away from the best opposite price on
+/// arrival. Either way the order never trades outside the market's price band. `None` means the
+/// order may sweep the whole opposite side.
+fn price_limit(order: &Order, market: &Market, order_book: &L2OrderBook) -> Option<BigDecimal> {
+    let own_limit = order_price_limit(order, order_book);
+    let band_limit = market
+        .price_band(order_book.last_trade_price())
+        .map(|(low, high)| if order.side == OrderSide::Bid { high } else { low });
+    match (own_limit, band_limit) {
+        (Some(own), Some(band)) if order.side == OrderSide::Bid => Some(own.min(band)),
+        (Some(own), Some(band)) => Some(own.max(band)),
+        (own, band) => own.or(band),
+    }
+}
+
+fn order_price_limit(order: &Order, order_book: &L2OrderBook) -> Option<BigDecimal> {
+    if order.order_type == OrderType::Limit {
+        return Some(order.price.clone());
+    }
+    if let Some(worst_price) = &order.worst_price {
+        return Some(worst_price.clone());
+    }
+    let max_slippage = order.max_slippage.clone()?;
+    let best_price = if order.side == OrderSide::Bid {
+        order_book.best_ask()?.clone()
+    } else {
+        order_book.best_bid()?.clone()
+    };
+    if order.side == OrderSide::Bid {
+        Some(best_price.clone() + best_price * max_slippage)
+    } else {
+        Some(best_price.clone() - best_price * max_slippage)
+    }
+}
+
+/// Walks the opposite side up to `limit` and returns how much of `order` could fill and what it
+/// would cost in quote currency, stopping where `match_order` would. GTD orders past their expiry
+/// are passed over. So are the trader's own orders if self-trade prevention cancels only them;
+/// any other mode takes amount off the incoming order, so the walk ends at the first own order, or
+/// at the start of its level in a pro-rata market. It also ends at the first level that would trip
+/// the circuit breaker against `reference`.
+fn estimate_fill_cost(
+    market: &Market,
+    order_book: &L2OrderBook,
+    order: &Order,
+    limit: &Option<BigDecimal>,
+    reference: Option<&BigDecimal>,
+) -> (BigDecimal, BigDecimal) {
+    let opposite = if order.side == OrderSide::Bid { OrderSide::Ask } else { OrderSide::Bid };
+    let now = Utc::now();
+    let stp_mode = order.self_trade_prevention.clone().unwrap_or(SelfTradePrevention::CancelNewest);
+    let entries: Vec<OrderEntry> = order_book
+        .entries(&opposite)
+        .into_iter()
+        .filter(|entry| entry.expires_at.is_none_or(|expires_at| expires_at > now))
+        .collect();
+    let own_levels: Vec<BigDecimal> = entries
+        .iter()
+        .filter(|entry| entry.trader_address == order.trader_address)
+        .map(|entry| entry.price.clone())
+        .collect();
+    let mut remaining = order.amount.clone();
+    let mut cost = BigDecimal::from(0);
+
+    for entry in entries {
+        if remaining <= BigDecimal::from(0) {
+            break;
+        }
+        let crosses = match limit {
+            Some(limit) if order.side == OrderSide::Bid => entry.price <= *limit,
+            Some(limit) => entry.price >= *limit,
+            None => true,
+        };
+        if !crosses || reference.is_some_and(|reference| market.trips_breaker(&entry.price, reference)) {
+            break;
+        }
+        if stp_mode != SelfTradePrevention::CancelOldest
+            && market.matching_algorithm == MatchingAlgorithm::ProRata
+            && own_levels.contains(&entry.price)
+        {
+            break;
+        }
+        if entry.trader_address == order.trader_address {
+            if stp_mode == SelfTradePrevention::CancelOldest {
+                continue;
+            }
+            break;
+        }
+        let take = remaining.clone().min(entry.total_amount());
+        cost += take.clone() * entry.price;
+        remaining -= take;
+    }
+
+    (order.amount.clone() - remaining, cost)
+}
+
+/// A self-trade that was prevented instead of being filled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SelfTradePrevented {
+    pub mode: SelfTradePrevention,
+    pub resting_hash: String,
+    pub incoming_cancelled: BigDecimal, // Amount taken off the incoming order
+    pub resting_cancelled: BigDecimal,  // Amount taken off the resting order
+}
+
+/// Fills produced by `match_order`, the self-trades it prevented along the way, and whether it
+/// stopped because the circuit breaker tripped.
+#[derive(Debug, Clone, Default)]
+pub struct MatchResult {
+    pub fills: Vec<Fill>,
+    pub self_trades: Vec<SelfTradePrevented>,
+    pub circuit_breaker: Option<TradingPhase>, // Phase the market was switched to if the breaker tripped
+    pub spent: BigDecimal, // Funds the incoming order's fills took out of its hold, refunds included
+    pub settlement_error: Option<String>, // Set if a fill could not be settled; matching stopped there
+}
+
+/// The asset and amount a resting order reserves: quote currency at its limit price for bids,
+/// the base asset itself for asks. Perpetual orders on either side reserve initial margin on
+/// their notional, in the quote asset.
+fn hold_for(market: &Market, side: &OrderSide, amount: &BigDecimal, price: &BigDecimal) -> (String, BigDecimal) {
+    if market.is_perpetual() {
+        return (market.quote_asset.clone(), market.margin_for(&(amount.clone() * price.clone())));
+    }
+    match side {
+        OrderSide::Bid => (market.quote_asset.clone(), amount.clone() * price.clone()),
+        OrderSide::Ask => (market.base_asset.clone(), amount.clone()),
+    }
+}
+
+/// Moves funds from the trader's available to their locked balance for order `order_id`.
+async fn lock_funds(db: &PgPool, trader: &Address, asset: &str, amount: &BigDecimal, order_id: i64) -> Result<(), String> {
+    let mut tx = db.begin().await.map_err(|e| format!("Failed to lock funds: {}", e))?;
+    let before = account_service::lock_account(&mut tx, trader)
+        .await
+        .map_err(|_| format!("Account not found for trader address: {:?}", trader))?;
+    let mut after = before.clone();
+    after.lock(asset, amount)?;
+    account_service::save_account(&mut tx, &after)
+        .await
+        .map_err(|e| format!("Failed to lock funds: {}", e))?;
+    ledger_service::record_movement(&mut tx, &LedgerCause::Hold, &order_id.to_string(), &[(&before, &after)])
+        .await
+        .map_err(|e| format!("Failed to lock funds: {}", e))?;
+    tx.commit().await.map_err(|e| format!("Failed to lock funds: {}", e))
+}
+
+/// Moves funds back from the trader's locked to their available balance for order `order_id`.
+async fn release_funds(db: &PgPool, trader: &Address, asset: &str, amount: &BigDecimal, order_id: i64) {
+    if *amount <= BigDecimal::from(0) {
+        return;
+    }
+    let released: sqlx::Result<()> = async {
+        let mut tx = db.begin().await?;
+        let before = account_service::lock_account(&mut tx, trader).await?;
+        let mut after = before.clone();
+        after.release(asset, amount);
+        account_service::save_account(&mut tx, &after).await?;
+        ledger_service::record_movement(&mut tx, &LedgerCause::Hold, &order_id.to_string(), &[(&before, &after)]).await?;
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = released {
+        eprintln!("Failed to release funds for {:?}: {}", trader, e);
+    }
+}
+
+/// Releases whatever a resting order still holds, e.g. once it is cancelled or expires.
+pub async fn release_order_hold(db: &PgPool, market: &Market, side: &OrderSide, entry: &OrderEntry) {
+    let (asset, amount) = hold_for(market, side, &entry.total_amount(), &entry.price);
+    release_funds(db, &entry.trader_address, &asset, &amount, entry.order_id).await;
+}
+
+/// Takes a resting order out of the book and releases its hold. The caller persists the book.
+pub async fn cancel_resting_order(
+    market: &Market,
+    order_book: &mut L2OrderBook,
+    eip712_hash: &str,
+    db: &PgPool,
+) -> Option<OrderEntry> {
+    let side = order_book.side_of(eip712_hash)?;
+    let entry = order_book.remove(eip712_hash)?;
+    release_order_hold(db, market, &side, &entry).await;
+    Some(entry)
+}
+
+/// Records `fill` and moves `amount` of the market's base asset from seller to buyer and
+/// `amount * price` of its quote asset from buyer to seller, out of their locked funds. The buyer
+/// reserved `amount * buyer_reserved_price`; anything above the trade price goes back to their available
+/// balance. The buyer pays exactly what the seller receives, so the quote asset is conserved across
+/// the two accounts. It all happens in one transaction with both account rows locked, so either the whole
+/// fill is settled or none of it is. Perpetual fills settle through `settle_perpetual_fill`.
+#[allow(clippy::too_many_arguments)]
+async fn settle_fill(
+    db: &PgPool,
+    market: &Market,
+    fill: &Fill,
+    buyer: &Address,
+    seller: &Address,
+    amount: &BigDecimal,
+    price: &BigDecimal,
+    buyer_reserved_price: &BigDecimal,
+    seller_reserved_price: &BigDecimal,
+) -> Result<(), String> {
+    if market.is_perpetual() {
+        return settle_perpetual_fill(db, market, fill, buyer, seller, amount, price, buyer_reserved_price, seller_reserved_price).await;
+    }
+    let failed = |e: sqlx::Error| e.to_string();
+    let mut tx = db.begin().await.map_err(failed)?;
+
+    let fill_id = insert_fill(&mut tx, fill).await.map_err(failed)?;
+
+    // Lock the two rows in address order so concurrent settlements cannot deadlock
+    let (buyer_before, seller_before) = if buyer <= seller {
+        let buyer_account = account_service::lock_account(&mut tx, buyer).await.map_err(failed)?;
+        (buyer_account, account_service::lock_account(&mut tx, seller).await.map_err(failed)?)
+    } else {
+        let seller_account = account_service::lock_account(&mut tx, seller).await.map_err(failed)?;
+        (account_service::lock_account(&mut tx, buyer).await.map_err(failed)?, seller_account)
+    };
+    let mut buyer_account = buyer_before.clone();
+    let mut seller_account = seller_before.clone();
+
+    transfer_spot_fill(market, &mut buyer_account, &mut seller_account, amount, price, buyer_reserved_price)?;
+
+    account_service::save_account(&mut tx, &buyer_account).await.map_err(failed)?;
+    account_service::save_account(&mut tx, &seller_account).await.map_err(failed)?;
+    ledger_service::record_movement(
+        &mut tx,
+        &LedgerCause::Fill,
+        &fill_id.to_string(),
+        &[(&buyer_before, &buyer_account), (&seller_before, &seller_account)],
+    )
+    .await
+    .map_err(failed)?;
+    tx.commit().await.map_err(failed)
+}
+
+/// Moves the funds of a spot fill between the two accounts: `amount * price` of the quote asset
+/// from the buyer's locked balance to the seller, and `amount` of the base asset from the
+/// seller's locked balance to the buyer. What the buyer reserved above `price` is refunded to
+/// their available balance. Fails if either side has less locked than the fill takes.
+fn transfer_spot_fill(
+    market: &Market,
+    buyer_account: &mut Account,
+    seller_account: &mut Account,
+    amount: &BigDecimal,
+    price: &BigDecimal,
+    buyer_reserved_price: &BigDecimal,
+) -> Result<(), String> {
+    let quote_amount = amount.clone() * price.clone();
+
+    // Update the buyer's balances
+    buyer_account.spend_locked(&market.quote_asset, &quote_amount)?;
+    buyer_account.release(&market.quote_asset, &(amount.clone() * (buyer_reserved_price.clone() - price.clone())));
+    buyer_account.credit(&market.base_asset, amount)?;
+
+    // Update the seller's balances
+    seller_account.spend_locked(&market.base_asset, amount)?;
+    seller_account.credit(&market.quote_asset, &quote_amount)
 }
 
-fn bigdecimal_to_u256(bd: &BigDecimal) -> U256 {
-    let big_int = bd.to_bigint().unwrap();
-    let bytes = big_int.to_bytes_le().1;
-    let mut array = [0u8; 32];
-    array[..bytes.len()].copy_from_slice(&bytes);
-    U256::from_little_endian(&array)
+/// Records a perpetual `fill`: the buyer's position grows by `amount` and the seller's shrinks by
+/// it, both at `price`, and no base asset changes hands. Each side gets back the margin its order
+/// reserved for the filled amount, since open positions are margined against the available
+/// balance instead, and any PnL the fill realized is paid to or taken from its available quote
+/// balance. Like `settle_fill`, it is one transaction with both accounts and positions locked.
+#[allow(clippy::too_many_arguments)]
+async fn settle_perpetual_fill(
+    db: &PgPool,
+    market: &Market,
+    fill: &Fill,
+    buyer: &Address,
+    seller: &Address,
+    amount: &BigDecimal,
+    price: &BigDecimal,
+    buyer_reserved_price: &BigDecimal,
+    seller_reserved_price: &BigDecimal,
+) -> Result<(), String> {
+    let failed = |e: sqlx::Error| e.to_string();
+    let mut tx = db.begin().await.map_err(failed)?;
+
+    let fill_id = insert_fill(&mut tx, fill).await.map_err(failed)?;
+
+    // Lock the accounts, then the positions, in address order so concurrent settlements cannot deadlock
+    let (buyer_before, seller_before, mut buyer_position, mut seller_position) = if buyer <= seller {
+        let buyer_account = account_service::lock_account(&mut tx, buyer).await.map_err(failed)?;
+        let seller_account = account_service::lock_account(&mut tx, seller).await.map_err(failed)?;
+        let buyer_position = position_service::lock_position(&mut tx, buyer, market.id).await.map_err(failed)?;
+        (buyer_account, seller_account, buyer_position, position_service::lock_position(&mut tx, seller, market.id).await.map_err(failed)?)
+    } else {
+        let seller_account = account_service::lock_account(&mut tx, seller).await.map_err(failed)?;
+        let buyer_account = account_service::lock_account(&mut tx, buyer).await.map_err(failed)?;
+        let seller_position = position_service::lock_position(&mut tx, seller, market.id).await.map_err(failed)?;
+        (buyer_account, seller_account, position_service::lock_position(&mut tx, buyer, market.id).await.map_err(failed)?, seller_position)
+    };
+    let mut buyer_account = buyer_before.clone();
+    let mut seller_account = seller_before.clone();
+
+    buyer_account.release(&market.quote_asset, &hold_for(market, &OrderSide::Bid, amount, buyer_reserved_price).1);
+    let buyer_pnl = buyer_position.apply_fill(amount, price);
+    buyer_account.adjust(&market.quote_asset, &buyer_pnl);
+
+    seller_account.release(&market.quote_asset, &hold_for(market, &OrderSide::Ask, amount, seller_reserved_price).1);
+    let seller_pnl = seller_position.apply_fill(&-amount.clone(), price);
+    seller_account.adjust(&market.quote_asset, &seller_pnl);
+
+    position_service::save_position(&mut tx, &buyer_position).await.map_err(failed)?;
+    position_service::save_position(&mut tx, &seller_position).await.map_err(failed)?;
+    account_service::save_account(&mut tx, &buyer_account).await.map_err(failed)?;
+    account_service::save_account(&mut tx, &seller_account).await.map_err(failed)?;
+    ledger_service::record_movement(
+        &mut tx,
+        &LedgerCause::PerpetualFill,
+        &fill_id.to_string(),
+        &[(&buyer_before, &buyer_account), (&seller_before, &seller_account)],
+    )
+    .await
+    .map_err(failed)?;
+    tx.commit().await.map_err(failed)
 }
+
 pub async fn match_order(
     order: &Order,
+    order_hash: &str,
+    market: &Market,
     order_book: &mut L2OrderBook,
-    domain: &EIP712DomainSeparator,
+    hold: &BigDecimal,
     db: &PgPool,
-) -> Vec<Fill> {
+) -> MatchResult {
     let mut fills: Vec<Fill> = Vec::new();
+    let mut self_trades: Vec<SelfTradePrevented> = Vec::new();
+    let mut circuit_breaker = None;
+    let mut spent = BigDecimal::from(0);
+    let mut settlement_error = None;
     let mut remaining_amount = order.amount.clone();
+    let stp_mode = order.self_trade_prevention.clone().unwrap_or(SelfTradePrevention::CancelNewest);
 
-    // Identify the opposite and same sides of the order book
-    let (opposite_side, same_side) = if order.side == OrderSide::Bid {
-        (&mut order_book.asks, &mut order_book.bids)
-    } else {
-        (&mut order_book.bids, &mut order_book.asks)
-    };
+    // Identify the opposite side of the order book
+    let opposite = if order.side == OrderSide::Bid { OrderSide::Ask } else { OrderSide::Bid };
+    let limit = price_limit(order, market, order_book);
+
+    'matching: while remaining_amount > BigDecimal::from(0) {
+        // Best resting order on the opposite side, in price-time priority
+        let existing_order = match order_book.front(&opposite) {
+            Some(entry) => entry,
+            None => break,
+        };
 
-    let mut i = 0;
-    while i < opposite_side.len() && remaining_amount > BigDecimal::from(0) {
-        let existing_order = &mut opposite_side[i];
+        // GTD orders past their expiry may not have been swept yet; they must not trade
+        if existing_order.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+            cancel_resting_order(market, order_book, &existing_order.eip712_hash, db).await;
+            continue;
+        }
 
-        // Check if the price conditions match
-        let is_opposite_side = (order.side == OrderSide::Bid && existing_order.price <= order.price)
-            || (order.side == OrderSide::Ask && existing_order.price >= order.price);
+        // Check if the price conditions match; levels are sorted so nothing further can cross
+        let is_opposite_side = match &limit {
+            Some(limit) => (order.side == OrderSide::Bid && existing_order.price <= *limit)
+                || (order.side == OrderSide::Ask && existing_order.price >= *limit),
+            None => true,
+        };
+        if !is_opposite_side {
+            break;
+        }
 
-        if is_opposite_side {
-            if existing_order.trader_address == order.trader_address {
-                // Prevent self-matching
-                println!("Self-matching detected: Order from the same trader. No fill created.");
-                return Vec::new();
+        // FIFO markets trade with the front order only, one at a time; pro-rata markets share
+        // the fill across every order at the level
+        let level_price = existing_order.price.clone();
+        let makers = match market.matching_algorithm {
+            MatchingAlgorithm::Fifo => vec![existing_order],
+            MatchingAlgorithm::ProRata => order_book.level_entries(&opposite, &level_price),
+        };
+        let now = Utc::now();
+        let expired = makers
+            .iter()
+            .find(|maker| maker.expires_at.is_some_and(|expires_at| expires_at <= now));
+        if let Some(expired) = expired {
+            cancel_resting_order(market, order_book, &expired.eip712_hash, db).await;
+            continue;
+        }
+
+        if let Some(existing_order) = makers.iter().find(|maker| maker.trader_address == order.trader_address) {
+            // Prevent self-matching: cancel one or both sides according to the STP mode, no fill
+            let resting_amount = existing_order.total_amount();
+            let (incoming_cancelled, resting_cancelled) = match stp_mode {
+                SelfTradePrevention::CancelNewest => (remaining_amount.clone(), BigDecimal::from(0)),
+                SelfTradePrevention::CancelOldest => (BigDecimal::from(0), resting_amount.clone()),
+                SelfTradePrevention::CancelBoth => (remaining_amount.clone(), resting_amount.clone()),
+                SelfTradePrevention::DecrementAndCancel => {
+                    let decrement = remaining_amount.clone().min(resting_amount.clone());
+                    (decrement.clone(), decrement)
+                }
+            };
+            eprintln!(
+                "Self-trade prevented ({}): incoming -{}, resting {} -{}",
+                stp_mode, incoming_cancelled, existing_order.eip712_hash, resting_cancelled
+            );
+
+            remaining_amount -= incoming_cancelled.clone();
+            if resting_cancelled > BigDecimal::from(0) {
+                order_book.set_amount(&existing_order.eip712_hash, resting_amount - resting_cancelled.clone());
+                let (asset, held) = hold_for(market, &opposite, &resting_cancelled, &existing_order.price);
+                release_funds(db, &existing_order.trader_address, &asset, &held, existing_order.order_id).await;
+            }
+            self_trades.push(SelfTradePrevented {
+                mode: stp_mode.clone(),
+                resting_hash: existing_order.eip712_hash.clone(),
+                incoming_cancelled,
+                resting_cancelled,
+            });
+            continue;
+        }
+
+        // Trading this far from where the market stood a window ago trips the circuit breaker:
+        // matching stops and the market halts or goes into a volatility auction
+        if let Some(reference) = order_book.reference_price_since(market.breaker_window_start(now)) {
+            if market.trips_breaker(&level_price, &reference) {
+                let phase = match market.protection.breaker_action {
+                    BreakerAction::Halt => TradingPhase::Halted,
+                    BreakerAction::Auction => TradingPhase::Auction,
+                };
+                let reason = format!(
+                    "Circuit breaker: trade at {} against reference price {}",
+                    level_price, reference
+                );
+                set_phase(market, order_book, phase.clone(), &reason, db).await;
+                circuit_breaker = Some(phase);
+                break;
             }
+        }
 
-            // Determine the fill amount as the minimum of the remaining and existing order amounts
-            let fill_amount = remaining_amount.clone().min(existing_order.amount.clone());
+        // Every fill executes at the maker's price. A limit order reserved funds at its own price,
+        // so a bid's price improvement goes back to its available balance; market orders reserved
+        // at the book's prices and have nothing to refund. Liquidation orders reserved nothing.
+        let reserved_price = match order.order_type {
+            _ if order.liquidation => BigDecimal::from(0),
+            OrderType::Limit | OrderType::StopLimit => order.price.clone(),
+            OrderType::Market | OrderType::StopMarket => level_price.clone(),
+        };
 
-            // Update remaining amount of incoming order and the matched order
-            remaining_amount -= fill_amount.clone();
-            existing_order.amount -= fill_amount.clone();
+        // A market order's hold is only an estimate of its cost; it never fills more than the
+        // hold covers, and whatever is left once it runs out is cancelled
+        let unit_hold = hold_for(market, &order.side, &BigDecimal::from(1), &reserved_price).1;
+        let level_amount = if unit_hold > BigDecimal::from(0) {
+            let affordable = round_down((hold.clone() - spent.clone()) / unit_hold, &market.rules.amount_step);
+            remaining_amount.clone().min(affordable)
+        } else {
+            remaining_amount.clone()
+        };
+        if level_amount <= BigDecimal::from(0) {
+            break;
+        }
+
+        let allocations = allocate_level(market, &makers, &level_amount);
+        for (existing_order, fill_amount) in makers.iter().zip(allocations) {
+            if fill_amount <= BigDecimal::from(0) {
+                continue;
+            }
 
             // Create the Fill entry
             let fill = Fill {
-                maker_hash: existing_order.eip712_hash(domain),
-                taker_hash: order.eip712_hash(domain),
-                fill_amount: bigdecimal_to_u256(&fill_amount),
-                price: bigdecimal_to_u256(&existing_order.price),
+                market_id: market.id,
+                maker_hash: existing_order.hash(),
+                taker_hash: H256::from_str(order_hash).unwrap_or_default(),
+                maker_order_id: existing_order.order_id,
+                taker_order_id: order.order_id,
+                fill_amount: fill_amount.clone(),
+                price: existing_order.price.clone(),
             };
 
-            // Insert the fill into the database
-            if let Err(e) = insert_fill(db, &fill).await {
-                eprintln!("Failed to insert fill into database: {}", e);
+            // Record the fill and move both sides' balances; the book only changes once that is done
+            let settled = if order.side == OrderSide::Bid {
+                settle_fill(db, market, &fill, &order.trader_address, &existing_order.trader_address, &fill_amount, &existing_order.price, &reserved_price, &existing_order.price).await
+            } else {
+                settle_fill(db, market, &fill, &existing_order.trader_address, &order.trader_address, &fill_amount, &existing_order.price, &existing_order.price, &reserved_price).await
+            };
+            if let Err(e) = settled {
+                eprintln!("Failed to settle fill against {}: {}", existing_order.eip712_hash, e);
+                settlement_error = Some(format!("Failed to settle fill: {}", e));
+                break 'matching;
             }
+            spent += hold_for(market, &order.side, &fill_amount, &reserved_price).1;
 
+            // Update remaining amount of incoming order and the matched order;
+            // fully filled resting orders are dropped from the book
+            remaining_amount -= fill_amount.clone();
+            order_book.fill(&existing_order.eip712_hash, fill_amount);
             fills.push(fill);
+            order_book.record_trade(existing_order.price.clone());
+        }
+    }
 
-            // Balance updates for both sides
-            if order.side == OrderSide::Bid {
-                // Update the bidder's USD balance
-                let account_result = account_service::get_account_from_db(&order.trader_address).await;
-                if let Ok(mut account) = account_result {
-                    let new_usd_balance = account.usd_balance - (fill_amount.clone() * order.price.clone());
-                    let new_ddx_balance = account.ddx_balance + fill_amount.clone() ;
-                    account.usd_balance = new_usd_balance;
-                    account.ddx_balance = new_ddx_balance;
-                    if let Err(e) = account_service::update_account_in_db( &account).await {
-                        eprintln!("Failed to update bidder's USD balance: {}", e);
-                    }
-                }
-
-                // Update the seller's DDX balance (opposite side)
-                let account_result = account_service::get_account_from_db(&existing_order.trader_address).await;
-                if let Ok(mut account) = account_result {
-                    let new_ddx_balance = account.ddx_balance - fill_amount.clone();
-                    let new_usd_balance = account.usd_balance + (fill_amount * order.price.clone());
-                    account.ddx_balance = new_ddx_balance;
-                    account.usd_balance = new_usd_balance;
-                    if let Err(e) = account_service::update_account_in_db( &account).await {
-                        eprintln!("Failed to update seller's DDX balance: {}", e);
-                    }
-                }
-            } else if order.side == OrderSide::Ask {
-                // Update the asker's DDX balance
-                let account_result = account_service::get_account_from_db(&order.trader_address).await;
-                if let Ok(mut account) = account_result {
-                    let new_ddx_balance = account.ddx_balance - fill_amount.clone();
-                    let new_usd_balance = account.usd_balance + (fill_amount.clone() * existing_order.price.clone());
-                    account.ddx_balance = new_ddx_balance;
-                    account.usd_balance= new_usd_balance;
-                    if let Err(e) = account_service::update_account_in_db(&account).await {
-                        eprintln!("Failed to update asker's DDX balance: {}", e);
-                    }
-                }
+    // Update the incoming order's own entry: keep the remainder resting or drop it if fully
+    // matched. After a failed settlement the remainder is cancelled rather than left crossing the book.
+    if settlement_error.is_some() {
+        order_book.remove(order_hash);
+    } else {
+        order_book.set_amount(order_hash, remaining_amount);
+    }
 
-                // Update the buyer's USD balance (opposite side)
-                let account_result = account_service::get_account_from_db(&existing_order.trader_address).await;
-                if let Ok(mut account) = account_result {
-                    let new_usd_balance = account.usd_balance - (fill_amount.clone() * existing_order.price.clone());
-                    let new_ddx_balance = account.ddx_balance + fill_amount;
-                    account.ddx_balance = new_ddx_balance;
-                    account.usd_balance = new_usd_balance;
-                    if let Err(e) = account_service::update_account_in_db(&account).await {
-                        eprintln!("Failed to update buyer's USD balance: {}", e);
-                    }
-                }
-            }
+    MatchResult { fills, self_trades, circuit_breaker, spent, settlement_error }
+}
 
-            // Remove fully filled existing orders from opposite side
-            if existing_order.amount <= BigDecimal::from(0) {
-                opposite_side.remove(i);
-            } else {
-                i += 1; // Move to the next order if the current one isn't fully filled
-            }
-        } else {
-            i += 1; // Move to the next order if price conditions aren't met
+/// Splits `amount` across the resting orders at one price level, oldest first. FIFO gives it all
+/// out in time priority. Pro-rata gives the market's `fifo_slice` of it out in time priority, then
+/// shares the rest in proportion to visible size, rounded down to the lot size; whatever rounding
+/// leaves over goes to the oldest orders.
+fn allocate_level(market: &Market, makers: &[OrderEntry], amount: &BigDecimal) -> Vec<BigDecimal> {
+    let zero = BigDecimal::from(0);
+    let mut sizes: Vec<BigDecimal> = makers.iter().map(|maker| maker.amount.clone()).collect();
+    let mut allocations = vec![zero.clone(); makers.len()];
+
+    let priority_amount = match market.matching_algorithm {
+        MatchingAlgorithm::Fifo => amount.clone(),
+        MatchingAlgorithm::ProRata => round_down(amount.clone() * market.fifo_slice.clone(), &market.rules.amount_step),
+    };
+    let mut remaining = amount.clone() - priority_amount.clone()
+        + allocate_in_time_priority(&mut allocations, &mut sizes, priority_amount);
+
+    let total = sizes.iter().fold(zero.clone(), |total, size| total + size.clone());
+    if market.matching_algorithm == MatchingAlgorithm::ProRata && total > zero && remaining > zero {
+        let pool = remaining.clone().min(total.clone());
+        for (allocation, size) in allocations.iter_mut().zip(sizes.iter_mut()) {
+            let share = round_down(pool.clone() * size.clone() / total.clone(), &market.rules.amount_step)
+                .min(size.clone());
+            *allocation += share.clone();
+            *size -= share.clone();
+            remaining -= share;
         }
     }
 
-    // If the incoming order is only partially matched, update the same side
-    if remaining_amount > BigDecimal::from(0) {
-        if let Some(position) = same_side.iter_mut().position(|x| x.trader_address == order.trader_address && x.price == order.price) {
-            same_side[position].amount = remaining_amount.clone();
-        }
-    } else {
-        // If fully matched, remove the incoming order from the same side
-        if let Some(position) = same_side.iter().position(|x| x.trader_address == order.trader_address && x.price == order.price) {
-            same_side.remove(position);
+    allocate_in_time_priority(&mut allocations, &mut sizes, remaining);
+    allocations
+}
+
+/// Fills orders oldest first until `amount` runs out; returns what could not be allocated.
+fn allocate_in_time_priority(allocations: &mut [BigDecimal], sizes: &mut [BigDecimal], mut amount: BigDecimal) -> BigDecimal {
+    for (allocation, size) in allocations.iter_mut().zip(sizes.iter_mut()) {
+        if amount <= BigDecimal::from(0) {
+            break;
         }
+        let share = amount.clone().min(size.clone());
+        *allocation += share.clone();
+        *size -= share.clone();
+        amount -= share;
     }
+    amount
+}
 
-    fills
+fn round_down(value: BigDecimal, step: &BigDecimal) -> BigDecimal {
+    if *step > BigDecimal::from(0) {
+        let (_, scale) = step.as_bigint_and_exponent();
+        (value.clone() - value % step.clone()).with_scale(scale)
+    } else {
+        value
+    }
 }
 
 
 
 
-fn validate_order(order: &Order) -> Result<(), String> {
+fn validate_order(order: &Order, market: &Market, reference_price: Option<&BigDecimal>) -> Result<(), String> {
     if order.amount <= BigDecimal::from(0) {
         return Err("Amount must be greater than zero".to_string());
     }
-    if order.price <= BigDecimal::from(0) {
-        return Err("Price must be greater than zero".to_string());
+    match order.order_type {
+        OrderType::Limit | OrderType::StopLimit => {
+            if order.price <= BigDecimal::from(0) {
+                return Err("Price must be greater than zero".to_string());
+            }
+        }
+        OrderType::Market | OrderType::StopMarket => {
+            if let Some(worst_price) = &order.worst_price {
+                if *worst_price <= BigDecimal::from(0) {
+                    return Err("Worst price must be greater than zero".to_string());
+                }
+            }
+            if let Some(max_slippage) = &order.max_slippage {
+                if *max_slippage < BigDecimal::from(0) || *max_slippage >= BigDecimal::from(1) {
+                    return Err("Max slippage must be between 0 and 1".to_string());
+                }
+            }
+        }
     }
     if order.side != OrderSide::Bid && order.side != OrderSide::Ask {
         return Err("Invalid order side".to_string());
     }
+    match (&order.time_in_force, &order.expires_at) {
+        (TimeInForce::GTD, None) => return Err("GTD orders require an expiry time".to_string()),
+        (TimeInForce::GTD, Some(expires_at)) if *expires_at <= Utc::now() => {
+            return Err("Expiry time must be in the future".to_string());
+        }
+        (TimeInForce::GTD, Some(_)) if matches!(order.order_type, OrderType::Market | OrderType::StopMarket) => {
+            return Err("Market orders cannot be good-till-date".to_string());
+        }
+        (TimeInForce::GTD, Some(_)) => {}
+        (_, Some(_)) => return Err("Expiry time is only valid for GTD orders".to_string()),
+        (_, None) => {}
+    }
+    match (&order.order_type, &order.trigger_price) {
+        (OrderType::StopMarket | OrderType::StopLimit, None) => {
+            return Err("Stop orders require a trigger price".to_string());
+        }
+        (OrderType::StopMarket | OrderType::StopLimit, Some(trigger_price)) if *trigger_price <= BigDecimal::from(0) => {
+            return Err("Trigger price must be greater than zero".to_string());
+        }
+        (OrderType::Limit | OrderType::Market, Some(_)) => {
+            return Err("Trigger price is only valid for stop orders".to_string());
+        }
+        _ => {}
+    }
+    if let Some(display_amount) = &order.display_amount {
+        if *display_amount <= BigDecimal::from(0) || *display_amount > order.amount {
+            return Err("Display amount must be greater than zero and at most the order amount".to_string());
+        }
+        if matches!(order.order_type, OrderType::Market | OrderType::StopMarket)
+            || matches!(order.time_in_force, TimeInForce::IOC | TimeInForce::FOK)
+        {
+            return Err("Only resting limit orders can have a display amount".to_string());
+        }
+    }
+    if order.post_only.is_some() {
+        if matches!(order.order_type, OrderType::Market | OrderType::StopMarket) {
+            return Err("Market orders cannot be post-only".to_string());
+        }
+        if matches!(order.time_in_force, TimeInForce::IOC | TimeInForce::FOK) {
+            return Err("Post-only orders cannot be IOC or FOK".to_string());
+        }
+    }
+
+    // Market trading rules: tick size, lot size and minimum order value
+    market.check_amount("Amount", &order.amount)?;
+    if matches!(order.order_type, OrderType::Limit | OrderType::StopLimit) {
+        market.check_price("Price", &order.price)?;
+        market.check_notional(&order.amount, &order.price)?;
+        market.check_band("Price", &order.price, reference_price)?;
+    }
+    if let Some(trigger_price) = &order.trigger_price {
+        market.check_price("Trigger price", trigger_price)?;
+    }
+    if let Some(worst_price) = &order.worst_price {
+        market.check_price("Worst price", worst_price)?;
+    }
+    if let Some(display_amount) = &order.display_amount {
+        market.check_amount("Display amount", display_amount)?;
+    }
     Ok(())
 }
 
+/// Collateral a trader has left for new orders in a perpetual market: their available quote
+/// balance, less the initial margin of their open positions in every market margined in the same
+/// asset, plus the unrealized PnL of those positions. This market is valued at its book's mark
+/// price, the others at `mark_prices`.
+async fn free_collateral(
+    account: &Account,
+    market: &Market,
+    order_book: &L2OrderBook,
+    mark_prices: &HashMap<i32, BigDecimal>,
+    db: &PgPool,
+) -> sqlx::Result<BigDecimal> {
+    let mut mark_prices = mark_prices.clone();
+    match order_book.mark_price() {
+        Some(mark_price) => mark_prices.insert(market.id, mark_price.clone()),
+        None => mark_prices.remove(&market.id),
+    };
+    let available = account.available(&market.quote_asset);
+    position_service::free_collateral(db, &account.trader_address, &market.quote_asset, &available, &mark_prices).await
+}
+
+/// What happened to an order submitted through `add_order_to_book`.
+#[derive(Debug, Clone, Default)]
+pub struct OrderPlacement {
+    pub order_id: Option<i64>,      // Engine-assigned id, once the order was accepted
+    pub order_hash: Option<String>, // Canonical order hash used by fills and the order routes
+    pub fills: Vec<Fill>,
+    pub rejection: Option<String>,       // Why the order was not accepted, if it wasn't
+    pub repriced_to: Option<BigDecimal>, // Post-only orders moved behind the best price
+    pub triggered_stops: Vec<String>,    // Hashes of stop orders activated by this placement
+    pub self_trades: Vec<SelfTradePrevented>,
+    pub circuit_breaker: Option<TradingPhase>, // Set if this order tripped the circuit breaker
+    pub settlement_error: Option<String>, // Set if a fill failed to settle and the rest of the order was cancelled
+}
+
+impl OrderPlacement {
+    fn rejected(reason: String) -> Self {
+        eprintln!("Order rejected: {}", reason);
+        OrderPlacement { rejection: Some(reason), ..Default::default() }
+    }
+}
+
 pub async fn add_order_to_book(
-    order: Order,
+    mut order: Order,
+    market: &Market,
     order_book: &mut L2OrderBook,
     domain: &EIP712DomainSeparator,
+    mark_prices: &HashMap<i32, BigDecimal>,
+    db: &PgPool,
+) -> OrderPlacement {
+    let order_hash = match order.eip712_hash(domain) {
+        Ok(order_hash) => format!("{:?}", order_hash),
+        Err(reason) => return OrderPlacement::rejected(format!("Order validation failed: {}", reason)),
+    };
+    // Malformed orders are refused before they use up their nonce
+    if let Err(error) = validate_order(&order, market, order_book.last_trade_price()) {
+        return OrderPlacement::rejected(format!("Order validation failed: {}", error));
+    }
+
+    // Every valid order gets an id up front; its nonce is used up even if the order is then
+    // rejected, e.g. for want of balance
+    order.order_id = match register_order(db, market.id, &order, &order_hash).await {
+        Ok(Some(order_id)) => order_id,
+        Ok(None) => return OrderPlacement::rejected(format!("Nonce {:?} has already been used", order.nonce)),
+        Err(e) => {
+            eprintln!("Failed to register order: {}", e);
+            return OrderPlacement::rejected("Failed to register order".to_string());
+        }
+    };
+
+    let mut placement = place_order(order.clone(), &order_hash, market, order_book, mark_prices, db).await;
+    placement.order_id = Some(order.order_id);
+    placement.order_hash = Some(order_hash);
+    // Stops wait for the next trade while the market is halted or in an auction
+    if placement.rejection.is_none() && order_book.phase == TradingPhase::Continuous {
+        placement.triggered_stops = activate_triggered_stops(market, order_book, mark_prices, db).await;
+    }
+    placement
+}
+
+/// Activates stop orders whose trigger price has traded, one at a time. Stops are checked against
+/// every fill's price in the order the fills happened, so a sweep through several levels triggers
+/// what each level reaches, and fills of activated stops are queued up behind those. At each
+/// price buy stops go first, lowest trigger first, then sell stops, highest trigger first.
+async fn activate_triggered_stops(
+    market: &Market,
+    order_book: &mut L2OrderBook,
+    mark_prices: &HashMap<i32, BigDecimal>,
     db: &PgPool,
-) -> Vec<Fill> {
+) -> Vec<String> {
+    let mut activated = Vec::new();
+    while order_book.phase == TradingPhase::Continuous {
+        let trade_price = match order_book.trigger_check_price() {
+            Some(price) => price.clone(),
+            None => break,
+        };
+        let (eip712_hash, mut stop) = match order_book.stops.pop_triggered(&trade_price) {
+            Some(triggered) => triggered,
+            None if order_book.finish_trigger_check() => continue,
+            None => break,
+        };
+
+        // The stop keeps the hash it was signed with
+        stop.order_type = if stop.order_type == OrderType::StopLimit { OrderType::Limit } else { OrderType::Market };
+        stop.trigger_price = None;
+        eprintln!("Stop order triggered at {}: {}", trade_price, eip712_hash);
+
+        let placement = place_order(stop, &eip712_hash, market, order_book, mark_prices, db).await;
+        if let Some(reason) = placement.rejection.or(placement.settlement_error) {
+            eprintln!("Triggered stop order {} was not placed: {}", eip712_hash, reason);
+        }
+        activated.push(eip712_hash);
+    }
+    // If the market halted or went into an auction on the way, the trades left unchecked are
+    // dropped; once trading resumes the stops are checked against the last trade price
+    order_book.skip_trigger_checks();
+    activated
+}
+
+async fn place_order(
+    mut order: Order,
+    order_hash: &str,
+    market: &Market,
+    order_book: &mut L2OrderBook,
+    mark_prices: &HashMap<i32, BigDecimal>,
+    db: &PgPool,
+) -> OrderPlacement {
     // Ensure the order book is initialized
-    if let Err(e) = ensure_empty_order_book(db).await {
+    if let Err(e) = ensure_empty_order_book(db, market.id).await {
         eprintln!("Error ensuring empty order book: {}", e);
     }
 
+    if order.market_id != market.id {
+        return OrderPlacement::rejected(format!("Order is not for market {}", market.symbol));
+    }
+
+    if order_book.phase == TradingPhase::Halted {
+        return OrderPlacement::rejected(format!("Trading in {} is halted", market.symbol));
+    }
+
     // Validate the order
-    if let Err(error) = validate_order(&order) {
-        println!("Order validation failed: {}", error);
-        return Vec::new();
+    if let Err(error) = validate_order(&order, market, order_book.last_trade_price()) {
+        return OrderPlacement::rejected(format!("Order validation failed: {}", error));
+    }
+
+    // During a call auction orders only collect in the book, so orders that cannot rest are refused
+    let in_auction = order_book.phase == TradingPhase::Auction;
+    if in_auction
+        && (order.order_type == OrderType::Market || matches!(order.time_in_force, TimeInForce::IOC | TimeInForce::FOK))
+    {
+        return OrderPlacement::rejected("Market, IOC and FOK orders are not accepted during the auction".to_string());
+    }
+
+    // Post-only orders must never take liquidity: reject them, or move them one tick behind the
+    // best opposite price, when they would cross. Stop orders are checked once they trigger, and
+    // nothing takes liquidity during an auction.
+    let mut repriced_to = None;
+    let post_only = if order.order_type == OrderType::Limit && !in_auction { order.post_only.clone() } else { None };
+    if let Some(action) = post_only {
+        let best_opposite = if order.side == OrderSide::Bid {
+            order_book.best_ask().cloned()
+        } else {
+            order_book.best_bid().cloned()
+        };
+        if let Some(best_opposite) = best_opposite {
+            let crosses = (order.side == OrderSide::Bid && order.price >= best_opposite)
+                || (order.side == OrderSide::Ask && order.price <= best_opposite);
+            if crosses {
+                let new_price = if order.side == OrderSide::Bid {
+                    best_opposite - market.rules.price_tick.clone()
+                } else {
+                    best_opposite + market.rules.price_tick.clone()
+                };
+                if action == PostOnlyAction::Reject || new_price <= BigDecimal::from(0) {
+                    return OrderPlacement::rejected("Post-only order would take liquidity".to_string());
+                }
+                order.price = new_price.clone();
+                // The new price must still be inside the price band and meet the minimum order value
+                if let Err(error) = validate_order(&order, market, order_book.last_trade_price()) {
+                    return OrderPlacement::rejected(format!("Post-only order repriced to {} failed validation: {}", new_price, error));
+                }
+                repriced_to = Some(new_price);
+            }
+        }
     }
 
     // Retrieve the account by trader address
-    let trader_address_h160 = H160::from_str(&format!("{:?}", order.trader_address)).unwrap(); // Convert to H160
-    let account_result = account_service::get_account_from_db(&trader_address_h160).await;
+    let account_result = account_service::get_account_from_db(&order.trader_address).await;
 
     // Check if the account exists and has sufficient balance
     let account = match account_result {
         Ok(acc) => acc,
         Err(_) => {
-            println!("Account not found for trader address: {:?}", order.trader_address);
-            return Vec::new();
+            return OrderPlacement::rejected(format!("Account not found for trader address: {:?}", order.trader_address));
         }
     };
 
-    // Check if the trader has enough balance for the order
-    if order.side == OrderSide::Bid {
-        let amount = order.amount.clone();  
-        let price = order.price.clone();
-        if account.usd_balance < amount * price {
-            println!("Insufficient USD balance for trader: {:?}", order.trader_address);
-            return Vec::new();
-        }
+    // Orders without their own self-trade prevention mode use the account's
+    if order.self_trade_prevention.is_none() {
+        order.self_trade_prevention = account.stp_mode.clone();
+    }
+
+    // Where the market stood at the start of the circuit breaker window; sweeps are only costed
+    // up to the level that would trip the breaker
+    let reference = order_book.reference_price_since(market.breaker_window_start(Utc::now()));
+
+    // Work out what the order has to reserve and check the trader's available balance covers it.
+    // Perpetual orders on either side reserve initial margin on what they would cost. Liquidation
+    // orders only close a position, so they reserve nothing.
+    let (hold_asset, hold_amount) = if order.liquidation {
+        (market.quote_asset.clone(), BigDecimal::from(0))
+    } else if market.is_perpetual() {
+        let notional = match order.order_type {
+            OrderType::Market => {
+                let limit = price_limit(&order, market, order_book);
+                estimate_fill_cost(market, order_book, &order, &limit, reference.as_ref()).1
+            }
+            OrderType::Limit | OrderType::StopLimit => order.amount.clone() * order.price.clone(),
+            OrderType::StopMarket => BigDecimal::from(0),
+        };
+        (market.quote_asset.clone(), market.margin_for(&notional))
+    } else if order.side == OrderSide::Bid {
+        // Market bids are costed against the book, limit bids at their own price
+        let cost = match order.order_type {
+            OrderType::Market => {
+                let limit = price_limit(&order, market, order_book);
+                estimate_fill_cost(market, order_book, &order, &limit, reference.as_ref()).1
+            }
+            OrderType::Limit | OrderType::StopLimit => order.amount.clone() * order.price.clone(),
+            // Stop-market cost is unknown until the stop triggers, it is checked again then
+            OrderType::StopMarket => BigDecimal::from(0),
+        };
+        (market.quote_asset.clone(), cost)
     } else {
-        // For Ask orders, check USD balance
-        if account.ddx_balance < order.amount {
-            println!("Insufficient DDX balance for trader: {:?}", order.trader_address);
-            return Vec::new();
+        (market.base_asset.clone(), order.amount.clone())
+    };
+    if !order.liquidation && account.available(&hold_asset) < hold_amount {
+        return OrderPlacement::rejected(format!("Insufficient {} balance for trader: {:?}", hold_asset, order.trader_address));
+    }
+    // The insurance fund's orders only close positions it took over in liquidations, so they
+    // are held against its balance but not checked against margin; nor are liquidation orders
+    if market.is_perpetual() && !order.liquidation && order.trader_address != liquidation_service::insurance_fund() {
+        match free_collateral(&account, market, order_book, mark_prices, db).await {
+            Ok(free) if free >= hold_amount => {}
+            Ok(_) => return OrderPlacement::rejected(format!("Insufficient margin for trader: {:?}", order.trader_address)),
+            Err(e) => {
+                eprintln!("Failed to check margin: {}", e);
+                return OrderPlacement::rejected("Failed to check margin".to_string());
+            }
         }
     }
-    let hashhh = order.eip712_hash(domain).to_string();
-    println!("{}",format!("{:?}",order.eip712_hash(domain)));
-    // Create an OrderEntry from the incoming order
+    // Create an OrderEntry from the incoming order; icebergs only show their display slice
+    let visible_amount = match &order.display_amount {
+        Some(display_amount) => display_amount.clone().min(order.amount.clone()),
+        None => order.amount.clone(),
+    };
     let order_entry = OrderEntry {
-        amount: order.amount.clone(),
+        market_id: market.id,
+        order_id: order.order_id,
+        amount: visible_amount.clone(),
         price: order.price.clone(),
-        trader_address: order.trader_address.clone(),
-        eip712_hash: format!("{:?}",order.eip712_hash(domain)),  // Calculate and store the EIP712 hash
+        trader_address: order.trader_address,
+        eip712_hash: order_hash.to_string(), // Canonical EIP-712 hash
+        expires_at: order.expires_at,
+        display_amount: order.display_amount.clone(),
+        hidden_amount: order.amount.clone() - visible_amount,
     };
 
-    // Insert the order into the order book before matching
-    if order.side == OrderSide::Bid {
-        let pos = order_book
-            .bids
-            .iter()
-            .position(|x| x.price < order.price)
-            .unwrap_or(order_book.bids.len());
-        order_book.bids.insert(pos, order_entry); 
-    } else {
-        let pos = order_book
-            .asks
-            .iter()
-            .position(|x| x.price > order.price)
-            .unwrap_or(order_book.asks.len());
-        order_book.asks.insert(pos, order_entry); 
+    // Stop orders wait in the trigger book until a trade reaches their trigger. They reserve no
+    // funds while they wait: the hold is taken, and the balance checked, once they trigger
+    if matches!(order.order_type, OrderType::StopMarket | OrderType::StopLimit) {
+        order_book.stops.insert(order_entry.eip712_hash.clone(), order);
+        return OrderPlacement { repriced_to, ..Default::default() };
+    }
+
+    // Fill-or-kill orders only go ahead if the book can fill them completely, without running into
+    // one of the trader's own orders or tripping the circuit breaker part of the way through
+    if order.time_in_force == TimeInForce::FOK {
+        let limit = price_limit(&order, market, order_book);
+        let (fillable, _) = estimate_fill_cost(market, order_book, &order, &limit, reference.as_ref());
+        if fillable < order.amount {
+            return OrderPlacement::rejected("Fill-or-kill order could not be filled completely and was cancelled".to_string());
+        }
+    }
+
+    // Reserve the order's funds; fills pay out of the hold and whatever is unused is released
+    if !order.liquidation {
+        if let Err(reason) = lock_funds(db, &order.trader_address, &hold_asset, &hold_amount, order.order_id).await {
+            return OrderPlacement::rejected(reason);
+        }
+    }
+
+    // Auction orders rest without matching until the uncross
+    if in_auction {
+        order_book.insert(&order.side, order_entry);
+        if let Err(e) = update_order_book(db, market.id, order_book).await {
+            eprintln!("Failed to update order book in database: {}", e);
+        }
+        return OrderPlacement::default();
+    }
+
+    // Insert the order into the order book before matching; market, IOC and FOK orders never rest,
+    // so whatever is left of them after matching is cancelled
+    let rests = order.order_type == OrderType::Limit
+        && matches!(order.time_in_force, TimeInForce::GTC | TimeInForce::GTD);
+    if rests {
+        order_book.insert(&order.side, order_entry);
     }
 
     // Perform matching now that the order is in the book
-    let fills = match_order(&order, order_book, domain, db).await;
-    
-    // After matching, if the order has remaining amount, we leave it in the book
-    if fills.is_empty() || order.amount > BigDecimal::from(0) {
-        // The order is left in the book (no full match), so we update the order book in the DB
-        let updated_order_book = L2OrderBook {
-            asks: order_book.asks.clone(),
-            bids: order_book.bids.clone(),
-        };
+    let MatchResult { fills, self_trades, circuit_breaker, spent, settlement_error } = match_order(&order, order_hash, market, order_book, &hold_amount, db).await;
+
+    // Release the part of the hold that was neither spent nor is still needed by the resting remainder
+    let still_held = order_book
+        .get(order_hash)
+        .map(|entry| hold_for(market, &order.side, &entry.total_amount(), &entry.price).1)
+        .unwrap_or_default();
+    release_funds(db, &order.trader_address, &hold_asset, &(hold_amount - spent - still_held), order.order_id).await;
+
+    // Matching and a resting remainder both change the book, so the stored copy is always updated
+    if let Err(e) = update_order_book(db, market.id, order_book).await {
+        eprintln!("Failed to update order book in database: {}", e);
+    }
+
+    OrderPlacement { fills, repriced_to, self_trades, circuit_breaker, settlement_error, ..Default::default() }
+}
 
-        if let Err(e) = update_order_book(db, &updated_order_book).await {
+
+
+/// What a call auction uncross traded.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct AuctionUncross {
+    pub price: Option<BigDecimal>, // None if nothing crossed
+    pub volume: BigDecimal,
+    pub fills: Vec<Fill>,
+    pub triggered_stops: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settlement_error: Option<String>, // Set if a fill failed to settle; the market stays in the auction
+}
+
+/// Ends a call auction: executes every crossing order at the single equilibrium price, then
+/// returns the market to continuous trading. There is no aggressor in an auction, so each fill
+/// records the sell order as maker and the buy order as taker.
+pub async fn uncross_auction(
+    market: &Market,
+    order_book: &mut L2OrderBook,
+    mark_prices: &HashMap<i32, BigDecimal>,
+    db: &PgPool,
+) -> AuctionUncross {
+    let mut uncross = AuctionUncross::default();
+    if let Some((price, _)) = order_book.equilibrium() {
+        while let (Some(bid), Some(ask)) = (order_book.front(&OrderSide::Bid), order_book.front(&OrderSide::Ask)) {
+            let now = Utc::now();
+            let expired = [&bid, &ask]
+                .into_iter()
+                .find(|entry| entry.expires_at.is_some_and(|expires_at| expires_at <= now));
+            if let Some(expired) = expired {
+                cancel_resting_order(market, order_book, &expired.eip712_hash, db).await;
+                continue;
+            }
+            if bid.price < price || ask.price > price {
+                break;
+            }
+
+            // A trader's own orders never trade with each other; both are reduced instead
+            if bid.trader_address == ask.trader_address {
+                let decrement = bid.total_amount().min(ask.total_amount());
+                eprintln!("Self-trade prevented in auction: {} and {} -{}", bid.eip712_hash, ask.eip712_hash, decrement);
+                order_book.set_amount(&bid.eip712_hash, bid.total_amount() - decrement.clone());
+                order_book.set_amount(&ask.eip712_hash, ask.total_amount() - decrement.clone());
+                let (bid_asset, bid_held) = hold_for(market, &OrderSide::Bid, &decrement, &bid.price);
+                let (ask_asset, ask_held) = hold_for(market, &OrderSide::Ask, &decrement, &ask.price);
+                release_funds(db, &bid.trader_address, &bid_asset, &bid_held, bid.order_id).await;
+                release_funds(db, &ask.trader_address, &ask_asset, &ask_held, ask.order_id).await;
+                continue;
+            }
+
+            let fill_amount = bid.amount.clone().min(ask.amount.clone());
+            let fill = Fill {
+                market_id: market.id,
+                maker_hash: ask.hash(),
+                taker_hash: bid.hash(),
+                maker_order_id: ask.order_id,
+                taker_order_id: bid.order_id,
+                fill_amount: fill_amount.clone(),
+                price: price.clone(),
+            };
+            if let Err(e) = settle_fill(db, market, &fill, &bid.trader_address, &ask.trader_address, &fill_amount, &price, &bid.price, &ask.price).await {
+                eprintln!("Failed to settle auction fill: {}", e);
+                uncross.settlement_error = Some(format!("Failed to settle fill: {}", e));
+                break;
+            }
+            order_book.fill(&bid.eip712_hash, fill_amount.clone());
+            order_book.fill(&ask.eip712_hash, fill_amount.clone());
+            uncross.fills.push(fill);
+            uncross.volume += fill_amount;
+        }
+        if !uncross.fills.is_empty() {
+            order_book.record_trade(price.clone());
+            uncross.price = Some(price);
+        }
+    }
+
+    // A fill that failed to settle leaves the book crossed, so the auction cannot end yet
+    if uncross.settlement_error.is_some() {
+        if let Err(e) = update_order_book(db, market.id, order_book).await {
             eprintln!("Failed to update order book in database: {}", e);
         }
+        return uncross;
+    }
+
+    let reason = match &uncross.price {
+        Some(price) => format!("Auction uncrossed: {} traded at {}", uncross.volume, price),
+        None => "Auction ended without crossing orders".to_string(),
+    };
+    set_phase(market, order_book, TradingPhase::Continuous, &reason, db).await;
+    if let Err(e) = update_order_book(db, market.id, order_book).await {
+        eprintln!("Failed to update order book in database: {}", e);
+    }
+
+    // Stops triggered by the auction price go into continuous trading
+    uncross.triggered_stops = activate_triggered_stops(market, order_book, mark_prices, db).await;
+    uncross
+}
+
+/// Moves a market to another trading phase and records the change in its event history.
+pub async fn set_phase(market: &Market, order_book: &mut L2OrderBook, phase: TradingPhase, reason: &str, db: &PgPool) {
+    eprintln!("{} is now {}: {}", market.symbol, phase, reason);
+    if phase == TradingPhase::Continuous {
+        // The move that tripped the breaker shouldn't trip it again straight away
+        order_book.restart_price_window();
+    }
+    order_book.phase = phase.clone();
+    if let Err(e) = market_service::record_market_event(db, market.id, &phase, reason).await {
+        eprintln!("Failed to record market event: {}", e);
     }
+}
 
-    fills
+/// Result of amending a resting order.
+#[derive(Debug, Clone)]
+pub struct AmendedOrder {
+    pub entry: OrderEntry,
+    pub kept_priority: bool,
 }
 
+/// Changes the price and/or total amount of a resting order in place. Reducing the amount keeps
+/// the order's time priority; a price change or an increase sends it to the back of its level.
+/// Amends that would cross the book are rejected, cancel and re-place the order instead, and so
+/// are amends while the market is halted.
+pub async fn amend_order(
+    eip712_hash: &str,
+    new_price: Option<BigDecimal>,
+    new_amount: Option<BigDecimal>,
+    market: &Market,
+    order_book: &mut L2OrderBook,
+    db: &PgPool,
+) -> Result<AmendedOrder, String> {
+    let (mut entry, side) = match (order_book.get(eip712_hash), order_book.side_of(eip712_hash)) {
+        (Some(entry), Some(side)) => (entry.clone(), side),
+        _ => return Err("Order not found".to_string()),
+    };
+    if order_book.phase == TradingPhase::Halted {
+        return Err(format!("Trading in {} is halted", market.symbol));
+    }
+    let price = new_price.unwrap_or_else(|| entry.price.clone());
+    let amount = new_amount.unwrap_or_else(|| entry.total_amount());
+    if price <= BigDecimal::from(0) {
+        return Err("Price must be greater than zero".to_string());
+    }
+    if amount <= BigDecimal::from(0) {
+        return Err("Amount must be greater than zero".to_string());
+    }
+    market.check_price("Price", &price)?;
+    market.check_amount("Amount", &amount)?;
+    market.check_notional(&amount, &price)?;
+    market.check_band("Price", &price, order_book.last_trade_price())?;
+
+    let crosses = order_book.phase == TradingPhase::Continuous && match side {
+        OrderSide::Bid => order_book.best_ask().is_some_and(|best_ask| price >= *best_ask),
+        OrderSide::Ask => order_book.best_bid().is_some_and(|best_bid| price <= *best_bid),
+    };
+    if crosses {
+        return Err("Amended price would cross the book".to_string());
+    }
+
+    // The hold follows the amended order: an increase must be covered by the available balance
+    let (asset, old_hold) = hold_for(market, &side, &entry.total_amount(), &entry.price);
+    let (_, new_hold) = hold_for(market, &side, &amount, &price);
+    if new_hold > old_hold {
+        lock_funds(db, &entry.trader_address, &asset, &(new_hold - old_hold), entry.order_id).await?;
+    } else {
+        release_funds(db, &entry.trader_address, &asset, &(old_hold - new_hold), entry.order_id).await;
+    }
 
+    let kept_priority = price == entry.price && amount <= entry.total_amount();
+    if kept_priority {
+        order_book.set_amount(eip712_hash, amount);
+    } else {
+        order_book.remove(eip712_hash);
+        let visible_amount = match &entry.display_amount {
+            Some(display_amount) => display_amount.clone().min(amount.clone()),
+            None => amount.clone(),
+        };
+        entry.hidden_amount = amount - visible_amount.clone();
+        entry.amount = visible_amount;
+        entry.price = price;
+        order_book.insert(&side, entry);
+    }
 
-pub async fn insert_fill(db: &PgPool, fill: &Fill) -> sqlx::Result<()> {
-    
+    if let Err(e) = update_order_book(db, market.id, order_book).await {
+        eprintln!("Failed to update order book in database: {}", e);
+    }
 
-    // Convert U256 to String for database compatibility
-    let fill_amount =u256_to_bigdecimal( fill.fill_amount); // Convert U256 to String
-    let price = u256_to_bigdecimal(fill.price); // Convert U256 to String
+    let entry = order_book
+        .get(eip712_hash)
+        .cloned()
+        .ok_or_else(|| "Order not found".to_string())?;
+    Ok(AmendedOrder { entry, kept_priority })
+}
 
-    query!(
-        "INSERT INTO fills (maker_hash, taker_hash, fill_amount, price) 
-         VALUES ($1, $2, $3, $4)",
+/// Drops expired GTD orders from the book and the trigger book, releases the resting orders'
+/// holds and persists the book if anything was removed. Returns the hashes of the expired orders.
+pub async fn sweep_expired_orders(
+    market: &Market,
+    order_book: &mut L2OrderBook,
+    now: DateTime<Utc>,
+    db: &PgPool,
+) -> Vec<String> {
+    let mut expired = Vec::new();
+    for (side, entry) in order_book.remove_expired(now) {
+        release_order_hold(db, market, &side, &entry).await;
+        expired.push(entry.eip712_hash);
+    }
+    if !expired.is_empty() {
+        if let Err(e) = update_order_book(db, market.id, order_book).await {
+            eprintln!("Failed to update order book in database: {}", e);
+        }
+    }
+    // Pending stops hold no funds, there is nothing to release
+    for (eip712_hash, _) in order_book.stops.remove_expired(now) {
+        expired.push(eip712_hash);
+    }
+    expired
+}
+
+/// Records a new order and assigns its order id. Returns `None` if the trader already used the
+/// order's nonce.
+async fn register_order(db: &PgPool, market_id: i32, order: &Order, eip712_hash: &str) -> sqlx::Result<Option<i64>> {
+    let row = query!(
+        "INSERT INTO orders (market_id, trader_address, nonce, eip712_hash)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT DO NOTHING
+         RETURNING order_id",
+        market_id,
+        format!("{:?}", order.trader_address),
+        format!("{:?}", order.nonce),
+        eip712_hash,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|row| row.order_id))
+}
+
+/// Uses up an amend nonce for an order. Returns `false` if the nonce already amended it.
+pub async fn register_amendment(db: &PgPool, eip712_hash: &str, nonce: &Hash) -> sqlx::Result<bool> {
+    let row = query!(
+        "INSERT INTO order_amendments (eip712_hash, nonce)
+         VALUES ($1, $2)
+         ON CONFLICT DO NOTHING
+         RETURNING id",
+        eip712_hash,
+        format!("{:?}", nonce),
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+/// Records a fill and returns its id.
+pub async fn insert_fill(conn: &mut PgConnection, fill: &Fill) -> sqlx::Result<i64> {
+    let row = query!(
+        "INSERT INTO fills (market_id, maker_hash, taker_hash, maker_order_id, taker_order_id, fill_amount, price) 
+         VALUES ($1, $2, $3, $4, $5, $6, $7)
+         RETURNING id",
+         fill.market_id,
          format!("{:?}", fill.maker_hash),
          format!("{:?}", fill.taker_hash),
-        fill_amount,
-        price
+         fill.maker_order_id,
+         fill.taker_order_id,
+        fill.fill_amount,
+        fill.price
     )
-    .execute(db)
+    .fetch_one(conn)
     .await?; 
 
-    Ok(())
+    Ok(row.id)
 }
 
-pub async fn update_order_book(db: &PgPool, order_book: &L2OrderBook) -> sqlx::Result<()> {
-    let asks_json = serde_json::to_value(
-        order_book.asks.iter().map(|entry| json!({ "amount": entry.amount, "price": entry.price, "trader_address": entry.trader_address , "eip712_hash":entry.eip712_hash})).collect::<Vec<_>>()
-    ).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+pub async fn update_order_book(db: &PgPool, market_id: i32, order_book: &L2OrderBook) -> sqlx::Result<()> {
+    let asks_json = serde_json::to_value(order_book.asks().iter().map(order_entry_json).collect::<Vec<_>>())
+        .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
 
-    let bids_json = serde_json::to_value(
-        order_book.bids.iter().map(|entry| json!({ "amount": entry.amount, "price": entry.price, "trader_address": entry.trader_address , "eip712_hash":entry.eip712_hash})).collect::<Vec<_>>()
-    ).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+    let bids_json = serde_json::to_value(order_book.bids().iter().map(order_entry_json).collect::<Vec<_>>())
+        .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
 
     query!(
-        "UPDATE l2_order_book SET asks = $1, bids = $2 WHERE id = 1",
+        "UPDATE l2_order_book SET asks = $1, bids = $2 WHERE market_id = $3",
         asks_json,
         bids_json,
+        market_id,
     )
     .execute(db)
     .await?; 
@@ -310,16 +1291,69 @@ pub async fn update_order_book(db: &PgPool, order_book: &L2OrderBook) -> sqlx::R
     Ok(())
 }
 
-pub async fn ensure_empty_order_book(db: &PgPool) -> sqlx::Result<()> {
-    // Check if the table is empty (no records with id = 1)
-    let result = query!("SELECT COUNT(*) FROM l2_order_book WHERE id = 1")
+// Iceberg reserves are stored so a restored book holds exactly what it held before, but they are
+// never part of a published snapshot
+fn order_entry_json(entry: &OrderEntry) -> Value {
+    json!({
+        "market_id": entry.market_id,
+        "order_id": entry.order_id,
+        "amount": entry.amount,
+        "price": entry.price,
+        "trader_address": entry.trader_address,
+        "eip712_hash": entry.eip712_hash,
+        "expires_at": entry.expires_at,
+        "display_amount": entry.display_amount,
+        "hidden_amount": entry.hidden_amount,
+    })
+}
+
+/// Loads a market's resting orders from `l2_order_book` into an empty `order_book`, e.g. after a
+/// restart. They were saved in price-time order, so inserting them in that order keeps each
+/// level's time priority, and the funds they hold are matched by orders in the book again.
+/// Returns how many orders were restored.
+pub async fn restore_order_book(db: &PgPool, market_id: i32, order_book: &mut L2OrderBook) -> sqlx::Result<usize> {
+    let row = sqlx::query_as!(
+        OrderBookRow,
+        r#"
+        SELECT asks, bids
+        FROM l2_order_book
+        WHERE market_id = $1
+        "#,
+        market_id
+    )
+    .fetch_optional(db)
+    .await?;
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(0),
+    };
+
+    let mut restored = 0;
+    for (side, orders) in [(OrderSide::Ask, row.asks), (OrderSide::Bid, row.bids)] {
+        for order in orders.as_ref().and_then(Value::as_array).into_iter().flatten() {
+            match parse_order_entry(order, market_id) {
+                Some(entry) => {
+                    order_book.insert(&side, entry);
+                    restored += 1;
+                }
+                None => eprintln!("Failed to restore order in market {}: {}", market_id, order),
+            }
+        }
+    }
+    Ok(restored)
+}
+
+pub async fn ensure_empty_order_book(db: &PgPool, market_id: i32) -> sqlx::Result<()> {
+    // Check if the market already has an order book row
+    let result = query!("SELECT COUNT(*) FROM l2_order_book WHERE market_id = $1", market_id)
         .fetch_one(db)
         .await?;
 
     // If no record is found, insert an empty order book
     if result.count.unwrap_or(0) == 0 {
         query!(
-            "INSERT INTO l2_order_book (id, asks, bids) VALUES (1, $1, $2)",
+            "INSERT INTO l2_order_book (market_id, asks, bids) VALUES ($1, $2, $3)",
+            market_id,
             json!([]), // Empty asks array
             json!([]), // Empty bids array
         )
@@ -332,30 +1366,13 @@ pub async fn ensure_empty_order_book(db: &PgPool) -> sqlx::Result<()> {
 
 
 
-pub struct AppState {
-    pub order_book: L2OrderBook, // No synchronization needed
-    pub domain_separator: EIP712DomainSeparator, // Domain separator if needed
-}
-impl AppState {
-    pub fn new(domain_separator: EIP712DomainSeparator) -> Self {
-        AppState {
-            order_book: L2OrderBook { asks: Vec::new(), bids: Vec::new() },
-            domain_separator : EIP712DomainSeparator {
-                name: "DDX take-home".to_string(),
-                version: "0.1.0".to_string(),
-                // Add other required fields here...
-            }, // Initialize domain_separator
-        }
-    }
-}
-
 #[derive(sqlx::FromRow)]
 struct OrderBookRow {
     asks: Option<Value>,
     bids: Option<Value>,
 }
 
-pub async fn get_order_entry_by_hash(db: &PgPool, eip712_hash: &H256) -> Result<Option<OrderEntry>, Error> {
+pub async fn get_order_entry_by_hash(db: &PgPool, market_id: i32, eip712_hash: &H256) -> Result<Option<OrderEntry>, Error> {
     // Convert H256 hash to string format for querying
     let hash_str = format!("{:?}", eip712_hash);
 
@@ -365,8 +1382,9 @@ pub async fn get_order_entry_by_hash(db: &PgPool, eip712_hash: &H256) -> Result<
         r#"
         SELECT asks, bids
         FROM l2_order_book
-        WHERE id = 1 -- Assuming you're querying the default order book entry
-        "#
+        WHERE market_id = $1
+        "#,
+        market_id
     )
     .fetch_one(db)
     .await?;
@@ -374,12 +1392,12 @@ pub async fn get_order_entry_by_hash(db: &PgPool, eip712_hash: &H256) -> Result<
     // Check if we got any rows back
     if let (Some(asks), Some(bids)) = (row.asks, row.bids) {
         // Search in asks
-        if let Some(order_entry) = find_order_entry_in_json(&asks, &hash_str) {
+        if let Some(order_entry) = find_order_entry_in_json(&asks, market_id, &hash_str) {
             return Ok(Some(order_entry));
         }
         
         // Search in bids
-        if let Some(order_entry) = find_order_entry_in_json(&bids, &hash_str) {
+        if let Some(order_entry) = find_order_entry_in_json(&bids, market_id, &hash_str) {
             return Ok(Some(order_entry));
         }
     }
@@ -388,27 +1406,39 @@ pub async fn get_order_entry_by_hash(db: &PgPool, eip712_hash: &H256) -> Result<
     Ok(None)
 }
 // Helper function to find an order entry in a JSON array by EIP-712 hash
-fn find_order_entry_in_json(json_array: &Value, eip712_hash: &str) -> Option<OrderEntry> {
-    if let Some(orders) = json_array.as_array() {
-        for order in orders {
-            if let Some(hash) = order.get("eip712_hash").and_then(Value::as_str) {
-                if hash == eip712_hash {
-                    // Extract fields and create an OrderEntry instance
-                    return Some(OrderEntry {
-                        amount: BigDecimal::from_str(order.get("amount")?.as_str()?).ok()?, // Convert as needed
-                        price: BigDecimal::from_str(order.get("price")?.as_str()?).ok()?,   // Convert as needed
-                        trader_address: H160::from_str(order.get("trader_address")?.as_str()?).ok()?, // Convert string to H160
-                        eip712_hash: hash.to_string(),
-                    });
-                }
-            }
-        }
-    }
-    
-    None // Return None if not found
+fn find_order_entry_in_json(json_array: &Value, market_id: i32, eip712_hash: &str) -> Option<OrderEntry> {
+    json_array
+        .as_array()?
+        .iter()
+        .find(|order| order.get("eip712_hash").and_then(Value::as_str) == Some(eip712_hash))
+        .and_then(|order| parse_order_entry(order, market_id))
+}
+
+// Helper function to read an order entry stored as JSON by `update_order_book`
+fn parse_order_entry(order: &Value, market_id: i32) -> Option<OrderEntry> {
+    let decimal = |field: &str| order.get(field).and_then(Value::as_str).and_then(|value| BigDecimal::from_str(value).ok());
+    Some(OrderEntry {
+        market_id,
+        order_id: order.get("order_id").and_then(Value::as_i64).unwrap_or(0),
+        amount: decimal("amount")?,
+        price: decimal("price")?,
+        trader_address: H160::from_str(order.get("trader_address")?.as_str()?).ok()?,
+        eip712_hash: order.get("eip712_hash")?.as_str()?.to_string(),
+        expires_at: parse_expires_at(order),
+        display_amount: decimal("display_amount"),
+        hidden_amount: decimal("hidden_amount").unwrap_or_default(),
+    })
 }
 
-pub async fn delete_order_entry_by_hash(db: &PgPool, eip712_hash: &H256) -> Result<bool, anyhow::Error> {
+// Helper function to read the optional GTD expiry of an order stored as JSON
+fn parse_expires_at(order: &Value) -> Option<DateTime<Utc>> {
+    let expires_at = order.get("expires_at")?.as_str()?;
+    DateTime::parse_from_rfc3339(expires_at).ok().map(|d| d.with_timezone(&Utc))
+}
+
+/// Removes an order from the stored book, returning it with its side if it was there. Its hold
+/// is not touched.
+pub async fn delete_order_entry_by_hash(db: &PgPool, market_id: i32, eip712_hash: &H256) -> Result<Option<(OrderSide, OrderEntry)>, anyhow::Error> {
     // Convert H256 hash to string format for querying
 
     let hash_str = format!("{:?}", eip712_hash);
@@ -419,8 +1449,9 @@ pub async fn delete_order_entry_by_hash(db: &PgPool, eip712_hash: &H256) -> Resu
         r#"
         SELECT asks, bids
         FROM l2_order_book
-        WHERE id = 1 -- Assuming you're querying the default order book entry
-        "#
+        WHERE market_id = $1
+        "#,
+        market_id
     )
     .fetch_one(db)
     .await?;
@@ -429,67 +1460,53 @@ pub async fn delete_order_entry_by_hash(db: &PgPool, eip712_hash: &H256) -> Resu
     let mut asks: Value = row.asks.ok_or_else(|| anyhow::anyhow!("Asks not found"))?;
     let mut bids: Value = row.bids.ok_or_else(|| anyhow::anyhow!("Bids not found"))?;
 
-    // Flag to check if the order was found and deleted
-    let mut order_found = false;
-
-    // Remove the order from asks
-    if let Some(orders) = asks.as_array_mut() {
-        orders.retain(|order| {
-            if let Some(hash) = order.get("eip712_hash").and_then(Value::as_str) {
-                if hash == hash_str {
-                    order_found = true; // Mark as found
-                    return false; // Remove this order
-                }
-            }
-            true // Keep this order
-        });
-    }
-
-    // Remove the order from bids
-    if let Some(orders) = bids.as_array_mut() {
-        orders.retain(|order| {
-            if let Some(hash) = order.get("eip712_hash").and_then(Value::as_str) {
-                if hash == hash_str {
-                    order_found = true; // Mark as found
-                    return false; // Remove this order
-                }
+    // Remove the order from whichever side it rests on
+    let mut deleted = None;
+    for (side, orders) in [(OrderSide::Ask, &mut asks), (OrderSide::Bid, &mut bids)] {
+        if let Some(orders) = orders.as_array_mut() {
+            if let Some(index) = orders.iter().position(|order| order.get("eip712_hash").and_then(Value::as_str) == Some(hash_str.as_str())) {
+                deleted = Some((side, orders.remove(index)));
+                break;
             }
-            true // Keep this order
-        });
+        }
     }
 
-    // If no order was found, return false
-    if !order_found {
-        return Ok(false);
-    }
+    // If no order was found, return None
+    let (side, order) = match deleted {
+        Some(deleted) => deleted,
+        None => return Ok(None),
+    };
+    let entry = parse_order_entry(&order, market_id).ok_or_else(|| anyhow::anyhow!("Stored order is malformed: {}", order))?;
 
     // Update the order book in the database
     sqlx::query!(
         r#"
         UPDATE l2_order_book
         SET asks = $1, bids = $2
-        WHERE id = 1 -- Assuming you're updating the default order book entry
+        WHERE market_id = $3
         "#,
         serde_json::to_value(asks)?, // Serialize back to JSON
-        serde_json::to_value(bids)?  // Serialize back to JSON
+        serde_json::to_value(bids)?, // Serialize back to JSON
+        market_id
     )
     .execute(db)
     .await?;
 
-    Ok(true) // Return true indicating the order was deleted
+    Ok(Some((side, entry)))
 }
 
 
 
-pub async fn get_order_book_snapshot(db: &PgPool) -> Result<L2OrderBookGetResponse, anyhow::Error> {
+pub async fn get_order_book_snapshot(db: &PgPool, market_id: i32) -> Result<L2OrderBookGetResponse, anyhow::Error> {
     // Query the current order book
     let row: OrderBookRow = sqlx::query_as!(
         OrderBookRow,
         r#"
         SELECT asks, bids
         FROM l2_order_book
-        WHERE id = 1
-        "#
+        WHERE market_id = $1
+        "#,
+        market_id
     )
     .fetch_one(db)
     .await
@@ -502,47 +1519,24 @@ pub async fn get_order_book_snapshot(db: &PgPool) -> Result<L2OrderBookGetRespon
     let asks: Value = row.asks.unwrap_or_default();
     let bids: Value = row.bids.unwrap_or_default();
 
-    // Create the L2OrderBook from the raw data
-    let order_book = L2OrderBook {
-        asks: extract_best_orders(&asks, 50),
-        bids: extract_best_orders(&bids, 50),
-    };
-
-    // Create the L2OrderBookGetResponse
+    // Create the L2OrderBookGetResponse from the raw data
     let response = L2OrderBookGetResponse {
-        best_asks: order_book.asks,
-        best_bids: order_book.bids,
+        best_asks: extract_best_orders(&asks, market_id, 50, &OrderSide::Ask),
+        best_bids: extract_best_orders(&bids, market_id, 50, &OrderSide::Bid),
     };
 
     Ok(response)
 }
-fn extract_best_orders(json_array: &Value, limit: usize) -> Vec<OrderEntry> {
+fn extract_best_orders(json_array: &Value, market_id: i32, limit: usize, side: &OrderSide) -> Vec<OrderEntry> {
     if let Some(orders) = json_array.as_array() {
-        let mut order_vec: Vec<OrderEntry> = orders.iter()
-            .filter_map(|order| {
-                // Attempt to get and parse each field, logging issues if they occur
-                let price_str = order.get("price")?.as_str()?;
-                let amount_str = order.get("amount")?.as_str()?;
-
-                // Convert strings to BigDecimal
-                let price = BigDecimal::from_str(price_str).ok()?;
-                let amount = BigDecimal::from_str(amount_str).ok()?;
-
-                let eip712_hash = order.get("eip712_hash")?.as_str()?.to_string();
-                let trader_address_str = order.get("trader_address")?.as_str()?;
-                let trader_address = H160::from_str(trader_address_str).ok()?;
-
-                Some(OrderEntry {
-                    price,
-                    amount,
-                    eip712_hash,
-                    trader_address,
-                })
-            })
-            .collect();
+        let mut order_vec: Vec<OrderEntry> = orders.iter().filter_map(|order| parse_order_entry(order, market_id)).collect();
 
         // Sort by price (ascending for asks, descending for bids)
-        order_vec.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
+        // The sort is stable, so orders within a level keep their time priority
+        match side {
+            OrderSide::Ask => order_vec.sort_by(|a, b| a.price.cmp(&b.price)),
+            OrderSide::Bid => order_vec.sort_by(|a, b| b.price.cmp(&a.price)),
+        }
 
         // Return the top `limit` orders
         return order_vec.into_iter().take(limit).collect();
@@ -550,3 +1544,226 @@ fn extract_best_orders(json_array: &Value, limit: usize) -> Vec<OrderEntry> {
 
     Vec::new()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::account::Balance;
+    use crate::models::market::{FundingRules, MarketKind, PriceProtection, TradingRules};
+
+    fn dec(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).unwrap()
+    }
+
+    fn spot_market() -> Market {
+        Market {
+            id: 2,
+            symbol: "ETH-USD".to_string(),
+            base_asset: "ETH".to_string(),
+            quote_asset: "USD".to_string(),
+            kind: MarketKind::Spot,
+            initial_margin: BigDecimal::from(0),
+            maintenance_margin: BigDecimal::from(0),
+            funding: FundingRules { interval_secs: 3600, max_rate: BigDecimal::from(0) },
+            rules: TradingRules {
+                price_tick: dec("0.01"),
+                amount_step: dec("0.001"),
+                min_amount: dec("0.001"),
+                max_amount: dec("1000"),
+                min_notional: BigDecimal::from(0),
+            },
+            matching_algorithm: MatchingAlgorithm::Fifo,
+            fifo_slice: BigDecimal::from(0),
+            protection: PriceProtection {
+                price_band: BigDecimal::from(0),
+                breaker_threshold: BigDecimal::from(0),
+                breaker_window_secs: 0,
+                breaker_action: BreakerAction::Halt,
+            },
+        }
+    }
+
+    fn account(address: u64, balances: &[(&str, &str, &str)]) -> Account {
+        let mut account = Account::empty(H160::from_low_u64_be(address));
+        for (asset, available, locked) in balances {
+            account.balances.insert(asset.to_string(), Balance { available: dec(available), locked: dec(locked) });
+        }
+        account
+    }
+
+    fn total(accounts: &[&Account], asset: &str) -> BigDecimal {
+        accounts.iter().map(|account| account.available(asset) + account.locked(asset)).sum()
+    }
+
+    // A bid reserved at its 2000 limit takes an ask resting at 1990.5: the trade settles at the
+    // maker's price and the buyer gets the 9.5 per unit reserved above it back
+    #[test]
+    fn spot_fill_conserves_balances_and_refunds_price_improvement() {
+        let market = spot_market();
+        let buyer_before = account(1, &[("USD", "500", "3000")]);
+        let seller_before = account(2, &[("ETH", "0.5", "1.5"), ("USD", "100", "0")]);
+        let mut buyer = buyer_before.clone();
+        let mut seller = seller_before.clone();
+
+        transfer_spot_fill(&market, &mut buyer, &mut seller, &dec("1.5"), &dec("1990.5"), &dec("2000")).unwrap();
+
+        for asset in ["USD", "ETH"] {
+            assert_eq!(total(&[&buyer, &seller], asset), total(&[&buyer_before, &seller_before], asset), "{} not conserved", asset);
+        }
+        assert_eq!(buyer.balances["USD"], Balance { available: dec("514.25"), locked: dec("0") });
+        assert_eq!(buyer.available("ETH"), dec("1.5"));
+        assert_eq!(seller.balances["ETH"], Balance { available: dec("0.5"), locked: dec("0") });
+        assert_eq!(seller.available("USD"), dec("3085.75"));
+    }
+
+    // Partial fills at different maker prices refund each fill's own improvement and leave the
+    // rest of the hold for the resting remainder
+    #[test]
+    fn partial_fills_keep_the_remaining_hold() {
+        let market = spot_market();
+        let buyer_before = account(1, &[("USD", "0", "4000")]);
+        let seller_before = account(2, &[("ETH", "0", "1.25")]);
+        let mut buyer = buyer_before.clone();
+        let mut seller = seller_before.clone();
+
+        transfer_spot_fill(&market, &mut buyer, &mut seller, &dec("0.5"), &dec("1995"), &dec("2000")).unwrap();
+        transfer_spot_fill(&market, &mut buyer, &mut seller, &dec("0.75"), &dec("1999.99"), &dec("2000")).unwrap();
+
+        for asset in ["USD", "ETH"] {
+            assert_eq!(total(&[&buyer, &seller], asset), total(&[&buyer_before, &seller_before], asset), "{} not conserved", asset);
+        }
+        // 1.25 of the 2 reserved at 2000 filled, so 0.75 * 2000 stays locked for the remainder
+        assert_eq!(buyer.locked("USD"), dec("1500"));
+        assert_eq!(buyer.available("USD"), dec("2.5075"));
+        assert_eq!(seller.available("USD"), dec("2497.4925"));
+    }
+
+    // A fill costing more than the buyer has locked must fail rather than dip into their
+    // available balance
+    #[test]
+    fn spot_fill_cannot_spend_more_than_is_locked() {
+        let market = spot_market();
+        let mut buyer = account(1, &[("USD", "5000", "1990")]);
+        let mut seller = account(2, &[("ETH", "0", "1")]);
+
+        assert!(transfer_spot_fill(&market, &mut buyer, &mut seller, &dec("1"), &dec("2000"), &dec("2000")).is_err());
+        assert_eq!(buyer.available("USD"), dec("5000"));
+    }
+
+    fn resting(trader: u64, hash: &str, price: &str, amount: &str) -> OrderEntry {
+        OrderEntry {
+            market_id: 2,
+            order_id: 0,
+            amount: dec(amount),
+            price: dec(price),
+            trader_address: H160::from_low_u64_be(trader),
+            eip712_hash: hash.to_string(),
+            expires_at: None,
+            display_amount: None,
+            hidden_amount: BigDecimal::from(0),
+        }
+    }
+
+    fn fok_bid(trader: u64, amount: &str, price: &str, mode: SelfTradePrevention) -> Order {
+        Order {
+            market_id: 2,
+            side: OrderSide::Bid,
+            amount: dec(amount),
+            price: dec(price),
+            trader_address: H160::from_low_u64_be(trader),
+            time_in_force: TimeInForce::FOK,
+            self_trade_prevention: Some(mode),
+            ..Default::default()
+        }
+    }
+
+    // A FOK bid for 3 behind the trader's own ask at 101: matching would cancel the bid there
+    // after 1 filled, so only 1 counts as fillable and the order is refused up front
+    #[test]
+    fn fok_estimate_stops_at_own_resting_order() {
+        let market = spot_market();
+        let mut book = L2OrderBook::new();
+        book.insert(&OrderSide::Ask, resting(2, "a1", "100", "1"));
+        book.insert(&OrderSide::Ask, resting(1, "own", "101", "1"));
+        book.insert(&OrderSide::Ask, resting(3, "a2", "102", "5"));
+        let limit = Some(dec("102"));
+
+        let order = fok_bid(1, "3", "102", SelfTradePrevention::CancelNewest);
+        assert_eq!(estimate_fill_cost(&market, &book, &order, &limit, None), (dec("1"), dec("100")));
+        let order = fok_bid(1, "3", "102", SelfTradePrevention::DecrementAndCancel);
+        assert_eq!(estimate_fill_cost(&market, &book, &order, &limit, None).0, dec("1"));
+
+        // Cancelling the resting order leaves the incoming one whole, so the sweep carries on
+        let order = fok_bid(1, "3", "102", SelfTradePrevention::CancelOldest);
+        assert_eq!(estimate_fill_cost(&market, &book, &order, &limit, None), (dec("3"), dec("304")));
+    }
+
+    #[test]
+    fn fok_estimate_stops_before_the_circuit_breaker() {
+        let mut market = spot_market();
+        market.protection.breaker_threshold = dec("0.015");
+        let mut book = L2OrderBook::new();
+        book.insert(&OrderSide::Ask, resting(2, "a1", "100", "1"));
+        book.insert(&OrderSide::Ask, resting(3, "a2", "102", "5"));
+
+        let order = fok_bid(1, "3", "102", SelfTradePrevention::CancelNewest);
+        let (fillable, _) = estimate_fill_cost(&market, &book, &order, &Some(dec("102")), Some(&dec("100")));
+        assert_eq!(fillable, dec("1"));
+    }
+
+    fn level(sizes: &[&str]) -> Vec<OrderEntry> {
+        sizes.iter().enumerate().map(|(i, size)| resting(i as u64 + 10, &format!("m{}", i), "100", size)).collect()
+    }
+
+    fn decs(values: &[&str]) -> Vec<BigDecimal> {
+        values.iter().map(|value| dec(value)).collect()
+    }
+
+    #[test]
+    fn fifo_allocation_fills_oldest_first() {
+        let market = spot_market();
+        let makers = level(&["2", "3", "5"]);
+
+        assert_eq!(allocate_level(&market, &makers, &dec("4")), decs(&["2", "2", "0"]));
+        assert_eq!(allocate_level(&market, &makers, &dec("0.5")), decs(&["0.5", "0", "0"]));
+        assert_eq!(allocate_level(&market, &makers, &dec("20")), decs(&["2", "3", "5"]));
+    }
+
+    #[test]
+    fn pro_rata_allocation_follows_resting_size() {
+        let mut market = spot_market();
+        market.matching_algorithm = MatchingAlgorithm::ProRata;
+        let makers = level(&["2", "3", "5"]);
+
+        assert_eq!(allocate_level(&market, &makers, &dec("5")), decs(&["1", "1.5", "2.5"]));
+        assert_eq!(allocate_level(&market, &makers, &dec("20")), decs(&["2", "3", "5"]));
+    }
+
+    // A fifth of the 5 goes to the oldest order first; the other 4 is shared over the 1, 3 and 5
+    // still resting, and the 0.001 lost to rounding goes back to the oldest
+    #[test]
+    fn pro_rata_allocation_gives_the_fifo_slice_first() {
+        let mut market = spot_market();
+        market.matching_algorithm = MatchingAlgorithm::ProRata;
+        market.fifo_slice = dec("0.2");
+        let makers = level(&["2", "3", "5"]);
+
+        assert_eq!(allocate_level(&market, &makers, &dec("5")), decs(&["1.445", "1.333", "2.222"]));
+    }
+
+    #[test]
+    fn pro_rata_allocation_rounds_to_the_lot_and_gives_leftovers_to_the_oldest() {
+        let mut market = spot_market();
+        market.matching_algorithm = MatchingAlgorithm::ProRata;
+        let makers = level(&["1", "1", "1"]);
+
+        assert_eq!(allocate_level(&market, &makers, &dec("1")), decs(&["0.334", "0.333", "0.333"]));
+        // Each share of 0.002 rounds down to nothing, so the whole amount goes in time priority
+        assert_eq!(allocate_level(&market, &makers, &dec("0.002")), decs(&["0.002", "0", "0"]));
+
+        // The slice itself is rounded down to the lot: 0.002 of the 0.005 goes first, not 0.0025
+        market.fifo_slice = dec("0.5");
+        let makers = level(&["1", "0.998"]);
+        assert_eq!(allocate_level(&market, &makers, &dec("0.005")), decs(&["0.004", "0.001"]));
+    }
+}
@@ -0,0 +1,183 @@
+use crate::models::position::Position;
+use bigdecimal::BigDecimal;
+use ethereum_types::H160;
+use sqlx::{Executor, PgConnection, PgPool, Postgres};
+use std::collections::HashMap;
+
+/// Fetches a trader's position in a perpetual market inside a transaction, locking its row until
+/// the transaction ends. A trader who never traded the market gets a flat position. Callers lock
+/// the trader's account first, which keeps two settlements from creating the same row at once.
+pub async fn lock_position(conn: &mut PgConnection, trader_address: &H160, market_id: i32) -> sqlx::Result<Position> {
+    let position = sqlx::query_as!(
+        Position,
+        r#"
+        SELECT trader_address, market_id, size, entry_price, realized_pnl, updated_at
+        FROM positions
+        WHERE LOWER(trader_address) = LOWER($1) AND market_id = $2
+        FOR UPDATE
+        "#,
+        format!("{:?}", trader_address),
+        market_id,
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(position.unwrap_or_else(|| Position::flat(format!("{:?}", trader_address), market_id)))
+}
+
+/// Writes a position inside a transaction.
+pub async fn save_position(conn: &mut PgConnection, position: &Position) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO positions (trader_address, market_id, size, entry_price, realized_pnl, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (trader_address, market_id) DO UPDATE
+        SET size = EXCLUDED.size, entry_price = EXCLUDED.entry_price,
+            realized_pnl = EXCLUDED.realized_pnl, updated_at = EXCLUDED.updated_at
+        "#,
+        position.trader_address.to_lowercase(),
+        position.market_id,
+        position.size,
+        position.entry_price,
+        position.realized_pnl,
+        position.updated_at,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Every position a trader has held, including flat ones that still carry realized PnL.
+pub async fn get_positions(db: &PgPool, trader_address: &H160) -> sqlx::Result<Vec<Position>> {
+    sqlx::query_as!(
+        Position,
+        r#"
+        SELECT trader_address, market_id, size, entry_price, realized_pnl, updated_at
+        FROM positions
+        WHERE LOWER(trader_address) = LOWER($1)
+        ORDER BY market_id
+        "#,
+        format!("{:?}", trader_address),
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Every open position across all traders and markets.
+pub async fn open_positions(db: &PgPool) -> sqlx::Result<Vec<Position>> {
+    sqlx::query_as!(
+        Position,
+        r#"
+        SELECT trader_address, market_id, size, entry_price, realized_pnl, updated_at
+        FROM positions
+        WHERE size <> 0
+        ORDER BY LOWER(trader_address), market_id
+        "#,
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// A trader's position in one market, `None` if they never traded it.
+pub async fn get_position(db: &PgPool, trader_address: &H160, market_id: i32) -> sqlx::Result<Option<Position>> {
+    sqlx::query_as!(
+        Position,
+        r#"
+        SELECT trader_address, market_id, size, entry_price, realized_pnl, updated_at
+        FROM positions
+        WHERE LOWER(trader_address) = LOWER($1) AND market_id = $2
+        "#,
+        format!("{:?}", trader_address),
+        market_id,
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Initial margin tied up by a trader's open positions in markets margined in `quote_asset`,
+/// valued at their entry prices.
+pub async fn margin_in_use<'e, E: Executor<'e, Database = Postgres>>(executor: E, trader_address: &H160, quote_asset: &str) -> sqlx::Result<BigDecimal> {
+    let row = sqlx::query!(
+        r#"
+        SELECT COALESCE(SUM(ABS(p.size) * p.entry_price * m.initial_margin), 0) AS "margin!"
+        FROM positions p
+        JOIN markets m ON m.id = p.market_id
+        WHERE LOWER(p.trader_address) = LOWER($1) AND m.quote_asset = $2 AND p.size <> 0
+        "#,
+        format!("{:?}", trader_address),
+        quote_asset,
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(row.margin)
+}
+
+/// Unrealized PnL of a trader's open positions in markets margined in `quote_asset`, each valued
+/// at its market's price in `mark_prices` (keyed by market id). Positions in markets without a
+/// mark price count as zero.
+pub async fn unrealized_pnl<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    trader_address: &H160,
+    quote_asset: &str,
+    mark_prices: &HashMap<i32, BigDecimal>,
+) -> sqlx::Result<BigDecimal> {
+    let positions = sqlx::query_as!(
+        Position,
+        r#"
+        SELECT p.trader_address, p.market_id, p.size, p.entry_price, p.realized_pnl, p.updated_at
+        FROM positions p
+        JOIN markets m ON m.id = p.market_id
+        WHERE LOWER(p.trader_address) = LOWER($1) AND m.quote_asset = $2 AND p.size <> 0
+        "#,
+        format!("{:?}", trader_address),
+        quote_asset,
+    )
+    .fetch_all(executor)
+    .await?;
+    Ok(positions
+        .iter()
+        .filter_map(|position| Some(position.unrealized_pnl(mark_prices.get(&position.market_id)?)))
+        .sum())
+}
+
+/// Collateral a trader has left in `quote_asset` once their open positions are margined: the
+/// `available` balance, less the initial margin of every open position in markets margined in
+/// it, plus those positions' unrealized PnL at `mark_prices`.
+pub async fn free_collateral(
+    db: &PgPool,
+    trader_address: &H160,
+    quote_asset: &str,
+    available: &BigDecimal,
+    mark_prices: &HashMap<i32, BigDecimal>,
+) -> sqlx::Result<BigDecimal> {
+    let margin_in_use = margin_in_use(db, trader_address, quote_asset).await?;
+    let unrealized_pnl = unrealized_pnl(db, trader_address, quote_asset, mark_prices).await?;
+    Ok(available.clone() - margin_in_use + unrealized_pnl)
+}
+
+/// `free_collateral` inside a transaction. The trader's positions in markets margined in
+/// `quote_asset` stay locked until the transaction ends, so no fill can change them between the
+/// check and whatever the caller does with the result.
+pub async fn lock_free_collateral(
+    conn: &mut PgConnection,
+    trader_address: &H160,
+    quote_asset: &str,
+    available: &BigDecimal,
+    mark_prices: &HashMap<i32, BigDecimal>,
+) -> sqlx::Result<BigDecimal> {
+    sqlx::query!(
+        r#"
+        SELECT p.market_id
+        FROM positions p
+        JOIN markets m ON m.id = p.market_id
+        WHERE LOWER(p.trader_address) = LOWER($1) AND m.quote_asset = $2
+        FOR UPDATE OF p
+        "#,
+        format!("{:?}", trader_address),
+        quote_asset,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let margin_in_use = margin_in_use(&mut *conn, trader_address, quote_asset).await?;
+    let unrealized_pnl = unrealized_pnl(&mut *conn, trader_address, quote_asset, mark_prices).await?;
+    Ok(available.clone() - margin_in_use + unrealized_pnl)
+}
@@ -0,0 +1,216 @@
+use crate::models::ledger::LedgerCause;
+use crate::models::transfer::{Transfer, TransferKind, TransferStatus};
+use crate::services::account_service;
+use crate::services::asset_service;
+use crate::services::ledger_service;
+use crate::services::position_service;
+use bigdecimal::BigDecimal;
+use ethereum_types::{H160, H256};
+use sqlx::{PgConnection, PgPool};
+use std::collections::HashMap;
+
+async fn insert_transfer(
+    conn: &mut PgConnection,
+    trader_address: &H160,
+    kind: &TransferKind,
+    asset: &str,
+    amount: &BigDecimal,
+    status: &TransferStatus,
+    nonce: Option<&H256>,
+) -> sqlx::Result<Transfer> {
+    sqlx::query_as!(
+        Transfer,
+        r#"
+        INSERT INTO transfers (trader_address, kind, asset, amount, status, nonce)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, trader_address, kind, asset, amount, status, reason, created_at, updated_at
+        "#,
+        format!("{:?}", trader_address),
+        kind.to_string(),
+        asset,
+        amount,
+        status.to_string(),
+        nonce.map(|nonce| format!("{:?}", nonce)),
+    )
+    .fetch_one(conn)
+    .await
+}
+
+// Transfers must be of a registered asset, positive, and within the asset's decimal places
+async fn check_amount(db: &PgPool, asset: &str, amount: &BigDecimal) -> Result<(), String> {
+    let asset = asset_service::get_asset(db, asset)
+        .await
+        .map_err(|e| format!("Failed to look up asset: {}", e))?
+        .ok_or_else(|| format!("Unknown asset: {}", asset))?;
+    if *amount <= BigDecimal::from(0) {
+        return Err("Amount must be greater than zero".to_string());
+    }
+    if amount.normalized().as_bigint_and_exponent().1 > asset.decimals as i64 {
+        return Err(format!("{} amounts have at most {} decimal places", asset.symbol, asset.decimals));
+    }
+    Ok(())
+}
+
+/// Credits a deposit to the trader's available balance.
+pub async fn deposit(db: &PgPool, trader_address: &H160, asset: &str, amount: &BigDecimal) -> Result<Transfer, String> {
+    let failed = |e: sqlx::Error| format!("Failed to record deposit: {}", e);
+    check_amount(db, asset, amount).await?;
+    let mut tx = db.begin().await.map_err(failed)?;
+    let before = account_service::lock_account(&mut tx, trader_address)
+        .await
+        .map_err(|_| format!("Account not found for trader address: {:?}", trader_address))?;
+    let mut after = before.clone();
+    after.credit(asset, amount)?;
+
+    account_service::save_account(&mut tx, &after).await.map_err(failed)?;
+    let transfer = insert_transfer(&mut tx, trader_address, &TransferKind::Deposit, asset, amount, &TransferStatus::Completed, None)
+        .await
+        .map_err(failed)?;
+    ledger_service::record_movement(&mut tx, &LedgerCause::Deposit, &transfer.id.to_string(), &[(&before, &after)])
+        .await
+        .map_err(failed)?;
+    tx.commit().await.map_err(failed)?;
+    Ok(transfer)
+}
+
+/// Requests a withdrawal the trader signed with `nonce`, which they can only use once. The amount
+/// must be available and is locked until the withdrawal is completed or rejected. Collateral of
+/// perpetual positions stays put: what is left must still cover their initial margin, with their
+/// unrealized PnL at `mark_prices` (keyed by market id).
+pub async fn request_withdrawal(
+    db: &PgPool,
+    trader_address: &H160,
+    asset: &str,
+    amount: &BigDecimal,
+    nonce: &H256,
+    mark_prices: &HashMap<i32, BigDecimal>,
+) -> Result<Transfer, String> {
+    let failed = |e: sqlx::Error| format!("Failed to record withdrawal: {}", e);
+    check_amount(db, asset, amount).await?;
+    let mut tx = db.begin().await.map_err(failed)?;
+    let before = account_service::lock_account(&mut tx, trader_address)
+        .await
+        .map_err(|_| format!("Account not found for trader address: {:?}", trader_address))?;
+    // The account lock keeps another request from using the nonce before this one commits
+    let used = sqlx::query_scalar!(
+        r#"
+        SELECT id
+        FROM transfers
+        WHERE LOWER(trader_address) = LOWER($1) AND nonce = $2
+        "#,
+        format!("{:?}", trader_address),
+        format!("{:?}", nonce),
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(failed)?;
+    if used.is_some() {
+        return Err(format!("Nonce {:?} has already been used", nonce));
+    }
+    let mut after = before.clone();
+    after.lock(asset, amount)?;
+    let free_collateral = position_service::lock_free_collateral(&mut tx, trader_address, asset, &after.available(asset), mark_prices)
+        .await
+        .map_err(failed)?;
+    if free_collateral < BigDecimal::from(0) {
+        return Err(format!("Withdrawal would leave too little {} to margin open positions", asset));
+    }
+
+    account_service::save_account(&mut tx, &after).await.map_err(failed)?;
+    let transfer = insert_transfer(&mut tx, trader_address, &TransferKind::Withdrawal, asset, amount, &TransferStatus::Pending, Some(nonce))
+        .await
+        .map_err(failed)?;
+    ledger_service::record_movement(&mut tx, &LedgerCause::Withdrawal, &transfer.id.to_string(), &[(&before, &after)])
+        .await
+        .map_err(failed)?;
+    tx.commit().await.map_err(failed)?;
+    Ok(transfer)
+}
+
+/// Completes or rejects a pending withdrawal: completing pays the locked funds out, rejecting
+/// returns them to the available balance. `None` if there is no withdrawal with that id.
+pub async fn settle_withdrawal(
+    db: &PgPool,
+    transfer_id: i64,
+    status: TransferStatus,
+    reason: Option<String>,
+) -> Result<Option<Transfer>, String> {
+    let failed = |e: sqlx::Error| format!("Failed to settle withdrawal: {}", e);
+    if status == TransferStatus::Pending {
+        return Err("A withdrawal can only be completed or rejected".to_string());
+    }
+    let mut tx = db.begin().await.map_err(failed)?;
+    let transfer = sqlx::query_as!(
+        Transfer,
+        r#"
+        SELECT id, trader_address, kind, asset, amount, status, reason, created_at, updated_at
+        FROM transfers
+        WHERE id = $1 AND kind = $2
+        FOR UPDATE
+        "#,
+        transfer_id,
+        TransferKind::Withdrawal.to_string(),
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(failed)?;
+    let transfer = match transfer {
+        Some(transfer) => transfer,
+        None => return Ok(None),
+    };
+    if transfer.status != TransferStatus::Pending.to_string() {
+        return Err(format!("Withdrawal {} is already {}", transfer.id, transfer.status.to_lowercase()));
+    }
+
+    let trader_address: H160 = transfer
+        .trader_address
+        .parse()
+        .map_err(|_| format!("Invalid trader address on withdrawal {}", transfer.id))?;
+    let before = account_service::lock_account(&mut tx, &trader_address).await.map_err(failed)?;
+    let mut after = before.clone();
+    if status == TransferStatus::Completed {
+        after.spend_locked(&transfer.asset, &transfer.amount)?;
+    } else {
+        after.release(&transfer.asset, &transfer.amount);
+    }
+    account_service::save_account(&mut tx, &after).await.map_err(failed)?;
+    ledger_service::record_movement(&mut tx, &LedgerCause::Withdrawal, &transfer.id.to_string(), &[(&before, &after)])
+        .await
+        .map_err(failed)?;
+
+    let transfer = sqlx::query_as!(
+        Transfer,
+        r#"
+        UPDATE transfers
+        SET status = $1, reason = $2, updated_at = NOW()
+        WHERE id = $3
+        RETURNING id, trader_address, kind, asset, amount, status, reason, created_at, updated_at
+        "#,
+        status.to_string(),
+        reason,
+        transfer.id,
+    )
+    .fetch_one(&mut tx)
+    .await
+    .map_err(failed)?;
+    tx.commit().await.map_err(failed)?;
+    Ok(Some(transfer))
+}
+
+/// A trader's deposits and withdrawals, newest first.
+pub async fn get_transfers(db: &PgPool, trader_address: &H160, limit: i64) -> Result<Vec<Transfer>, sqlx::Error> {
+    sqlx::query_as!(
+        Transfer,
+        r#"
+        SELECT id, trader_address, kind, asset, amount, status, reason, created_at, updated_at
+        FROM transfers
+        WHERE LOWER(trader_address) = LOWER($1)
+        ORDER BY id DESC
+        LIMIT $2
+        "#,
+        format!("{:?}", trader_address),
+        limit
+    )
+    .fetch_all(db)
+    .await
+}